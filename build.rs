@@ -5,7 +5,7 @@ use bootloader::DiskImageBuilder;
 fn main() {
     // set by cargo for the kernel artifact dependency
     let kernel_path = env::var("CARGO_BIN_FILE_KERNEL").unwrap();
-    let disk_builder = DiskImageBuilder::new(PathBuf::from(kernel_path));
+    let disk_builder = DiskImageBuilder::new(PathBuf::from(&kernel_path));
 
     // specify output paths
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
@@ -19,4 +19,6 @@ fn main() {
     // pass the disk image paths via environment variables
     println!("cargo:rustc-env=UEFI_IMAGE={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_IMAGE={}", bios_path.display());
+    // pass the kernel binary through so the runner can point gdb at its symbols
+    println!("cargo:rustc-env=KERNEL_BINARY={}", kernel_path);
 }
@@ -1,25 +1,21 @@
-use core::{
-    cell::{OnceCell, RefCell},
-    ptr::NonNull,
-};
+use core::ptr::NonNull;
 
-use acpi::{AcpiError, AcpiHandler, AcpiTables, PhysicalMapping, PlatformInfo};
-use alloc::{alloc::Global, rc::Rc};
-use thiserror::Error;
-use tracing::{error, instrument, warn};
-use x86_64::{
-    structures::paging::{Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB},
-    PhysAddr, VirtAddr,
+use acpi::{
+    fadt::Fadt, mcfg::PciConfigRegions, AcpiError, AcpiHandler, AcpiTables, PhysicalMapping,
+    PlatformInfo,
 };
+use alloc::alloc::Global;
+use thiserror::Error;
+use tracing::{debug, error, instrument, warn};
+use x86_64::{PhysAddr, VirtAddr};
 
 use crate::{
-    memory::{mapping::MAPPER, PAGE_ALLOCATOR},
-    util::once::{OnceLock, TryInitError},
+    memory::mapping::{map_mmio, unmap_mmio},
+    pci::{self, EcamRegion},
+    rtc,
+    util::once::TryInitError,
 };
 
-pub static KERNEL_ACPI_ADDR: OnceLock<VirtAddr> = OnceLock::new();
-pub const KERNEL_ACPI_LEN: usize = 1024 * 1024;
-
 #[derive(Error, Debug)]
 pub enum AcpiInitError {
     #[error("Rsdp ({1:x}) that bootloader found is bad: {0:?}")]
@@ -48,23 +44,42 @@ pub fn init(rsdp: u64) -> Result<PlatformInfo<'static, Global>, AcpiInitError> {
         }
     };
 
+    match PciConfigRegions::new(&acpi_tables) {
+        Ok(regions) => pci::init_ecam(
+            regions
+                .iter()
+                .map(|entry| EcamRegion {
+                    segment_group: entry.segment_group,
+                    buses: entry.bus_range,
+                    base: PhysAddr::new(entry.physical_address as u64),
+                })
+                .collect(),
+        ),
+        Err(err) => warn!("No MCFG, PCI config space will use port io: {:?}", err),
+    }
+
+    match acpi_tables.find_table::<Fadt>() {
+        Ok(fadt) => {
+            // Zero means the firmware has no century register
+            let century = fadt.century;
+            debug!(century, "FADT century register");
+            rtc::CENTURY_REGISTER.init_once(|| (century != 0).then_some(century));
+        }
+        Err(err) => warn!("No FADT, the RTC will assume the 21st century: {:?}", err),
+    }
+
     PlatformInfo::new(&acpi_tables).map_err(AcpiInitError::PlatformInfoError)
 }
 
+/// Maps ACPI tables through the mmio window.
 #[derive(Debug, Clone)]
 pub struct KernelAcpi {
-    start_addr: Rc<RefCell<u64>>,
-    end_addr_exclusive: u64,
+    _private: (),
 }
 
 impl KernelAcpi {
     pub fn new() -> Self {
-        let start_addr = KERNEL_ACPI_ADDR.get().as_u64();
-        let end_addr_exclusive = start_addr + KERNEL_ACPI_LEN as u64 - 1;
-        Self {
-            start_addr: Rc::new(RefCell::new(start_addr)),
-            end_addr_exclusive,
-        }
+        Self { _private: () }
     }
 }
 
@@ -80,40 +95,11 @@ impl AcpiHandler for KernelAcpi {
         physical_address: usize,
         size: usize,
     ) -> acpi::PhysicalMapping<Self, T> {
-        let page_range = {
-            let guard = self.start_addr.borrow();
-            if *guard + size as u64 >= self.end_addr_exclusive {
-                panic!("acpi memory exhausted");
-            }
-
-            let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(*guard));
-            let end = *guard + size as u64;
-            let end_page = Page::<Size4KiB>::containing_address(VirtAddr::new(end));
-            start_page..=end_page
-        };
-
-        let virtual_start = OnceCell::new();
-        let mut mapper = MAPPER.spin_lock();
-        for page in page_range {
-            let _ = virtual_start.set(NonNull::new(page.start_address().as_mut_ptr()).unwrap());
-            let res = mapper
-                .map_to(
-                    page,
-                    PhysFrame::containing_address(PhysAddr::new(physical_address as u64)),
-                    PageTableFlags::PRESENT
-                        | PageTableFlags::WRITABLE
-                        | PageTableFlags::NO_CACHE
-                        | PageTableFlags::WRITE_THROUGH,
-                    &mut *PAGE_ALLOCATOR.get().spin_lock(),
-                )
-                .unwrap();
-            res.flush();
-            let mut guard = self.start_addr.borrow_mut();
-            *guard += Size4KiB::SIZE;
-        }
+        let virtual_start =
+            map_mmio(PhysAddr::new(physical_address as u64), size).expect("acpi memory exhausted");
         PhysicalMapping::new(
             physical_address,
-            virtual_start.into_inner().unwrap(),
+            NonNull::new(virtual_start.as_mut_ptr()).unwrap(),
             size,
             size,
             self.clone(),
@@ -121,17 +107,29 @@ impl AcpiHandler for KernelAcpi {
     }
 
     fn unmap_physical_region<T>(region: &acpi::PhysicalMapping<Self, T>) {
-        let page_range = {
-            let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(
-                region.virtual_start().as_ptr() as u64,
-            ));
-            let end = region.virtual_start().as_ptr() as u64 + region.region_length() as u64;
-            let end_page = Page::<Size4KiB>::containing_address(VirtAddr::new(end));
-            start_page..=end_page
-        };
-        for page in page_range {
-            MAPPER.spin_lock().unmap(page).unwrap().1.flush();
-            *region.handler().start_addr.borrow_mut() -= Size4KiB::SIZE;
+        let virtual_start = VirtAddr::from_ptr(region.virtual_start().as_ptr());
+        if let Err(err) = unmap_mmio(virtual_start, region.region_length()) {
+            error!("Failed to unmap acpi region: {err}");
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use acpi::AcpiHandler;
+
+    use super::KernelAcpi;
+    use crate::{memory::mapping::KERNEL_MMIO_LEN, testing::ShouldPanic};
+
+    #[test_case]
+    static MAP_PAST_MMIO_WINDOW: ShouldPanic = ShouldPanic {
+        name: "kernel::acpi::test::map_past_mmio_window",
+        test: || {
+            let handler = KernelAcpi::new();
+            // A page more than the whole window, so it can't fit whatever else is mapped
+            let len = KERNEL_MMIO_LEN + 0x1000;
+            let _mapping = unsafe { handler.map_physical_region::<u8>(0, len) };
+        },
+        check: None,
+    };
+}
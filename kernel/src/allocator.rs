@@ -1,25 +1,77 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
 use x86_64::{
-    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB},
+    structures::paging::{FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB},
     VirtAddr,
 };
 
 use crate::{
-    memory::mapping::MAPPER,
+    memory::{
+        mapping::{self, MAPPER},
+        PAGE_ALLOCATOR,
+    },
+    println,
     util::{once::OnceLock, r#async::mutex::Mutex},
 };
 
 use self::block::FixedSizeBlockAllocator;
+pub use self::block::{BlockAllocStats, BLOCK_SIZES};
 
+mod arena;
 mod block;
 mod linked_list;
 
+pub use self::arena::ArenaAllocator;
+
 #[global_allocator]
+static HEAP: CheckedHeap = CheckedHeap(&ALLOCATOR);
 static ALLOCATOR: Mutex<FixedSizeBlockAllocator> = Mutex::new(FixedSizeBlockAllocator::new());
 
+/// The global allocator, asserting in debug builds that nothing allocates while holding
+/// [`MAPPER`] or [`PAGE_ALLOCATOR`], see [`MAPPER`] for why.
+struct CheckedHeap(&'static Mutex<FixedSizeBlockAllocator>);
+
+/// Set by the first allocation under the page locks or by a panic, since the panic handler
+/// allocates too.
+static PAGE_LOCK_CHECK_OFF: AtomicBool = AtomicBool::new(false);
+
+/// Stops asserting that the page locks are free on allocation, for panic handlers.
+///
+/// The panicking code may hold them, and a second panic from the handler's logging would hide
+/// the first.
+pub fn stop_page_lock_check() {
+    PAGE_LOCK_CHECK_OFF.store(true, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for CheckedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if cfg!(debug_assertions) {
+            let busy = (MAPPER.is_init() && MAPPER.is_locked())
+                || PAGE_ALLOCATOR.try_get().is_ok_and(|frames| frames.is_locked());
+            if busy && !PAGE_LOCK_CHECK_OFF.swap(true, Ordering::Relaxed) {
+                panic!("allocated {layout:?} with the page tables or frame allocator locked");
+            }
+        }
+        self.0.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout)
+    }
+}
+
+/// Maps the start of the heap and registers the rest to be mapped on demand.
+///
+/// Only [`KERNEL_HEAP_EAGER_LEN`] is backed up front since faults can't be handled until the
+/// IDT is loaded.
 pub fn init(page_allocator: &mut impl FrameAllocator<Size4KiB>) {
+    let heap_start = *KERNEL_HEAP_ADDR.get();
+    let lazy_start = heap_start + KERNEL_HEAP_EAGER_LEN as u64;
     let page_range = {
-        let heap_start = *KERNEL_HEAP_ADDR.get();
-        let heap_end = heap_start + KERNEL_HEAP_LEN as u64 - 1u64;
+        let heap_end = lazy_start - 1u64;
         let heap_start_page = Page::<Size4KiB>::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         heap_start_page..=heap_end_page
@@ -28,7 +80,8 @@ pub fn init(page_allocator: &mut impl FrameAllocator<Size4KiB>) {
         let mut mapper = MAPPER.spin_lock();
         for page in page_range {
             let frame = page_allocator.allocate_frame().unwrap();
-            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            let flags =
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
             unsafe {
                 mapper
                     .map_to(page, frame, flags, &mut *page_allocator)
@@ -37,16 +90,215 @@ pub fn init(page_allocator: &mut impl FrameAllocator<Size4KiB>) {
             }
         }
     }
-    unsafe {
-        ALLOCATOR
-            .spin_lock()
-            .init(KERNEL_HEAP_ADDR.get().as_mut_ptr(), KERNEL_HEAP_LEN);
+    let heap_len = heap_len();
+    mapping::register_lazy_region(lazy_start..heap_start + heap_len as u64)
+        .expect("heap should be the first lazy region");
+    let mut allocator = ALLOCATOR.spin_lock();
+    unsafe { allocator.init(KERNEL_HEAP_ADDR.get().as_mut_ptr(), heap_len) };
+    allocator.set_grow(grow_heap);
+}
+
+/// Maps fresh frames right past the end of the heap, see [`KERNEL_HEAP_MAX_LEN`].
+///
+/// Runs with the allocator locked, so it mustn't allocate and only tries the page table and
+/// frame allocator locks. Their holders never allocate, see [`MAPPER`], so finding one busy
+/// means an interrupt handler is allocating over the holder and waiting would deadlock. That
+/// is recorded for [`alloc_error`] to tell apart from really running out. Maps as much as it
+/// can when frames run out.
+fn grow_heap(top: *mut u8, min: usize) -> usize {
+    let top = VirtAddr::from_ptr(top);
+    debug_assert!(top.is_aligned(Size4KiB::SIZE));
+    let end = *KERNEL_HEAP_ADDR.get() + KERNEL_HEAP_MAX_LEN as u64;
+    if min as u64 > end - top {
+        return 0;
     }
+    let len = align_up(min.max(HEAP_GROWTH_STEP), Size4KiB::SIZE as usize) as u64;
+    let len = len.min(end - top);
+
+    let (Some(mut mapper), Some(mut page_allocator)) = (
+        MAPPER.try_lock(),
+        PAGE_ALLOCATOR.try_get().ok().and_then(|a| a.try_lock()),
+    ) else {
+        GROWTH_SKIPPED.store(true, Ordering::Relaxed);
+        return 0;
+    };
+    GROWTH_SKIPPED.store(false, Ordering::Relaxed);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mut grown = 0;
+    for page in Page::range(
+        Page::<Size4KiB>::containing_address(top),
+        Page::containing_address(top + len),
+    ) {
+        let Some(frame) = page_allocator.allocate_frame() else {
+            break;
+        };
+        // Failing here means a page table couldn't get a frame. Handing `frame` back could
+        // allocate, so it's leaked, we're about out of memory anyway.
+        match unsafe { mapper.map_to(page, frame, flags, &mut *page_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => break,
+        }
+        grown += Size4KiB::SIZE as usize;
+    }
+    grown
+}
+
+/// Where the kernel heap's memory currently is.
+pub fn stats() -> BlockAllocStats {
+    x86_64::instructions::interrupts::without_interrupts(|| ALLOCATOR.spin_lock().stats())
+}
+
+/// Whether the last [`grow_heap`] gave up because the page tables or frame allocator were busy.
+static GROWTH_SKIPPED: AtomicBool = AtomicBool::new(false);
+
+static LAST_OOM: Mutex<Option<Layout>> = Mutex::new(None);
+
+/// The allocation the heap last failed to satisfy.
+pub fn last_oom() -> Option<Layout> {
+    *LAST_OOM.try_lock()?
+}
+
+/// Reports what the heap and frame allocator look like before giving up.
+///
+/// Only prints to serial since logging through tracing allocates.
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    if let Some(mut last) = LAST_OOM.try_lock() {
+        *last = Some(layout);
+    }
+    println!(
+        "out of memory: failed to allocate {} bytes aligned to {}",
+        layout.size(),
+        layout.align()
+    );
+    if GROWTH_SKIPPED.load(Ordering::Relaxed) {
+        println!("heap growth skipped, the page tables or frame allocator were locked");
+    }
+    if let Some(allocator) = ALLOCATOR.try_lock() {
+        println!("{}", allocator.stats());
+    } else {
+        println!("heap stats unavailable, allocator is locked");
+    }
+    if let Some(frames) = PAGE_ALLOCATOR.try_get().ok().and_then(|f| f.try_lock()) {
+        println!("{} free frames", frames.free_frames());
+    } else {
+        println!("frame stats unavailable");
+    }
+    panic!("out of memory allocating {:?}", layout);
+}
+
+/// Gives blocks cached by the global allocator back to the heap, see
+/// [`FixedSizeBlockAllocator::trim`].
+pub fn trim() -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| ALLOCATOR.spin_lock().trim())
 }
 
 pub static KERNEL_HEAP_ADDR: OnceLock<VirtAddr> = OnceLock::new();
+/// The heap length asked for when [`InitOptions`](crate::InitOptions) doesn't say otherwise.
 pub const KERNEL_HEAP_LEN: usize = 32 * 1024 * 1024;
+/// Virtual space reserved for the heap, it grows into what's past its initial length on demand.
+pub const KERNEL_HEAP_MAX_LEN: usize = 256 * 1024 * 1024;
+/// The least the heap grows by at once, so growing stays rare.
+const HEAP_GROWTH_STEP: usize = 1024 * 1024;
+static HEAP_LEN: OnceLock<usize> = OnceLock::new();
+/// The part of the heap mapped during [`init`], the rest is mapped on first touch.
+pub const KERNEL_HEAP_EAGER_LEN: usize = 64 * 1024;
+
+/// The length the heap was sized to at boot, see [`clamp_heap_len`]. It may have grown since.
+pub fn heap_len() -> usize {
+    *HEAP_LEN.get()
+}
+
+pub(crate) fn set_heap_len(len: usize) {
+    HEAP_LEN.init_once(|| len);
+}
+
+/// Fits a `requested` heap length into `usable` bytes of physical memory and
+/// [`KERNEL_HEAP_MAX_LEN`].
+///
+/// The result is whole pages and never smaller than [`KERNEL_HEAP_EAGER_LEN`].
+pub fn clamp_heap_len(requested: usize, usable: u64) -> usize {
+    let usable = usize::try_from(usable).unwrap_or(usize::MAX);
+    let len = requested.min(usable).min(KERNEL_HEAP_MAX_LEN) & !(Size4KiB::SIZE as usize - 1);
+    len.max(KERNEL_HEAP_EAGER_LEN)
+}
 
 fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
+
+#[cfg(test)]
+mod test {
+    use alloc::{
+        alloc::{alloc, dealloc, Layout},
+        boxed::Box,
+    };
+
+    use x86_64::{
+        structures::paging::{PageSize, Size4KiB, Translate},
+        VirtAddr,
+    };
+
+    use core::sync::atomic::Ordering;
+
+    use super::{
+        clamp_heap_len, grow_heap, GROWTH_SKIPPED, KERNEL_HEAP_ADDR, KERNEL_HEAP_EAGER_LEN,
+        KERNEL_HEAP_MAX_LEN,
+    };
+    use crate::{memory::mapping::MAPPER, testing::Bench};
+
+    #[test_case]
+    fn heap_len_clamped_to_usable() {
+        assert_eq!(clamp_heap_len(1 << 20, 1 << 30), 1 << 20);
+        assert_eq!(clamp_heap_len(1 << 30, 1 << 20), 1 << 20);
+        assert_eq!(clamp_heap_len((1 << 20) + 1, 1 << 30), 1 << 20);
+        assert_eq!(clamp_heap_len(1 << 20, 0), KERNEL_HEAP_EAGER_LEN);
+    }
+
+    #[test_case]
+    static BOX_ALLOC: Bench = Bench {
+        name: "kernel::allocator::test::box_alloc",
+        iterations: 10_000,
+        bench: || {
+            core::hint::black_box(Box::new([0u64; 8]));
+        },
+    };
+
+    #[test_case]
+    fn heap_pages_mapped_on_demand() {
+        const PAGES: usize = 64;
+        let layout = Layout::from_size_align(PAGES * Size4KiB::SIZE as usize, 4096).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        assert!(!ptr.is_null());
+
+        // The first and last pages may share a frame with allocator bookkeeping
+        let pages = (1..PAGES - 1).map(|i| unsafe { ptr.add(i * Size4KiB::SIZE as usize) });
+        let mapped = || {
+            let mapper = MAPPER.spin_lock();
+            pages
+                .clone()
+                .filter(|&p| mapper.translate_addr(VirtAddr::from_ptr(p)).is_some())
+                .count()
+        };
+
+        assert!(mapped() < PAGES - 2, "heap pages were mapped up front");
+        for page in pages.clone() {
+            unsafe { page.write_volatile(0xAA) };
+        }
+        assert_eq!(mapped(), PAGES - 2);
+
+        unsafe { dealloc(ptr, layout) };
+    }
+
+    #[test_case]
+    fn growth_on_busy_page_tables_is_recorded() {
+        let page = Size4KiB::SIZE as usize;
+        let top = *KERNEL_HEAP_ADDR.get() + (KERNEL_HEAP_MAX_LEN - page) as u64;
+        let mapper = MAPPER.spin_lock();
+        let grown = grow_heap(top.as_mut_ptr(), page);
+        drop(mapper);
+
+        assert_eq!(grown, 0);
+        assert!(GROWTH_SKIPPED.swap(false, Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,111 @@
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    marker::PhantomData,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::align_up;
+
+/// Bump allocator over a fixed slab for short lived allocations.
+///
+/// Freeing is a no-op except for the most recent allocation, the whole slab is reclaimed at once
+/// with [`Self::reset`]. Keeps transient boot-time data from fragmenting the global heap.
+pub struct ArenaAllocator<'a> {
+    start: usize,
+    end: usize,
+    next: AtomicUsize,
+    _slab: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> ArenaAllocator<'a> {
+    pub fn new(slab: &'a mut [u8]) -> Self {
+        let start = slab.as_mut_ptr() as usize;
+        Self {
+            start,
+            end: start + slab.len(),
+            next: AtomicUsize::new(start),
+            _slab: PhantomData,
+        }
+    }
+
+    /// Frees every allocation, which can't be borrowing the arena anymore.
+    pub fn reset(&mut self) {
+        *self.next.get_mut() = self.start;
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Bytes handed out since the last reset, including alignment padding.
+    pub fn used(&self) -> usize {
+        self.next.load(Ordering::Relaxed) - self.start
+    }
+}
+
+unsafe impl Allocator for ArenaAllocator<'_> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let mut alloc_start = 0;
+        self.next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                alloc_start = align_up(next, layout.align());
+                let alloc_end = alloc_start.checked_add(layout.size())?;
+                (alloc_end <= self.end).then_some(alloc_end)
+            })
+            .map_err(|_| AllocError)?;
+
+        let ptr = NonNull::new(alloc_start as *mut u8).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // Only the newest allocation can be given back before a reset
+        let alloc_start = ptr.as_ptr() as usize;
+        let _ = self.next.compare_exchange(
+            alloc_start + layout.size(),
+            alloc_start,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{boxed::Box, vec::Vec};
+
+    use super::ArenaAllocator;
+
+    #[test_case]
+    fn reset_reclaims_slab() {
+        let mut slab = [0u8; 256];
+        let mut arena = ArenaAllocator::new(&mut slab);
+        {
+            let a = Box::new_in(1u8, &arena);
+            let b = Box::new_in(2u64, &arena);
+            let c = Box::new_in([3u32; 4], &arena);
+            assert_eq!((*a, *b, c[3]), (1, 2, 3));
+            // Padding for the u64 after the u8
+            assert_eq!(arena.used(), 8 + 8 + 16);
+        }
+        assert!(arena.used() > 0);
+
+        arena.reset();
+        assert_eq!(arena.used(), 0);
+        let mut all = Vec::with_capacity_in(arena.capacity(), &arena);
+        all.resize(arena.capacity(), 0u8);
+        assert!(Box::try_new_in(0u8, &arena).is_err());
+    }
+
+    #[test_case]
+    fn newest_allocation_freed() {
+        let mut slab = [0u8; 64];
+        let arena = ArenaAllocator::new(&mut slab);
+        let a = Box::new_in(1u32, &arena);
+        drop(Box::new_in(2u32, &arena));
+        assert_eq!(arena.used(), 4);
+        drop(a);
+        assert_eq!(arena.used(), 0);
+    }
+}
@@ -1,12 +1,31 @@
 use core::{
     alloc::{GlobalAlloc, Layout},
-    mem,
-    ptr::NonNull,
+    fmt, mem,
+    ptr::{self, NonNull},
 };
 
 use crate::util::r#async::mutex::Mutex;
 
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 512, 1024, 2048];
+/// The size classes, each a power of two so blocks are aligned to their size.
+///
+/// Every class wastes up to half a block on requests just past the class below, so there are
+/// no gaps bigger than a doubling. 256 is there for the 129 to 256 byte requests that went to
+/// 512 before, and 4096 keeps page sized buffers off the linked list heap. Tune these against
+/// [`BlockAllocStats::class_wasted`] under a real workload, `mem` in the shell prints it.
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
+/// Free blocks per size class kept by [`FixedSizeBlockAllocator::trim`].
+pub const TRIM_RESERVE: usize = 4;
+/// Written over freed blocks with the `heap-poison` feature, so stale pointers read it back.
+pub const FREED_POISON: u8 = 0xDE;
+/// Written over freshly allocated blocks with the `heap-poison` feature.
+pub const ALLOCATED_POISON: u8 = 0xAA;
+
+/// Fills `len` bytes at `ptr` with `byte`, compiled out unless poisoning is on.
+unsafe fn poison(ptr: *mut u8, len: usize, byte: u8) {
+    if cfg!(all(feature = "heap-poison", debug_assertions)) {
+        ptr.write_bytes(byte, len);
+    }
+}
 
 struct ListNode {
     next: Option<&'static mut ListNode>,
@@ -24,9 +43,56 @@ impl ListNode {
     }
 }
 
+/// A snapshot of where the heap's memory is.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockAllocStats {
+    /// Free blocks held per size class, lined up with [`BLOCK_SIZES`].
+    pub cached_blocks: [usize; BLOCK_SIZES.len()],
+    /// Bytes handed out by the fallback heap, cached blocks count as used.
+    pub fallback_used: usize,
+    pub fallback_size: usize,
+    /// Bytes asked for by live allocations served from a size class.
+    pub class_requested: usize,
+    /// Bytes of the blocks serving them, at least `class_requested`.
+    pub class_allocated: usize,
+}
+
+impl BlockAllocStats {
+    /// Internal fragmentation, what rounding up to a size class is costing right now.
+    pub fn class_wasted(&self) -> usize {
+        self.class_allocated - self.class_requested
+    }
+}
+
+impl fmt::Display for BlockAllocStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "heap {} / {} bytes used, cached blocks:",
+            self.fallback_used, self.fallback_size
+        )?;
+        for (size, cached) in BLOCK_SIZES.iter().zip(self.cached_blocks) {
+            write!(f, " {size}B x{cached}")?;
+        }
+        write!(
+            f,
+            ", size classes waste {} of {} bytes",
+            self.class_wasted(),
+            self.class_allocated
+        )
+    }
+}
+
+/// Backs at least `min` bytes right past `top`, the end of the heap, and returns how many it
+/// backed. Returning less than `min` is fine, that memory is still added to the heap.
+pub type GrowFn = fn(top: *mut u8, min: usize) -> usize;
+
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    grow: Option<GrowFn>,
+    class_requested: usize,
+    class_allocated: usize,
 }
 
 impl FixedSizeBlockAllocator {
@@ -35,6 +101,9 @@ impl FixedSizeBlockAllocator {
         Self {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            grow: None,
+            class_requested: 0,
+            class_allocated: 0,
         }
     }
 
@@ -42,11 +111,97 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size)
     }
 
-    /// Allocates using the fallback allocator.
+    /// Lets the fallback heap extend itself through `grow` instead of failing when it's full.
+    pub fn set_grow(&mut self, grow: GrowFn) {
+        self.grow = Some(grow);
+    }
+
+    pub fn stats(&self) -> BlockAllocStats {
+        let mut cached_blocks = [0; BLOCK_SIZES.len()];
+        for (cached, head) in cached_blocks.iter_mut().zip(&self.list_heads) {
+            *cached = head.as_ref().map_or(0, |node| node.length());
+        }
+        BlockAllocStats {
+            cached_blocks,
+            fallback_used: self.fallback_allocator.used(),
+            fallback_size: self.fallback_allocator.size(),
+            class_requested: self.class_requested,
+            class_allocated: self.class_allocated,
+        }
+    }
+
+    /// Returns all but [`TRIM_RESERVE`] cached blocks of each size to the fallback heap.
+    ///
+    /// Returns how many bytes went back. Meant for idle time, it walks every free list.
+    pub fn trim(&mut self) -> usize {
+        let mut freed = 0;
+        for (index, head) in self.list_heads.iter_mut().enumerate() {
+            let mut cursor = head;
+            for _ in 0..TRIM_RESERVE {
+                match cursor {
+                    Some(node) => cursor = &mut node.next,
+                    None => break,
+                }
+            }
+            let mut rest = cursor.take();
+            while let Some(node) = rest {
+                rest = node.next.take();
+                let ptr = NonNull::from(node).cast();
+                unsafe { self.fallback_allocator.deallocate(ptr, block_layout(index)) };
+                freed += BLOCK_SIZES[index];
+            }
+        }
+        freed
+    }
+
+    /// Walks every free list checking that each node is a block inside the heap.
+    ///
+    /// Returns false if a node is out of bounds or misaligned for its size class, or if a list
+    /// has more nodes than the heap could hold which means it loops.
+    #[cfg(debug_assertions)]
+    pub fn check_integrity(&self) -> bool {
+        let bottom = self.fallback_allocator.bottom() as usize;
+        let top = self.fallback_allocator.top() as usize;
+        self.list_heads.iter().enumerate().all(|(index, head)| {
+            let block_size = BLOCK_SIZES[index];
+            let max_nodes = (top - bottom) / block_size;
+            let mut node = head
+                .as_deref()
+                .map_or(ptr::null(), |n| n as *const ListNode);
+            let mut count = 0;
+            while !node.is_null() {
+                let addr = node as usize;
+                if addr < bottom || addr + block_size > top || addr % block_size != 0 {
+                    return false;
+                }
+                count += 1;
+                if count > max_nodes {
+                    return false;
+                }
+                // Read the link as a raw pointer since it isn't known to be valid yet
+                node = unsafe { ptr::addr_of!((*node).next).cast::<*const ListNode>().read() };
+            }
+            true
+        })
+    }
+
+    /// Allocates using the fallback allocator, growing it once if it's full.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+        let Some(grow) = self.grow else {
+            return ptr::null_mut();
+        };
+        // Enough for the allocation even if none of the old top is free or it needs padding
+        let grown = grow(self.fallback_allocator.top(), layout.size() + layout.align());
+        if grown == 0 {
+            return ptr::null_mut();
+        }
+        unsafe { self.fallback_allocator.extend(grown) };
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
-            Err(_) => core::ptr::null_mut(),
+            Err(_) => ptr::null_mut(),
         }
     }
 }
@@ -57,6 +212,12 @@ impl Default for FixedSizeBlockAllocator {
     }
 }
 
+/// The layout blocks of `BLOCK_SIZES[index]` are taken from the fallback allocator with.
+fn block_layout(index: usize) -> Layout {
+    let block_size = BLOCK_SIZES[index];
+    Layout::from_size_align(block_size, block_size).unwrap()
+}
+
 /// Choose an appropriate block size for the given layout.
 ///
 /// Returns an index into the `BLOCK_SIZES` array.
@@ -70,19 +231,22 @@ unsafe impl GlobalAlloc for Mutex<FixedSizeBlockAllocator> {
         x86_64::instructions::interrupts::without_interrupts(|| {
             let mut alloc = self.spin_lock();
             match list_index(&layout) {
-                Some(index) => match alloc.list_heads[index].take() {
-                    Some(node) => {
-                        alloc.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
-                    }
-                    None => {
-                        let block_size = BLOCK_SIZES[index];
-
-                        let block_align = block_size;
-                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                        alloc.fallback_alloc(layout)
+                Some(index) => {
+                    let block = match alloc.list_heads[index].take() {
+                        Some(node) => {
+                            alloc.list_heads[index] = node.next.take();
+                            node as *mut ListNode as *mut u8
+                        }
+                        None => alloc.fallback_alloc(block_layout(index)),
+                    };
+                    if !block.is_null() {
+                        poison(block, BLOCK_SIZES[index], ALLOCATED_POISON);
+                        alloc.class_requested += layout.size();
+                        alloc.class_allocated += BLOCK_SIZES[index];
                     }
-                },
+                    block
+                }
+                // Large allocations are left alone, writing them would map the whole thing
                 None => alloc.fallback_alloc(layout),
             }
         })
@@ -93,11 +257,16 @@ unsafe impl GlobalAlloc for Mutex<FixedSizeBlockAllocator> {
             let mut alloc = self.spin_lock();
             match list_index(&layout) {
                 Some(index) => {
+                    alloc.class_requested -= layout.size();
+                    alloc.class_allocated -= BLOCK_SIZES[index];
+                    // Before the node is written so the free list stays intact
+                    poison(ptr, BLOCK_SIZES[index], FREED_POISON);
                     if let Some(n) = &alloc.list_heads[index]
                         && n.length() > 16
                     {
+                        // The block came from the fallback with the block layout, not `layout`
                         let ptr = NonNull::new(ptr).unwrap();
-                        alloc.fallback_allocator.deallocate(ptr, layout);
+                        alloc.fallback_allocator.deallocate(ptr, block_layout(index));
                     } else {
                         let new_node = ListNode {
                             next: alloc.list_heads[index].take(),
@@ -109,11 +278,169 @@ unsafe impl GlobalAlloc for Mutex<FixedSizeBlockAllocator> {
                         alloc.list_heads[index] = Some(&mut *new_node_ptr);
                     }
                 }
+                // Like on the alloc side large frees aren't poisoned, that would fault in any
+                // lazy pages of the allocation that were never touched
                 None => {
                     let ptr = NonNull::new(ptr).unwrap();
                     alloc.fallback_allocator.deallocate(ptr, layout);
                 }
             }
+            #[cfg(all(feature = "heap-check", debug_assertions))]
+            assert!(alloc.check_integrity(), "heap free lists are corrupted");
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use core::{
+        alloc::{GlobalAlloc, Layout},
+        ptr::addr_of_mut,
+    };
+
+    use super::{list_index, FixedSizeBlockAllocator, BLOCK_SIZES, TRIM_RESERVE};
+    #[cfg(feature = "heap-poison")]
+    use super::{ListNode, ALLOCATED_POISON, FREED_POISON};
+    use crate::util::r#async::mutex::Mutex;
+
+    #[repr(align(4096))]
+    struct Heap([u8; 16 * 1024]);
+
+    #[test_case]
+    fn integrity_catches_bad_link() {
+        static mut HEAP: Heap = Heap([0; 16 * 1024]);
+        let allocator = Mutex::new(FixedSizeBlockAllocator::new());
+        unsafe {
+            let heap = addr_of_mut!(HEAP);
+            allocator
+                .spin_lock()
+                .init((*heap).0.as_mut_ptr(), (*heap).0.len())
+        };
+
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let blocks = [(); 3].map(|_| unsafe { allocator.alloc(layout) });
+        for block in blocks {
+            unsafe { allocator.dealloc(block, layout) };
+        }
+        assert!(allocator.spin_lock().check_integrity());
+
+        // The last freed block is the head of the 64 byte list
+        unsafe { blocks[2].cast::<usize>().write(0xdead_b000) };
+        assert!(!allocator.spin_lock().check_integrity());
+    }
+
+    #[test_case]
+    fn classes_fit_requests() {
+        let class = |size| {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            list_index(&layout).map(|index| BLOCK_SIZES[index])
+        };
+        assert_eq!(class(200), Some(256));
+        assert_eq!(class(256), Some(256));
+        assert_eq!(class(257), Some(512));
+        assert_eq!(class(4096), Some(4096));
+        assert_eq!(class(4097), None);
+    }
+
+    #[test_case]
+    fn stats_count_class_waste() {
+        static mut HEAP: Heap = Heap([0; 16 * 1024]);
+        let allocator = Mutex::new(FixedSizeBlockAllocator::new());
+        unsafe {
+            let heap = addr_of_mut!(HEAP);
+            allocator
+                .spin_lock()
+                .init((*heap).0.as_mut_ptr(), (*heap).0.len())
+        };
+
+        let layout = Layout::from_size_align(200, 8).unwrap();
+        let block = unsafe { allocator.alloc(layout) };
+        let stats = allocator.spin_lock().stats();
+        assert_eq!(stats.class_requested, 200);
+        assert_eq!(stats.class_allocated, 256);
+        assert_eq!(stats.class_wasted(), 56);
+
+        unsafe { allocator.dealloc(block, layout) };
+        assert_eq!(allocator.spin_lock().stats().class_allocated, 0);
+    }
+
+    #[test_case]
+    fn trim_returns_blocks() {
+        static mut HEAP: Heap = Heap([0; 16 * 1024]);
+        let allocator = Mutex::new(FixedSizeBlockAllocator::new());
+        unsafe {
+            let heap = addr_of_mut!(HEAP);
+            allocator
+                .spin_lock()
+                .init((*heap).0.as_mut_ptr(), (*heap).0.len())
+        };
+
+        let layout = Layout::from_size_align(512, 8).unwrap();
+        let blocks = [(); 12].map(|_| unsafe { allocator.alloc(layout) });
+        assert!(blocks.iter().all(|b| !b.is_null()));
+        for block in blocks {
+            unsafe { allocator.dealloc(block, layout) };
+        }
+        let cached = allocator.spin_lock().stats().fallback_used;
+
+        let freed = allocator.spin_lock().trim();
+        assert_eq!(freed, (12 - TRIM_RESERVE) * 512);
+        let used = allocator.spin_lock().stats().fallback_used;
+        assert_eq!(cached - used, freed);
+
+        // The reserve is still served from the free list
+        let block = unsafe { allocator.alloc(layout) };
+        assert_eq!(allocator.spin_lock().stats().fallback_used, used);
+        unsafe { allocator.dealloc(block, layout) };
+    }
+
+    #[test_case]
+    fn full_heap_grows() {
+        const HALF: usize = 8 * 1024;
+        static mut HEAP: Heap = Heap([0; 16 * 1024]);
+        // Hands out the second half of `HEAP` once the first is full
+        fn grow(top: *mut u8, min: usize) -> usize {
+            let second_half = unsafe { addr_of_mut!(HEAP).cast::<u8>().add(HALF) };
+            assert_eq!(top, second_half);
+            assert!(min <= HALF);
+            HALF
+        }
+        let allocator = Mutex::new(FixedSizeBlockAllocator::new());
+        unsafe { allocator.spin_lock().init(addr_of_mut!(HEAP).cast(), HALF) };
+
+        let layout = Layout::from_size_align(6 * 1024, 8).unwrap();
+        let first = unsafe { allocator.alloc(layout) };
+        assert!(!first.is_null());
+        assert!(unsafe { allocator.alloc(layout) }.is_null());
+
+        allocator.spin_lock().set_grow(grow);
+        let second = unsafe { allocator.alloc(layout) };
+        assert!(!second.is_null());
+        assert_eq!(allocator.spin_lock().stats().fallback_size, 2 * HALF);
+    }
+
+    #[cfg(feature = "heap-poison")]
+    #[test_case]
+    fn blocks_are_poisoned() {
+        static mut HEAP: Heap = Heap([0; 16 * 1024]);
+        let allocator = Mutex::new(FixedSizeBlockAllocator::new());
+        unsafe {
+            let heap = addr_of_mut!(HEAP);
+            allocator
+                .spin_lock()
+                .init((*heap).0.as_mut_ptr(), (*heap).0.len())
+        };
+
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let block = unsafe { allocator.alloc(layout) };
+        let bytes = unsafe { core::slice::from_raw_parts(block, 64) };
+        assert!(bytes.iter().all(|&b| b == ALLOCATED_POISON));
+
+        unsafe { allocator.dealloc(block, layout) };
+        // Past the free list link everything reads as freed
+        let bytes = unsafe { core::slice::from_raw_parts(block, 64) };
+        let link = core::mem::size_of::<ListNode>();
+        assert!(bytes[link..].iter().all(|&b| b == FREED_POISON));
+        assert!(allocator.spin_lock().check_integrity());
+    }
+}
@@ -8,14 +8,20 @@ use x2apic::{
 };
 use x86_64::{
     addr::PhysAddrNotValid,
+    registers::model_specific::Msr,
     structures::paging::{mapper::MapToError, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
     PhysAddr, VirtAddr,
 };
 
 use crate::{
+    cpu,
     interrupts::InterruptIndex,
-    memory::{mapping::MAPPER, PAGE_ALLOCATOR},
+    memory::{
+        mapping::{map_mmio, MapMmioError, MAPPER},
+        PAGE_ALLOCATOR,
+    },
     pic::PICS,
+    time::{self, TimeSource},
     util::{
         once::{OnceLock, TryInitError},
         r#async::mutex::Mutex,
@@ -27,6 +33,13 @@ pub static LAPIC: OnceLock<Mutex<LocalApic>> = OnceLock::new();
 pub static KERNEL_APIC_ADDR: OnceLock<VirtAddr> = OnceLock::new();
 pub const KERNEL_APIC_LEN: usize = 4096;
 
+/// Size of the IOREGSEL/IOWIN register window.
+const IO_APIC_MMIO_LEN: usize = 0x20;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
 #[derive(Error, Debug)]
 pub enum ApicInitError {
     #[error(
@@ -38,8 +51,8 @@ pub enum ApicInitError {
     FailedToMapLApic(MapToError<Size4KiB>),
     #[error("Failed to build lapic: {0}")]
     LapicBuildFailed(&'static str),
-    #[error("Couldn't map page for IoApic")]
-    FailedToMapIoApic(MapToError<Size4KiB>),
+    #[error("Couldn't map page for IoApic: {0}")]
+    FailedToMapIoApic(MapMmioError),
     #[error("Lapic already init")]
     LapicAlreadyInit(#[from] TryInitError),
 }
@@ -49,40 +62,23 @@ pub fn init(apic_info: &ApicInfo<'static, Global>) -> Result<(), ApicInitError>
     disable_8259();
 
     // SETUP LAPIC
-    let apic_phys_addr = unsafe { xapic_base() };
-    debug_assert_eq!(apic_phys_addr, apic_info.local_apic_address);
-    let apic_phys_addr =
-        PhysAddr::try_new(apic_phys_addr).map_err(ApicInitError::BadLapicAddress)?;
-    let apic_phys_frame = PhysFrame::<Size4KiB>::containing_address(apic_phys_addr);
-
-    let apic_virt_address = *KERNEL_APIC_ADDR.get();
-
-    let page = Page::containing_address(apic_virt_address);
-
-    unsafe {
-        MAPPER.spin_lock().map_to(
-            page,
-            apic_phys_frame,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::NO_EXECUTE,
-            &mut *PAGE_ALLOCATOR.get().spin_lock(),
-        )
-    }
-    .map_err(ApicInitError::FailedToMapLApic)?
-    .flush();
-
-    let lapic = LocalApicBuilder::new()
+    let mut builder = LocalApicBuilder::new();
+    builder
         .timer_vector(InterruptIndex::Timer as usize)
         .error_vector(InterruptIndex::LapicErr as usize)
         .spurious_vector(InterruptIndex::Spurious as usize)
-        .set_xapic_base(apic_virt_address.as_u64())
         .timer_mode(TimerMode::Periodic)
         .timer_initial(65535)
-        .timer_divide(TimerDivide::Div256)
-        .build()
-        .map_err(ApicInitError::LapicBuildFailed)?;
+        .timer_divide(TimerDivide::Div256);
+    // The builder picks x2APIC on its own whenever CPUID has it, so the mode has to match
+    if cpu::features().x2apic {
+        // Registers are MSRs, there's nothing to map
+        unsafe { enable_x2apic_mode() };
+    } else {
+        builder.set_xapic_base(map_xapic(apic_info)?.as_u64());
+    }
+    let lapic = builder.build().map_err(ApicInitError::LapicBuildFailed)?;
+    trace!(x2apic = x2apic_enabled(), "lapic mode");
 
     // Not using Lapic Timer
     //unsafe {
@@ -96,28 +92,11 @@ pub fn init(apic_info: &ApicInfo<'static, Global>) -> Result<(), ApicInitError>
         let io_apic_phys_addr = PhysAddr::new(io_apic.address as u64);
 
         // Map io apic
-        let io_apic_phys_frame = PhysFrame::<Size4KiB>::containing_address(io_apic_phys_addr);
-
-        let apic_virt_address = VirtAddr::new(io_apic_phys_addr.as_u64());
-
-        let page = Page::containing_address(apic_virt_address);
+        let io_apic_virt_addr = map_mmio(io_apic_phys_addr, IO_APIC_MMIO_LEN)
+            .map_err(ApicInitError::FailedToMapIoApic)?;
 
         unsafe {
-            MAPPER.spin_lock().map_to(
-                page,
-                io_apic_phys_frame,
-                PageTableFlags::PRESENT
-                    | PageTableFlags::WRITABLE
-                    | PageTableFlags::NO_CACHE
-                    | PageTableFlags::NO_EXECUTE,
-                &mut *PAGE_ALLOCATOR.get().spin_lock(),
-            )
-        }
-        .map_err(ApicInitError::FailedToMapIoApic)?
-        .flush();
-
-        unsafe {
-            let mut io = IoApic::new(io_apic_phys_addr.as_u64());
+            let mut io = IoApic::new(io_apic_virt_addr.as_u64());
             let offset = 32;
             io.init(offset); // 16
 
@@ -159,6 +138,36 @@ pub fn init(apic_info: &ApicInfo<'static, Global>) -> Result<(), ApicInitError>
             io.set_table_entry(InterruptIndex::Keyboard as u8 - offset, entry);
             io.enable_irq(InterruptIndex::Keyboard as u8 - offset);
 
+            // Setup serial redirect
+            let mut entry = RedirectionTableEntry::default();
+            entry.set_dest(lapic.id() as u8);
+            entry.set_vector(InterruptIndex::Serial as u8);
+            entry.set_flags(IrqFlags::LEVEL_TRIGGERED);
+            io.set_table_entry(InterruptIndex::Serial as u8 - offset, entry);
+            io.enable_irq(InterruptIndex::Serial as u8 - offset);
+
+            // Setup mouse redirect
+            let mut entry = RedirectionTableEntry::default();
+            entry.set_dest(lapic.id() as u8);
+            entry.set_vector(InterruptIndex::Mouse as u8);
+            entry.set_flags(IrqFlags::LEVEL_TRIGGERED);
+            io.set_table_entry(InterruptIndex::Mouse as u8 - offset, entry);
+            io.enable_irq(InterruptIndex::Mouse as u8 - offset);
+
+            // Setup PIT redirect, ISA IRQ0 is usually overridden onto another GSI
+            if time::time_source() == TimeSource::Pit {
+                let gsi = redirects
+                    .iter()
+                    .find(|redirect| redirect.isa_source == 0)
+                    .map_or(0, |redirect| redirect.global_system_interrupt as u8);
+                let mut entry = RedirectionTableEntry::default();
+                entry.set_dest(lapic.id() as u8);
+                entry.set_vector(InterruptIndex::Timer as u8);
+                entry.set_flags(IrqFlags::empty());
+                io.set_table_entry(gsi, entry);
+                io.enable_irq(gsi);
+            }
+
             // Setup RTC redirect
             let mut entry = RedirectionTableEntry::default();
             entry.set_dest(lapic.id() as u8);
@@ -172,6 +181,49 @@ pub fn init(apic_info: &ApicInfo<'static, Global>) -> Result<(), ApicInitError>
     Ok(())
 }
 
+/// Maps the xAPIC registers at [`KERNEL_APIC_ADDR`].
+fn map_xapic(apic_info: &ApicInfo<'static, Global>) -> Result<VirtAddr, ApicInitError> {
+    let apic_phys_addr = unsafe { xapic_base() };
+    debug_assert_eq!(apic_phys_addr, apic_info.local_apic_address);
+    let apic_phys_addr =
+        PhysAddr::try_new(apic_phys_addr).map_err(ApicInitError::BadLapicAddress)?;
+    let apic_phys_frame = PhysFrame::<Size4KiB>::containing_address(apic_phys_addr);
+
+    let apic_virt_address = *KERNEL_APIC_ADDR.get();
+
+    let page = Page::containing_address(apic_virt_address);
+
+    unsafe {
+        MAPPER.spin_lock().map_to(
+            page,
+            apic_phys_frame,
+            PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::NO_CACHE
+                | PageTableFlags::NO_EXECUTE,
+            &mut *PAGE_ALLOCATOR.get().spin_lock(),
+        )
+    }
+    .map_err(ApicInitError::FailedToMapLApic)?
+    .flush();
+    Ok(apic_virt_address)
+}
+
+/// Switches the local APIC to x2APIC mode without touching anything else about it.
+///
+/// # Safety
+/// The CPU has to support x2APIC, and nothing may be using the xAPIC MMIO registers.
+unsafe fn enable_x2apic_mode() {
+    let mut base = Msr::new(IA32_APIC_BASE);
+    // Going to x2APIC is only allowed from an enabled xAPIC
+    base.write(base.read() | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+}
+
+/// Whether the local APIC is in x2APIC mode, with its registers as MSRs.
+pub fn x2apic_enabled() -> bool {
+    unsafe { Msr::new(IA32_APIC_BASE).read() & APIC_BASE_X2APIC != 0 }
+}
+
 fn disable_8259() {
     unsafe {
         // Disable 8259 immediately, thanks kennystrawnmusic
@@ -209,3 +261,70 @@ fn disable_8259() {
         */
     };
 }
+
+#[cfg(test)]
+mod test {
+    use futures::{FutureExt, StreamExt};
+    use x86_64::instructions::{interrupts, port::Port};
+
+    use core::sync::atomic::Ordering;
+
+    use super::{x2apic_enabled, LAPIC};
+    use crate::{cpu, keyboard::ScancodeStream, util::r#async::sleep_future::MONOTONIC_TIME};
+
+    const PS2_DATA: u16 = 0x60;
+    const PS2_COMMAND: u16 = 0x64;
+    const PS2_INPUT_FULL: u8 = 1 << 1;
+    /// Makes the controller hand the next data byte back as if the keyboard sent it.
+    const PS2_WRITE_KEYBOARD_OUTPUT: u8 = 0xd2;
+
+    #[test_case]
+    fn keyboard_interrupt_arrives() {
+        if LAPIC.try_get().is_err() {
+            // Legacy PIC mode, nothing routed through the IOAPIC
+            return;
+        }
+        let mut scancodes = ScancodeStream::new();
+
+        let mut status = Port::<u8>::new(PS2_COMMAND);
+        let mut command = Port::<u8>::new(PS2_COMMAND);
+        let mut data = Port::<u8>::new(PS2_DATA);
+        unsafe {
+            while status.read() & PS2_INPUT_FULL != 0 {}
+            command.write(PS2_WRITE_KEYBOARD_OUTPUT);
+            while status.read() & PS2_INPUT_FULL != 0 {}
+            data.write(0x1e);
+        }
+
+        // The RTC keeps ticking so this can't halt forever
+        let mut received = None;
+        for _ in 0..100 {
+            interrupts::enable_and_hlt();
+            interrupts::disable();
+            if let Some(scancode) = scancodes.next().now_or_never() {
+                received = scancode;
+                break;
+            }
+        }
+        assert_eq!(received, Some(0x1e));
+    }
+
+    #[test_case]
+    fn lapic_mode_follows_cpuid() {
+        if LAPIC.try_get().is_err() {
+            return;
+        }
+        // Run with `-cpu max` to get the x2APIC path under QEMU
+        assert_eq!(x2apic_enabled(), cpu::features().x2apic);
+
+        // The clock only keeps ticking if its interrupts are acknowledged
+        let start = MONOTONIC_TIME.load(Ordering::Acquire);
+        for _ in 0..2 {
+            let now = MONOTONIC_TIME.load(Ordering::Acquire);
+            while MONOTONIC_TIME.load(Ordering::Acquire) == now {
+                x86_64::instructions::hlt();
+            }
+        }
+        assert!(MONOTONIC_TIME.load(Ordering::Acquire) - start >= 2);
+    }
+}
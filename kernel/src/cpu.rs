@@ -0,0 +1,157 @@
+use alloc::string::{String, ToString};
+use raw_cpuid::{CpuId, CpuIdReader, FeatureInfo, Hypervisor};
+use tracing::info;
+
+use crate::util::once::OnceLock;
+
+static FEATURES: OnceLock<CpuFeatures> = OnceLock::new();
+
+/// What the boot CPU reports it supports, for telling machines apart in bug reports.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CpuFeatures {
+    pub vendor: String,
+    pub brand: String,
+    pub sse: bool,
+    pub sse2: bool,
+    pub avx: bool,
+    /// No-execute page flag.
+    pub nx: bool,
+    pub rdrand: bool,
+    pub x2apic: bool,
+    pub tsc: bool,
+    /// What we're virtualized under, `None` on bare metal.
+    pub hypervisor: Option<Hypervisor>,
+}
+
+/// The features of the CPU this runs on, read once and cached.
+pub fn features() -> &'static CpuFeatures {
+    FEATURES.get_or_init(|| features_from(CpuId::new()))
+}
+
+/// Logs [`features`] so the boot log says what hardware paths are available.
+pub fn init() {
+    info!("{:?}", features());
+}
+
+fn features_from<R: CpuIdReader>(cpuid: CpuId<R>) -> CpuFeatures {
+    let info = cpuid.get_feature_info();
+    let extended = cpuid.get_extended_processor_and_feature_identifiers();
+    let has = |f: fn(&FeatureInfo) -> bool| info.as_ref().is_some_and(f);
+
+    CpuFeatures {
+        vendor: cpuid
+            .get_vendor_info()
+            .map(|vendor| vendor.as_str().to_string())
+            .unwrap_or_default(),
+        brand: cpuid
+            .get_processor_brand_string()
+            .map(|brand| brand.as_str().trim().to_string())
+            .unwrap_or_default(),
+        sse: has(FeatureInfo::has_sse),
+        sse2: has(FeatureInfo::has_sse2),
+        avx: has(FeatureInfo::has_avx),
+        nx: extended.is_some_and(|ext| ext.has_execute_disable()),
+        rdrand: has(FeatureInfo::has_rdrand),
+        x2apic: has(FeatureInfo::has_x2apic),
+        tsc: has(FeatureInfo::has_tsc),
+        hypervisor: cpuid.get_hypervisor_info().map(|info| info.identify()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use raw_cpuid::{CpuId, CpuIdResult, Hypervisor};
+
+    use super::{features, features_from};
+
+    /// Packs 16 bytes of a string into registers the way CPUID returns them.
+    fn string_leaf(bytes: &[u8]) -> CpuIdResult {
+        let reg = |i: usize| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+        CpuIdResult {
+            eax: reg(0),
+            ebx: reg(1),
+            ecx: reg(2),
+            edx: reg(3),
+        }
+    }
+
+    fn mock_cpuid(leaf: u32, _subleaf: u32) -> CpuIdResult {
+        const BRAND: &[u8; 48] =
+            b"Mock CPU @ 1.00GHz\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+        let empty = CpuIdResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        };
+        match leaf {
+            // Highest leaf and "GenuineIntel" in ebx, edx, ecx order
+            0x0 => CpuIdResult {
+                eax: 1,
+                ebx: u32::from_le_bytes(*b"Genu"),
+                ecx: u32::from_le_bytes(*b"ntel"),
+                edx: u32::from_le_bytes(*b"ineI"),
+            },
+            // x2APIC, AVX, RDRAND and hypervisor present in ecx, TSC, SSE and SSE2 in edx
+            0x1 => CpuIdResult {
+                ecx: 1 << 21 | 1 << 28 | 1 << 30 | 1 << 31,
+                edx: 1 << 4 | 1 << 25 | 1 << 26,
+                ..empty
+            },
+            0x4000_0000 => CpuIdResult {
+                eax: 0x4000_0001,
+                ebx: u32::from_le_bytes(*b"KVMK"),
+                ecx: u32::from_le_bytes(*b"VMKV"),
+                edx: u32::from_le_bytes(*b"M\0\0\0"),
+            },
+            0x8000_0000 => CpuIdResult {
+                eax: 0x8000_0004,
+                ..empty
+            },
+            // NX
+            0x8000_0001 => CpuIdResult {
+                edx: 1 << 20,
+                ..empty
+            },
+            0x8000_0002..=0x8000_0004 => {
+                let start = (leaf - 0x8000_0002) as usize * 16;
+                string_leaf(&BRAND[start..start + 16])
+            }
+            _ => empty,
+        }
+    }
+
+    #[test_case]
+    fn parses_mocked_leaves() {
+        let features = features_from(CpuId::with_cpuid_reader(mock_cpuid));
+        assert_eq!(features.vendor, "GenuineIntel");
+        assert_eq!(features.brand, "Mock CPU @ 1.00GHz");
+        assert!(features.sse && features.sse2 && features.avx);
+        assert!(features.nx && features.rdrand && features.x2apic && features.tsc);
+        assert_eq!(features.hypervisor, Some(Hypervisor::KVM));
+    }
+
+    #[test_case]
+    fn missing_leaves_report_nothing() {
+        let features = features_from(CpuId::with_cpuid_reader(|_, _| CpuIdResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        }));
+        assert!(!features.sse && !features.nx && !features.x2apic && !features.tsc);
+        assert_eq!(features.brand, "");
+        assert_eq!(features.hypervisor, None);
+    }
+
+    #[test_case]
+    fn detects_qemu() {
+        let cpu = features();
+        assert!(!cpu.vendor.is_empty());
+        assert!(cpu.sse2 && cpu.tsc);
+        // Tests run under QEMU without an accelerator
+        assert_eq!(cpu.hypervisor, Some(Hypervisor::QEMU));
+        // Cached after the first read
+        assert!(core::ptr::eq(cpu, features()));
+    }
+}
@@ -0,0 +1,387 @@
+//! A minimal GDB remote serial protocol stub, so GDB can attach over COM2 without QEMU's
+//! gdbserver.
+//!
+//! Only stopping at breakpoints is supported. Once [`attach`]ed, a breakpoint hands control to
+//! GDB over [`ComPort::Com2`], which can read the registers and memory and then continue.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use thiserror::Error;
+use x86_64::{
+    structures::{idt::InterruptStackFrame, paging::Translate},
+    VirtAddr,
+};
+
+use crate::{memory::mapping::MAPPER, serial::ComPort};
+
+/// Longest packet in either direction, sent to GDB as `PacketSize`.
+pub const PACKET_LEN: usize = 1024;
+
+/// Reported for every stop, breakpoints are the only way in.
+const SIGTRAP: u8 = 5;
+/// EFAULT, for memory that can't be read.
+const BAD_ADDRESS: u8 = 14;
+
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+/// Makes breakpoints wait for GDB on COM2 instead of just being logged.
+pub fn attach() {
+    ATTACHED.store(true, Ordering::SeqCst);
+}
+
+pub fn attached() -> bool {
+    ATTACHED.load(Ordering::SeqCst)
+}
+
+/// A byte pipe to GDB.
+pub trait Connection {
+    /// Blocks until a byte arrives.
+    fn read(&mut self) -> u8;
+    fn write(&mut self, byte: u8);
+}
+
+impl Connection for ComPort {
+    fn read(&mut self) -> u8 {
+        self.read_byte()
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.write_byte(byte)
+    }
+}
+
+/// The registers of the stopped code, in the order GDB's amd64 `g` packet has them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Registers {
+    /// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8 to r15.
+    pub gprs: [u64; 16],
+    pub rip: u64,
+    pub rflags: u64,
+    /// cs, ss, ds, es, fs, gs.
+    pub segments: [u32; 6],
+}
+
+impl Registers {
+    const RSP: usize = 7;
+
+    /// What the interrupt stack frame has, the x86-interrupt ABI doesn't hand over the rest.
+    pub fn from_frame(frame: &InterruptStackFrame) -> Self {
+        let mut registers = Self {
+            rip: frame.instruction_pointer.as_u64(),
+            rflags: frame.cpu_flags.bits(),
+            ..Default::default()
+        };
+        registers.gprs[Self::RSP] = frame.stack_pointer.as_u64();
+        registers.segments[0] = frame.code_segment.0.into();
+        registers.segments[1] = frame.stack_segment.0.into();
+        registers
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum GdbError {
+    #[error("Packet checksum was {got:#04x} but its data sums to {expected:#04x}")]
+    BadChecksum { expected: u8, got: u8 },
+    #[error("Packet longer than {PACKET_LEN} bytes")]
+    TooLong,
+    #[error("Expected hex digits in packet")]
+    BadHex,
+}
+
+/// Answers GDB until it says to continue.
+///
+/// Runs with whatever stopped held, so it mustn't allocate or wait on locks.
+pub fn serve(connection: &mut impl Connection, registers: &Registers) {
+    let mut packet = [0; PACKET_LEN];
+    let mut response = Response::new();
+    loop {
+        let len = match read_packet(connection, &mut packet) {
+            Ok(len) => len,
+            Err(_) => {
+                // Asks GDB to send it again
+                connection.write(b'-');
+                continue;
+            }
+        };
+        connection.write(b'+');
+        response.clear();
+        if handle(&packet[..len], registers, &mut response) == Action::Continue {
+            return;
+        }
+        write_packet(connection, response.as_bytes());
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Reply,
+    Continue,
+}
+
+fn handle(packet: &[u8], registers: &Registers, response: &mut Response) -> Action {
+    match packet {
+        b"?" => {
+            response.push(b'S');
+            response.push_hex(&[SIGTRAP]);
+        }
+        b"g" => {
+            for gpr in registers.gprs {
+                response.push_hex(&gpr.to_le_bytes());
+            }
+            response.push_hex(&registers.rip.to_le_bytes());
+            response.push_hex(&(registers.rflags as u32).to_le_bytes());
+            for segment in registers.segments {
+                response.push_hex(&segment.to_le_bytes());
+            }
+        }
+        [b'm', args @ ..] => match parse_memory_args(args) {
+            Ok((addr, len)) => {
+                // Each byte is two hex digits
+                let len = len.min(PACKET_LEN as u64 / 2);
+                if !read_memory(addr, len, response) {
+                    response.clear();
+                    response.push(b'E');
+                    response.push_hex(&[BAD_ADDRESS]);
+                }
+            }
+            Err(_) => {
+                response.push(b'E');
+                response.push_hex(&[BAD_ADDRESS]);
+            }
+        },
+        b"c" => return Action::Continue,
+        _ if packet.starts_with(b"qSupported") => {
+            response.push_str("PacketSize=");
+            response.push_number(PACKET_LEN as u64);
+        }
+        b"qAttached" => response.push(b'1'),
+        [b'H', ..] => response.push_str("OK"),
+        // An empty response means unsupported
+        _ => {}
+    }
+    Action::Reply
+}
+
+/// Reads the next `$data#checksum` packet into `buf`, returning the length of the data.
+///
+/// Anything before the `$`, like GDB's acks, is skipped.
+fn read_packet(connection: &mut impl Connection, buf: &mut [u8]) -> Result<usize, GdbError> {
+    while connection.read() != b'$' {}
+    let mut len = 0;
+    let mut sum = 0u8;
+    loop {
+        let byte = connection.read();
+        if byte == b'#' {
+            break;
+        }
+        *buf.get_mut(len).ok_or(GdbError::TooLong)? = byte;
+        len += 1;
+        sum = sum.wrapping_add(byte);
+    }
+    let digits = [connection.read(), connection.read()];
+    let got = parse_hex(&digits)? as u8;
+    if got != sum {
+        return Err(GdbError::BadChecksum { expected: sum, got });
+    }
+    Ok(len)
+}
+
+fn write_packet(connection: &mut impl Connection, data: &[u8]) {
+    connection.write(b'$');
+    for &byte in data {
+        connection.write(byte);
+    }
+    connection.write(b'#');
+    let sum = checksum(data);
+    connection.write(HEX_DIGITS[(sum >> 4) as usize]);
+    connection.write(HEX_DIGITS[(sum & 0xf) as usize]);
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Splits the `addr,length` of an `m` packet.
+fn parse_memory_args(args: &[u8]) -> Result<(u64, u64), GdbError> {
+    let comma = args
+        .iter()
+        .position(|&b| b == b',')
+        .ok_or(GdbError::BadHex)?;
+    Ok((parse_hex(&args[..comma])?, parse_hex(&args[comma + 1..])?))
+}
+
+fn parse_hex(digits: &[u8]) -> Result<u64, GdbError> {
+    if digits.is_empty() || digits.len() > 16 {
+        return Err(GdbError::BadHex);
+    }
+    digits.iter().try_fold(0, |value, &digit| {
+        let digit = (digit as char).to_digit(16).ok_or(GdbError::BadHex)?;
+        Ok(value << 4 | digit as u64)
+    })
+}
+
+/// Hex encodes `len` bytes at `addr` into `response`, or returns false if some aren't mapped.
+///
+/// Only tries the page table lock, a breakpoint may have hit while it was held.
+fn read_memory(addr: u64, len: u64, response: &mut Response) -> bool {
+    let Some(mapper) = MAPPER.try_lock() else {
+        return false;
+    };
+    for addr in addr..addr.saturating_add(len) {
+        let mapped = VirtAddr::try_new(addr).is_ok_and(|a| mapper.translate_addr(a).is_some());
+        if !mapped {
+            return false;
+        }
+        let byte = unsafe { (addr as *const u8).read_volatile() };
+        response.push_hex(&[byte]);
+    }
+    true
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Fixed size packet data, since the stub can't count on the heap.
+struct Response {
+    buf: [u8; PACKET_LEN],
+    len: usize,
+}
+
+impl Response {
+    const fn new() -> Self {
+        Self {
+            buf: [0; PACKET_LEN],
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Drops what doesn't fit, every response is sized to fit well within [`PACKET_LEN`].
+    fn push(&mut self, byte: u8) {
+        if let Some(slot) = self.buf.get_mut(self.len) {
+            *slot = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        s.bytes().for_each(|b| self.push(b));
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(HEX_DIGITS[(byte >> 4) as usize]);
+            self.push(HEX_DIGITS[(byte & 0xf) as usize]);
+        }
+    }
+
+    /// `value` as big endian hex without leading zeros, how GDB writes numbers.
+    fn push_number(&mut self, value: u64) {
+        let digits = (64 - value.leading_zeros()).div_ceil(4).max(1);
+        for i in (0..digits).rev() {
+            self.push(HEX_DIGITS[(value >> (i * 4) & 0xf) as usize]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{format, string::String, vec::Vec};
+    use core::fmt::Write;
+
+    use super::{checksum, serve, Connection, Registers};
+
+    struct Mock {
+        input: Vec<u8>,
+        pos: usize,
+        output: Vec<u8>,
+    }
+
+    impl Mock {
+        fn new(packets: &[&str]) -> Self {
+            let mut input = String::new();
+            for data in packets {
+                write!(input, "${data}#{:02x}", checksum(data.as_bytes())).unwrap();
+            }
+            Self {
+                input: input.into_bytes(),
+                pos: 0,
+                output: Vec::new(),
+            }
+        }
+
+        /// The data of each response packet in order.
+        fn responses(&self) -> Vec<&str> {
+            let output = core::str::from_utf8(&self.output).unwrap();
+            output
+                .split('$')
+                .skip(1)
+                .map(|frame| {
+                    let (data, sum) = frame.split_once('#').expect("frame has no checksum");
+                    assert_eq!(&sum[..2], format!("{:02x}", checksum(data.as_bytes())));
+                    data
+                })
+                .collect()
+        }
+    }
+
+    impl Connection for Mock {
+        fn read(&mut self) -> u8 {
+            let byte = *self.input.get(self.pos).expect("stub read past its input");
+            self.pos += 1;
+            byte
+        }
+
+        fn write(&mut self, byte: u8) {
+            self.output.push(byte);
+        }
+    }
+
+    #[test_case]
+    fn read_registers_frame() {
+        let registers = Registers {
+            rip: 0x1122_3344_5566_7788,
+            ..Default::default()
+        };
+        let mut gdb = Mock::new(&["g", "c"]);
+        serve(&mut gdb, &registers);
+
+        assert_eq!(gdb.output.first(), Some(&b'+'));
+        assert_eq!(gdb.output.last(), Some(&b'+'));
+        let responses = gdb.responses();
+        assert_eq!(responses.len(), 1);
+        // 16 gprs and rip at 8 bytes, eflags and 6 segments at 4, two digits a byte
+        assert_eq!(responses[0].len(), (17 * 8 + 7 * 4) * 2);
+        assert_eq!(&responses[0][16 * 16..17 * 16], "8877665544332211");
+    }
+
+    #[test_case]
+    fn handshake_and_memory_read() {
+        static BYTES: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
+        let read = format!("m{:x},4", BYTES.as_ptr() as u64);
+        let mut gdb = Mock::new(&["qSupported:swbreak+", "?", &read, "m0,4", "c"]);
+        serve(&mut gdb, &Registers::default());
+
+        assert_eq!(
+            gdb.responses(),
+            ["PacketSize=400", "S05", "deadbeef", "E0e"]
+        );
+    }
+
+    #[test_case]
+    fn bad_checksum_is_nacked() {
+        let mut gdb = Mock::new(&["c"]);
+        let mut input = b"$?#00".to_vec();
+        input.append(&mut gdb.input);
+        gdb.input = input;
+        serve(&mut gdb, &Registers::default());
+
+        assert_eq!(gdb.output, b"-+");
+    }
+}
@@ -0,0 +1,84 @@
+use core::fmt;
+
+use crate::{
+    allocator::{self, BLOCK_SIZES},
+    memory::{mapping::MMIO_REGIONS, PAGE_ALLOCATOR},
+};
+
+/// Where the kernel's memory is, from the frame allocator, the heap and the mmio window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryReport {
+    pub total_frames: u64,
+    pub free_frames: u64,
+    pub heap_size: usize,
+    /// Includes [`Self::heap_cached`].
+    pub heap_used: usize,
+    /// Freed blocks the heap holds on to for reuse.
+    pub heap_cached: usize,
+    pub mmio_regions: usize,
+}
+
+/// Gathers a [`MemoryReport`], taking each allocator's lock in turn.
+pub fn memory_report() -> MemoryReport {
+    let (total_frames, free_frames) = {
+        let frames = PAGE_ALLOCATOR.get().spin_lock();
+        (frames.total_frames(), frames.free_frames())
+    };
+    let heap = allocator::stats();
+    let heap_cached = BLOCK_SIZES
+        .iter()
+        .zip(heap.cached_blocks)
+        .map(|(size, count)| size * count)
+        .sum();
+    let mmio_regions = MMIO_REGIONS.spin_lock().region_count();
+    MemoryReport {
+        total_frames,
+        free_frames,
+        heap_size: heap.fallback_size,
+        heap_used: heap.fallback_used,
+        heap_cached,
+        mmio_regions,
+    }
+}
+
+impl fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mib = |frames: u64| frames * 4 / 1024;
+        writeln!(
+            f,
+            "frames: {} of {} free ({} of {} MiB)",
+            self.free_frames,
+            self.total_frames,
+            mib(self.free_frames),
+            mib(self.total_frames)
+        )?;
+        writeln!(
+            f,
+            "heap: {} of {} bytes used, {} cached",
+            self.heap_used, self.heap_size, self.heap_cached
+        )?;
+        write!(f, "mmio: {} regions mapped", self.mmio_regions)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{boxed::Box, format};
+
+    use super::memory_report;
+
+    #[test_case]
+    fn report_is_consistent() {
+        let boxed = Box::new([0u8; 64]);
+        let report = memory_report();
+        assert!(report.free_frames <= report.total_frames);
+        assert!(report.total_frames > 0);
+        assert!(report.heap_cached <= report.heap_used);
+        assert!(report.heap_used <= report.heap_size);
+        assert!(report.heap_used >= boxed.len());
+
+        let text = format!("{report}");
+        assert!(text.contains(&format!("of {} free", report.total_frames)));
+        assert!(text.contains(&format!("{} regions mapped", report.mmio_regions)));
+    }
+}
@@ -1,24 +1,34 @@
-use core::{f32::consts::PI, time::Duration};
+use core::{f32::consts::PI, sync::atomic::Ordering, time::Duration};
 
-use alloc::format;
-use chrono::Timelike;
+use alloc::{format, string::String};
+use chrono::{Datelike, Timelike};
 use embedded_graphics::{
-    mono_font::{ascii::FONT_9X15, MonoTextStyle},
+    mono_font::{ascii::FONT_9X15, MonoFont, MonoTextStyle},
     pixelcolor::Rgb888,
     prelude::*,
-    primitives::{Circle, Line, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle},
+    primitives::{Arc, Circle, Line, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle},
     text::Text,
 };
 use libm::{cosf, sinf};
-use tracing::info;
 
-use crate::{framebuffer::DISPLAY, rtc::RTC, util::r#async::sleep};
+use crate::{
+    framebuffer::DISPLAY,
+    rtc::{to_local, RTC, TIMER_FREQ},
+    util::r#async::{sleep, sleep_future::MONOTONIC_TIME},
+};
 
 const MARGIN: u32 = 10;
-
-#[tracing::instrument]
+/// Font of the digital clock and date unless [`draw_clock`] is given another.
+pub const DEFAULT_FONT: &MonoFont<'static> = &FONT_9X15;
+
+/// Draws an analog and digital clock in the top right corner, forever.
+///
+/// With `smooth` the second hand sweeps between RTC seconds using the monotonic clock instead
+/// of jumping once a second, at the cost of redrawing every frame. With `show_date` the date
+/// and weekday are shown below the center. Both are written in `font`.
+#[tracing::instrument(skip(font))]
 #[allow(unused_must_use)]
-pub async fn draw_clock() {
+pub async fn draw_clock(smooth: bool, show_date: bool, font: &'static MonoFont<'static>) {
     let (clock_face, crop) = {
         let mut disp = DISPLAY.get().lock().await;
         let target = disp.as_mut();
@@ -35,11 +45,29 @@ pub async fn draw_clock() {
     };
     let center_clock_face = Circle::with_center(clock_face.center(), 9)
         .into_styled(PrimitiveStyle::with_fill(Rgb888::WHITE));
-    let mut last_time = RTC.lock().await.read_date_time().time();
+    let mut last_time = to_local(RTC.lock().await.read_date_time()).time();
+    // When the RTC was last seen changing seconds, which the sweep is interpolated from
+    let mut second_start = MONOTONIC_TIME.load(Ordering::Acquire);
+    // What the last frame drew, so the next one only has to undo that
+    let mut previous: Option<Hands> = None;
+    let mut previous_text = String::new();
+    let mut previous_date = String::new();
+    {
+        let mut disp = DISPLAY.get().lock().await;
+        disp.begin_frame();
+        let target = &mut disp.cropped(&crop);
+        target.clear(Rgb888::BLACK);
+        draw_face(target, &clock_face);
+        disp.end_frame();
+    }
     loop {
-        let time = RTC.lock().await.read_date_time().time();
+        let date_time = to_local(RTC.lock().await.read_date_time());
+        let time = date_time.time();
+        let now = MONOTONIC_TIME.load(Ordering::Acquire);
 
-        if time == last_time {
+        if time != last_time {
+            second_start = now;
+        } else if !smooth {
             sleep(Duration::from_millis(50)).await;
             continue;
         }
@@ -51,29 +79,67 @@ pub async fn draw_clock() {
             time.minute(),
             time.second()
         );
+        // The weekday comes from the local date since the offset can move it off the RTC's
+        let date_text = if show_date {
+            format!(
+                "{:04}-{:02}-{:02} {}",
+                date_time.year(),
+                date_time.month(),
+                date_time.day(),
+                date_time.weekday()
+            )
+        } else {
+            String::new()
+        };
 
         // Calculate the position of the three clock hands in radians.
-        let hours_radians = hour_to_angle(time.hour());
-        let minutes_radians = sexagesimal_to_angle(time.minute());
-        let seconds_radians = sexagesimal_to_angle(time.second());
+        let fraction = if smooth {
+            second_fraction(now.wrapping_sub(second_start))
+        } else {
+            0.0
+        };
+        let hands = Hands {
+            hours: hour_to_angle(time.hour()),
+            minutes: sexagesimal_to_angle(time.minute() as f32),
+            seconds: sexagesimal_to_angle(time.second() as f32 + fraction),
+        };
 
+        if previous != Some(hands)
+            || digital_clock_text != previous_text
+            || date_text != previous_date
         {
             let mut disp = DISPLAY.get().lock().await;
+            disp.begin_frame();
             let target = &mut disp.cropped(&crop);
-            target.clear(Rgb888::BLACK);
 
-            draw_face(target, &clock_face);
-
-            draw_hand(target, &clock_face, hours_radians, -60, Rgb888::WHITE);
-            draw_hand(target, &clock_face, minutes_radians, -30, Rgb888::WHITE);
-            draw_hand(target, &clock_face, seconds_radians, 0, Rgb888::WHITE);
-            draw_second_decoration(target, &clock_face, seconds_radians, -20, Rgb888::WHITE);
-
-            draw_digital_clock(target, &clock_face, &digital_clock_text);
+            let text_area = digital_clock_area(&clock_face, font);
+            let date_area = date_area(&clock_face, font);
+            // Erasing or drawing a hand over the digits means they have to go back on top
+            let mut redraw_text =
+                digital_clock_text != previous_text || hands.touches(&clock_face, &text_area);
+            let mut redraw_date =
+                date_text != previous_date || hands.touches(&clock_face, &date_area);
+            if let Some(previous) = previous {
+                previous.draw(target, &clock_face, Rgb888::BLACK);
+                repair_face(target, &clock_face, previous.seconds);
+                redraw_text |= previous.touches(&clock_face, &text_area);
+                redraw_date |= previous.touches(&clock_face, &date_area);
+            }
+            hands.draw(target, &clock_face, Rgb888::WHITE);
+
+            if redraw_text {
+                draw_digital_clock(target, &clock_face, font, &digital_clock_text);
+            }
+            if show_date && redraw_date {
+                draw_date(target, &clock_face, font, &date_text);
+            }
 
             center_clock_face.draw(target);
 
-            disp.draw_frame();
+            disp.end_frame();
+            previous = Some(hands);
+            previous_text = digital_clock_text;
+            previous_date = date_text;
         }
         sleep(Duration::from_millis(50)).await;
 
@@ -81,6 +147,54 @@ pub async fn draw_clock() {
     }
 }
 
+/// Angles of the clock hands in radians, clockwise from 12 o'clock.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Hands {
+    hours: f32,
+    minutes: f32,
+    seconds: f32,
+}
+
+impl Hands {
+    fn draw<D>(&self, target: &mut D, clock_face: &Circle, color: Rgb888) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb888>,
+    {
+        draw_hand(target, clock_face, self.hours, -60, color)?;
+        draw_hand(target, clock_face, self.minutes, -30, color)?;
+        draw_hand(target, clock_face, self.seconds, 0, color)?;
+        draw_second_decoration(target, clock_face, self.seconds, -20, color)
+    }
+
+    /// Whether the hands might draw over `area`.
+    fn touches(&self, clock_face: &Circle, area: &Rectangle) -> bool {
+        !self
+            .bounding_box(clock_face)
+            .intersection(area)
+            .is_zero_sized()
+    }
+
+    /// The area the hands and the second hand decoration cover.
+    fn bounding_box(&self, clock_face: &Circle) -> Rectangle {
+        let center = clock_face.center();
+        let decoration = polar(clock_face, self.seconds, -20);
+        [
+            polar(clock_face, self.hours, -60),
+            polar(clock_face, self.minutes, -30),
+            polar(clock_face, self.seconds, 0),
+            decoration - Point::new(6, 6),
+            decoration + Point::new(6, 6),
+        ]
+        .into_iter()
+        .fold(Rectangle::new(center, Size::new(1, 1)), |area, point| {
+            Rectangle::with_corners(
+                area.top_left.component_min(point),
+                area.bottom_right().unwrap().component_max(point),
+            )
+        })
+    }
+}
+
 fn polar(circle: &Circle, angle: f32, radius_delta: i32) -> Point {
     let radius = circle.diameter as f32 / 2.0 + radius_delta as f32;
 
@@ -98,8 +212,14 @@ fn hour_to_angle(hour: u32) -> f32 {
     (hour as f32 / 12.0) * 2.0 * PI
 }
 /// Converts a sexagesimal (base 60) value into an angle in radians.
-fn sexagesimal_to_angle(value: u32) -> f32 {
-    (value as f32 / 60.0) * 2.0 * PI
+fn sexagesimal_to_angle(value: f32) -> f32 {
+    (value / 60.0) * 2.0 * PI
+}
+
+/// How far into the current second `ticks` of the monotonic clock since it started are.
+fn second_fraction(ticks: usize) -> f32 {
+    // The RTC can be late to tick over, so never sweep into the next second
+    (ticks as f32 / TIMER_FREQ as f32).min(0.999)
 }
 
 /// Draws a circle and 12 graduations as a simple clock face.
@@ -114,20 +234,44 @@ where
 
     // Draw 12 graduations.
     for angle in (0..12).map(hour_to_angle) {
-        // Start point on circumference.
-        let start = polar(clock_face, angle, 0);
-
-        // End point offset by 10 pixels from the edge.
-        let end = polar(clock_face, angle, -10);
-
-        Line::new(start, end)
-            .into_styled(PrimitiveStyle::with_stroke(Rgb888::WHITE, 1))
-            .draw(target)?;
+        draw_graduation(target, clock_face, angle)?;
     }
 
     Ok(())
 }
 
+/// Draws one graduation of the face at `angle`.
+fn draw_graduation<D>(target: &mut D, clock_face: &Circle, angle: f32) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb888>,
+{
+    // Start point on circumference.
+    let start = polar(clock_face, angle, 0);
+
+    // End point offset by 10 pixels from the edge.
+    let end = polar(clock_face, angle, -10);
+
+    Line::new(start, end)
+        .into_styled(PrimitiveStyle::with_stroke(Rgb888::WHITE, 1))
+        .draw(target)
+}
+
+/// Redraws the bits of the face the second hand at `angle` covered, the outline where it
+/// ends and the nearest graduation.
+fn repair_face<D>(target: &mut D, clock_face: &Circle, angle: f32) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb888>,
+{
+    // Arcs start at 3 o'clock while the hands start at 12
+    let start = Angle::from_radians(angle - PI / 2.0) - Angle::from_degrees(3.0);
+    Arc::from_circle(*clock_face, start, Angle::from_degrees(6.0))
+        .into_styled(PrimitiveStyle::with_stroke(Rgb888::WHITE, 2))
+        .draw(target)?;
+
+    let hour = libm::roundf(angle / (2.0 * PI) * 12.0) as u32;
+    draw_graduation(target, clock_face, hour_to_angle(hour))
+}
+
 /// Draws a clock hand.
 fn draw_hand<D>(
     target: &mut D,
@@ -171,41 +315,182 @@ where
         .draw(target)
 }
 
-/// Draw digital clock just above center with black text on a white background
-fn draw_digital_clock<D>(
-    target: &mut D,
+/// Lays out the digital clock just above center, returning the text and its background.
+fn digital_clock<'a>(
     clock_face: &Circle,
-    time_str: &str,
-) -> Result<(), D::Error>
-where
-    D: DrawTarget<Color = Rgb888>,
-{
-    // Create a styled text object for the time text.
+    font: &'static MonoFont<'static>,
+    time_str: &'a str,
+) -> (Text<'a, MonoTextStyle<'static, Rgb888>>, Rectangle) {
+    label(clock_face, font, time_str, -1)
+}
+
+/// Lays out the date just below center, returning the text and its background.
+fn date<'a>(
+    clock_face: &Circle,
+    font: &'static MonoFont<'static>,
+    date_str: &'a str,
+) -> (Text<'a, MonoTextStyle<'static, Rgb888>>, Rectangle) {
+    label(clock_face, font, date_str, 1)
+}
+
+/// Lays out text centered a quarter of the face above (`direction` -1) or below (1) the center.
+fn label<'a>(
+    clock_face: &Circle,
+    font: &'static MonoFont<'static>,
+    label: &'a str,
+    direction: i32,
+) -> (Text<'a, MonoTextStyle<'static, Rgb888>>, Rectangle) {
+    // Create a styled text object for the label.
     let mut text = Text::new(
-        &time_str,
+        label,
         Point::zero(),
-        MonoTextStyle::new(&FONT_9X15, Rgb888::BLACK),
+        MonoTextStyle::new(font, Rgb888::BLACK),
     );
 
-    // Move text to be centered between the 12 o'clock point and the center of the clock face.
+    // Move text to be centered between the 12 or 6 o'clock point and the center of the face.
     text.translate_mut(
-        clock_face.center()
-            - text.bounding_box().center()
-            - clock_face.bounding_box().size.y_axis() / 4,
+        clock_face.center() - text.bounding_box().center()
+            + Point::new(0, clock_face.diameter as i32 * direction / 4),
     );
 
-    // Add a background around the time digits.
+    // Add a background around the text.
     // Note that there is no bottom-right padding as this is added by the font renderer itself.
     let text_dimensions = text.bounding_box();
-    Rectangle::new(
+    let background = Rectangle::new(
         text_dimensions.top_left - Point::new(3, 3),
         text_dimensions.size + Size::new(4, 4),
-    )
-    .into_styled(PrimitiveStyle::with_fill(Rgb888::WHITE))
-    .draw(target)?;
+    );
+    (text, background)
+}
+
+/// The area the digital clock covers, which is the same for any time since the font is
+/// monospaced.
+fn digital_clock_area(clock_face: &Circle, font: &'static MonoFont<'static>) -> Rectangle {
+    digital_clock(clock_face, font, "00:00:00").1
+}
+
+/// The area the date covers, the same for any date within 4 digit years.
+fn date_area(clock_face: &Circle, font: &'static MonoFont<'static>) -> Rectangle {
+    date(clock_face, font, "0000-00-00 Mon").1
+}
+
+/// Draw digital clock just above center with black text on a white background
+fn draw_digital_clock<D>(
+    target: &mut D,
+    clock_face: &Circle,
+    font: &'static MonoFont<'static>,
+    time_str: &str,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb888>,
+{
+    let (text, background) = digital_clock(clock_face, font, time_str);
+    draw_label(target, text, background)
+}
+
+/// Draw the date just below center with black text on a white background
+fn draw_date<D>(
+    target: &mut D,
+    clock_face: &Circle,
+    font: &'static MonoFont<'static>,
+    date_str: &str,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb888>,
+{
+    let (text, background) = date(clock_face, font, date_str);
+    draw_label(target, text, background)
+}
+
+fn draw_label<D>(
+    target: &mut D,
+    text: Text<'_, MonoTextStyle<'static, Rgb888>>,
+    background: Rectangle,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb888>,
+{
+    background
+        .into_styled(PrimitiveStyle::with_fill(Rgb888::WHITE))
+        .draw(target)?;
 
     // Draw the text after the background is drawn.
     text.draw(target)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use embedded_graphics::{
+        mono_font::ascii::{FONT_10X20, FONT_6X10},
+        prelude::*,
+        primitives::Circle,
+    };
+
+    use super::{
+        date_area, digital_clock_area, hour_to_angle, second_fraction, sexagesimal_to_angle, Hands,
+        DEFAULT_FONT,
+    };
+    use crate::rtc::TIMER_FREQ;
+
+    #[test_case]
+    fn second_hand_sweeps() {
+        let angle = |ticks| sexagesimal_to_angle(30.0 + second_fraction(ticks));
+        let early = angle(TIMER_FREQ / 10);
+        let late = angle(TIMER_FREQ / 2);
+        assert!(sexagesimal_to_angle(30.0) < early && early < late);
+        assert!(late < sexagesimal_to_angle(31.0));
+        // A late RTC doesn't push the hand past the next second
+        assert!(angle(TIMER_FREQ * 2) < sexagesimal_to_angle(31.0));
+    }
+
+    #[test_case]
+    fn hands_only_touch_digits_near_twelve() {
+        let face = Circle::with_center(Point::new(128, 128), 236);
+        let text_area = digital_clock_area(&face, DEFAULT_FONT);
+        let touches = |hands: Hands| hands.touches(&face, &text_area);
+
+        // 6:15:45, every hand points away from the digits above center
+        let away = Hands {
+            hours: hour_to_angle(6),
+            minutes: sexagesimal_to_angle(15.0),
+            seconds: sexagesimal_to_angle(45.0),
+        };
+        assert!(!touches(away));
+        assert!(touches(Hands {
+            seconds: 0.0,
+            ..away
+        }));
+
+        // Most of the face is left alone between frames
+        let area = away.bounding_box(&face).size;
+        assert!(area.width * area.height < face.bounding_box().size.width.pow(2) / 2);
+    }
+
+    #[test_case]
+    fn date_mirrors_time_below_center() {
+        let face = Circle::with_center(Point::new(128, 128), 236);
+        let time = digital_clock_area(&face, DEFAULT_FONT);
+        let date = date_area(&face, DEFAULT_FONT);
+        assert!(time.intersection(&date).is_zero_sized());
+        // The background padding is lopsided, so only roughly mirrored
+        let above = face.center().y - time.center().y;
+        let below = date.center().y - face.center().y;
+        assert!(above > 0 && below > 0 && above.abs_diff(below) <= 2);
+        assert!(face.contains(date.top_left) && face.contains(date.bottom_right().unwrap()));
+    }
+
+    #[test_case]
+    fn labels_follow_font() {
+        let face = Circle::with_center(Point::new(128, 128), 236);
+        let small = digital_clock_area(&face, &FONT_6X10).size;
+        let default = digital_clock_area(&face, DEFAULT_FONT).size;
+        let large = digital_clock_area(&face, &FONT_10X20).size;
+        assert!(small.width < default.width && default.width < large.width);
+        assert!(small.height < default.height && default.height < large.height);
+        // Still centered whatever the size
+        let date = date_area(&face, &FONT_10X20);
+        assert!(date.center().x.abs_diff(face.center().x) <= 2);
+    }
+}
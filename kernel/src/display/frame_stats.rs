@@ -0,0 +1,92 @@
+use core::time::Duration;
+
+use crate::{framebuffer::DISPLAY, rtc::TIMER_FREQ};
+
+/// Number of recent frames the average is taken over.
+const WINDOW: usize = 16;
+
+/// Time between the recent frames [`Display`](crate::framebuffer::Display) put on screen.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    /// Ticks between successive frames, oldest overwritten first.
+    deltas: [usize; WINDOW],
+    next: usize,
+    len: usize,
+    last_tick: Option<usize>,
+}
+
+impl FrameStats {
+    pub const fn new() -> Self {
+        Self {
+            deltas: [0; WINDOW],
+            next: 0,
+            len: 0,
+            last_tick: None,
+        }
+    }
+
+    /// Notes a frame shown at `tick` of the monotonic clock.
+    pub fn record(&mut self, tick: usize) {
+        if let Some(last) = self.last_tick.replace(tick) {
+            self.deltas[self.next] = tick.wrapping_sub(last);
+            self.next = (self.next + 1) % WINDOW;
+            self.len = (self.len + 1).min(WINDOW);
+        }
+    }
+
+    /// Average ticks per frame, `None` until two frames were shown.
+    pub fn average_ticks(&self) -> Option<usize> {
+        (self.len > 0).then(|| self.deltas[..self.len].iter().sum::<usize>() / self.len)
+    }
+
+    pub fn frame_time(&self) -> Option<Duration> {
+        let ticks = self.average_ticks()?;
+        Some(Duration::from_micros(
+            ticks as u64 * 1_000_000 / TIMER_FREQ as u64,
+        ))
+    }
+
+    /// Frames per second, `None` until frames were far enough apart to tell.
+    pub fn fps(&self) -> Option<usize> {
+        match self.average_ticks()? {
+            0 => None,
+            ticks => Some(TIMER_FREQ / ticks),
+        }
+    }
+}
+
+/// The frame timings of the screen, `None` if it isn't set up or is being drawn to.
+pub fn frame_stats() -> Option<FrameStats> {
+    Some(DISPLAY.try_get().ok()?.try_lock()?.frame_stats())
+}
+
+#[cfg(test)]
+mod test {
+    use core::time::Duration;
+
+    use super::{FrameStats, WINDOW};
+    use crate::rtc::TIMER_FREQ;
+
+    #[test_case]
+    fn averages_frame_deltas() {
+        let mut stats = FrameStats::new();
+        assert_eq!(stats.average_ticks(), None);
+        stats.record(100);
+        assert_eq!(stats.average_ticks(), None);
+
+        for tick in [264, 428, 592] {
+            stats.record(tick);
+        }
+        assert_eq!(stats.average_ticks(), Some(164));
+        assert_eq!(stats.fps(), Some(TIMER_FREQ / 164));
+        assert_eq!(stats.frame_time(), Some(Duration::from_micros(20019)));
+
+        // Only the last WINDOW frames count
+        let mut tick = 592;
+        for _ in 0..WINDOW {
+            tick += 82;
+            stats.record(tick);
+        }
+        assert_eq!(stats.average_ticks(), Some(82));
+    }
+}
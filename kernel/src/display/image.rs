@@ -0,0 +1,236 @@
+use embedded_graphics::{pixelcolor::Rgb888, prelude::*, primitives::Rectangle};
+use thiserror::Error;
+
+use crate::framebuffer::DISPLAY;
+
+/// Magic of the raw format: `RGB8`, then width and height as little endian u16s, then the
+/// pixels as RGB888 rows top to bottom.
+const RAW_MAGIC: &[u8; 4] = b"RGB8";
+const RAW_HEADER_LEN: usize = 8;
+const BMP_MAGIC: &[u8; 2] = b"BM";
+/// File header plus the smallest info header with a width, height and bit depth.
+const BMP_HEADER_LEN: usize = 14 + 40;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ImageError {
+    #[error("Image is neither a BMP nor raw RGB888")]
+    UnknownFormat,
+    #[error("Image header is truncated")]
+    Truncated,
+    #[error("Only uncompressed 24 bit BMPs are supported, got {bits_per_pixel} bpp with compression {compression}")]
+    UnsupportedBmp {
+        bits_per_pixel: u16,
+        compression: u32,
+    },
+    #[error("Image is {width}x{height} but only has {len} bytes of pixels")]
+    MissingPixels { width: u32, height: u32, len: usize },
+}
+
+/// A decoded view of a bundled image, borrowing its pixels from the file.
+#[derive(Debug, Clone, Copy)]
+pub struct Image<'a> {
+    size: Size,
+    pixels: &'a [u8],
+    /// Bytes per row including padding.
+    stride: usize,
+    layout: Layout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    Raw,
+    /// BGR rows, bottom row first unless the height was negative.
+    Bmp {
+        bottom_up: bool,
+    },
+}
+
+impl<'a> Image<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ImageError> {
+        if bytes.starts_with(RAW_MAGIC) {
+            Self::parse_raw(bytes)
+        } else if bytes.starts_with(BMP_MAGIC) {
+            Self::parse_bmp(bytes)
+        } else {
+            Err(ImageError::UnknownFormat)
+        }
+    }
+
+    fn parse_raw(bytes: &'a [u8]) -> Result<Self, ImageError> {
+        let width = read_u16(bytes, 4)? as u32;
+        let height = read_u16(bytes, 6)? as u32;
+        Self::new(
+            Size::new(width, height),
+            &bytes[RAW_HEADER_LEN..],
+            width as usize * 3,
+            Layout::Raw,
+        )
+    }
+
+    fn parse_bmp(bytes: &'a [u8]) -> Result<Self, ImageError> {
+        if bytes.len() < BMP_HEADER_LEN {
+            return Err(ImageError::Truncated);
+        }
+        let pixels_offset = read_u32(bytes, 10)? as usize;
+        let width = read_u32(bytes, 18)? as i32;
+        let height = read_u32(bytes, 22)? as i32;
+        let bits_per_pixel = read_u16(bytes, 28)?;
+        let compression = read_u32(bytes, 30)?;
+        if bits_per_pixel != 24 || compression != 0 {
+            return Err(ImageError::UnsupportedBmp {
+                bits_per_pixel,
+                compression,
+            });
+        }
+
+        let width = width.unsigned_abs();
+        // Rows are padded to 4 bytes
+        let stride = (width as usize * 3).next_multiple_of(4);
+        Self::new(
+            Size::new(width, height.unsigned_abs()),
+            bytes.get(pixels_offset..).ok_or(ImageError::Truncated)?,
+            stride,
+            Layout::Bmp {
+                bottom_up: height > 0,
+            },
+        )
+    }
+
+    fn new(
+        size: Size,
+        pixels: &'a [u8],
+        stride: usize,
+        layout: Layout,
+    ) -> Result<Self, ImageError> {
+        // The last row doesn't need its padding
+        let needed = match size.height {
+            0 => 0,
+            height => (height as usize - 1) * stride + size.width as usize * 3,
+        };
+        if pixels.len() < needed {
+            return Err(ImageError::MissingPixels {
+                width: size.width,
+                height: size.height,
+                len: pixels.len(),
+            });
+        }
+        Ok(Self {
+            size,
+            pixels,
+            stride,
+            layout,
+        })
+    }
+
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    /// The pixels in rows from top to bottom, the order [`DrawTarget::fill_contiguous`] takes.
+    pub fn colors(&self) -> impl Iterator<Item = Rgb888> + '_ {
+        let height = self.size.height as usize;
+        (0..height).flat_map(move |row| {
+            let row = match self.layout {
+                Layout::Bmp { bottom_up: true } => height - 1 - row,
+                _ => row,
+            };
+            let start = row * self.stride;
+            self.pixels[start..start + self.size.width as usize * 3]
+                .chunks_exact(3)
+                .map(move |pixel| match self.layout {
+                    Layout::Raw => Rgb888::new(pixel[0], pixel[1], pixel[2]),
+                    Layout::Bmp { .. } => Rgb888::new(pixel[2], pixel[1], pixel[0]),
+                })
+        })
+    }
+
+    /// Draws the image with its top left corner at `top_left`, clipping whatever is off screen.
+    pub fn draw<D>(&self, target: &mut D, top_left: Point) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb888>,
+    {
+        target.fill_contiguous(&Rectangle::new(top_left, self.size), self.colors())
+    }
+}
+
+/// Draws a bundled BMP or raw RGB888 image straight to the screen.
+pub fn draw_image(bytes: &[u8], top_left: Point) -> Result<(), ImageError> {
+    let image = Image::parse(bytes)?;
+    let mut display = DISPLAY.get().spin_lock();
+    let _ = image.draw(display.as_mut(), top_left);
+    display.draw_frame();
+    Ok(())
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, ImageError> {
+    let field = bytes.get(offset..offset + 2).ok_or(ImageError::Truncated)?;
+    Ok(u16::from_le_bytes(field.try_into().unwrap()))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, ImageError> {
+    let field = bytes.get(offset..offset + 4).ok_or(ImageError::Truncated)?;
+    Ok(u32::from_le_bytes(field.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod test {
+    use embedded_graphics::{pixelcolor::Rgb888, prelude::*};
+
+    use super::{draw_image, Image, ImageError};
+    use crate::framebuffer::{luma, DISPLAY};
+
+    /// A 2x2 24 bit BMP, red and green on top, blue and white below.
+    #[rustfmt::skip]
+    const BMP: [u8; 70] = [
+        b'B', b'M', 70, 0, 0, 0, 0, 0, 0, 0, 54, 0, 0, 0,
+        40, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 24, 0,
+        0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        // Bottom row first, BGR with 2 bytes of padding
+        255, 0, 0, 255, 255, 255, 0, 0,
+        0, 0, 255, 0, 255, 0, 0, 0,
+    ];
+
+    #[test_case]
+    fn bmp_lands_on_screen() {
+        let top_left = Point::new(10, 20);
+        draw_image(&BMP, top_left).unwrap();
+
+        let display = DISPLAY.get().spin_lock();
+        let at = |x, y| display.pixel(top_left + Point::new(x, y)).unwrap();
+        let expected = [
+            (0, 0, Rgb888::RED),
+            (1, 0, Rgb888::GREEN),
+            (0, 1, Rgb888::BLUE),
+            (1, 1, Rgb888::WHITE),
+        ];
+        for (x, y, color) in expected {
+            let pixel = at(x, y);
+            // Grayscale framebuffers only keep the brightness
+            assert!(pixel == color || pixel.r() == luma(color.into()));
+        }
+    }
+
+    #[test_case]
+    fn raw_and_bad_headers() {
+        let raw = *b"RGB8\x01\x00\x02\x00\x01\x02\x03\x04\x05\x06";
+        let image = Image::parse(&raw).unwrap();
+        assert_eq!(image.size(), Size::new(1, 2));
+        assert!(image
+            .colors()
+            .eq([Rgb888::new(1, 2, 3), Rgb888::new(4, 5, 6)]));
+
+        assert_eq!(
+            Image::parse(&raw[..12]).unwrap_err(),
+            ImageError::MissingPixels {
+                width: 1,
+                height: 2,
+                len: 4
+            }
+        );
+        assert_eq!(
+            Image::parse(b"GIF89a").unwrap_err(),
+            ImageError::UnknownFormat
+        );
+        assert_eq!(Image::parse(&BMP[..20]).unwrap_err(), ImageError::Truncated);
+    }
+}
@@ -1 +1,10 @@
 pub mod clock;
+pub mod frame_stats;
+pub mod image;
+pub mod splash;
+
+pub use self::{
+    frame_stats::{frame_stats, FrameStats},
+    image::draw_image,
+    splash::splash,
+};
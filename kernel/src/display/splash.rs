@@ -0,0 +1,87 @@
+use core::time::Duration;
+
+use embedded_graphics::{
+    mono_font::{ascii::FONT_10X20, MonoTextStyle},
+    pixelcolor::Rgb888,
+    prelude::*,
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+
+use crate::{
+    framebuffer::{blend, DISPLAY},
+    util::r#async::{interval, sleep},
+};
+
+const TEXT: &str = "zoom-os";
+const FADE_FRAMES: u32 = 16;
+const FRAME_PERIOD: Duration = Duration::from_millis(30);
+/// How long the text stays fully drawn before the screen is cleared.
+const HOLD: Duration = Duration::from_millis(500);
+/// How long [`splash`] takes from the first frame to clearing the screen.
+pub const SPLASH_DURATION: Duration = FRAME_PERIOD
+    .saturating_mul(FADE_FRAMES)
+    .saturating_add(HOLD);
+
+/// Fades in a centered logo, holds it, then clears the screen for whatever draws next.
+#[tracing::instrument]
+pub async fn splash() {
+    let mut frames = interval(FRAME_PERIOD);
+    for frame in 0..FADE_FRAMES {
+        frames.tick().await;
+        let alpha = (frame * 255 / (FADE_FRAMES - 1)) as u8;
+        draw_text(blend(Rgb888::WHITE, Rgb888::BLACK, alpha)).await;
+    }
+    frames.tick().await;
+    sleep(HOLD).await;
+
+    let mut disp = DISPLAY.get().lock().await;
+    let _ = disp.clear(Rgb888::BLACK);
+    disp.draw_frame();
+}
+
+async fn draw_text(color: Rgb888) {
+    let mut disp = DISPLAY.get().lock().await;
+    disp.begin_frame();
+    let center = disp.bounding_box().center();
+    let style = TextStyleBuilder::new()
+        .alignment(Alignment::Center)
+        .baseline(Baseline::Middle)
+        .build();
+    let _ = Text::with_text_style(TEXT, center, MonoTextStyle::new(&FONT_10X20, color), style)
+        .draw(disp.as_mut());
+    disp.end_frame();
+}
+
+#[cfg(test)]
+mod test {
+    use core::{pin::pin, sync::atomic::Ordering, task::Context};
+
+    use embedded_graphics::{pixelcolor::Rgb888, prelude::*, primitives::Rectangle};
+    use futures::{task::noop_waker_ref, Future};
+
+    use super::{splash, SPLASH_DURATION};
+    use crate::{
+        framebuffer::DISPLAY, rtc::TIMER_FREQ, util::r#async::sleep_future::MONOTONIC_TIME,
+    };
+
+    #[test_case]
+    fn splash_finishes_cleared() {
+        let start = MONOTONIC_TIME.load(Ordering::Acquire);
+        let mut splash = pin!(splash());
+        let mut cx = Context::from_waker(noop_waker_ref());
+        // Tests run with interrupts enabled so the clock wakes us
+        while splash.as_mut().poll(&mut cx).is_pending() {
+            x86_64::instructions::hlt();
+        }
+        let elapsed = MONOTONIC_TIME.load(Ordering::Acquire) - start;
+        // A frame of slack for the ticks the drawing itself takes
+        let budget = (SPLASH_DURATION.as_secs_f64() * TIMER_FREQ as f64) as usize;
+        assert!(elapsed <= budget + TIMER_FREQ / 10);
+
+        let display = DISPLAY.get().spin_lock();
+        let center = Rectangle::with_center(display.bounding_box().center(), Size::new(80, 20));
+        assert!(center
+            .points()
+            .all(|point| display.pixel(point) == Some(Rgb888::BLACK)));
+    }
+}
@@ -1,4 +1,8 @@
-use core::{ptr::addr_of, u8, usize};
+use core::{
+    fmt::{self, Write},
+    ptr::addr_of,
+    sync::atomic::Ordering,
+};
 
 use alloc::{boxed::Box, vec};
 use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
@@ -15,8 +19,12 @@ use x86_64::{
 };
 
 use crate::{
+    display::FrameStats,
     memory::mapping::MAPPER,
-    util::{once::OnceLock, r#async::mutex::Mutex},
+    util::{
+        once::OnceLock,
+        r#async::{mutex::Mutex, sleep_future::MONOTONIC_TIME},
+    },
     vga_buffer::{Writer, WRITER},
 };
 
@@ -49,6 +57,19 @@ impl From<Rgb888> for Color {
     }
 }
 
+/// Mixes `fg` over `bg`, `alpha` 0 is all `bg` and 255 is all `fg`.
+pub fn blend(fg: Rgb888, bg: Rgb888, alpha: u8) -> Rgb888 {
+    let mix = |fg: u8, bg: u8| {
+        let alpha = alpha as u16;
+        ((fg as u16 * alpha + bg as u16 * (255 - alpha) + 127) / 255) as u8
+    };
+    Rgb888::new(
+        mix(fg.r(), bg.r()),
+        mix(fg.g(), bg.g()),
+        mix(fg.b(), bg.b()),
+    )
+}
+
 pub static DISPLAY: OnceLock<Mutex<Display<'static>>> = OnceLock::new();
 
 pub fn init(framebuffer: &'static mut FrameBuffer) {
@@ -88,6 +109,57 @@ pub fn init(framebuffer: &'static mut FrameBuffer) {
 pub struct Display<'f> {
     framebuffer: &'f mut FrameBuffer,
     backbuffer: Box<[u8]>,
+    /// Nesting depth of [`Display::begin_frame`], flushes are deferred while nonzero.
+    batch_depth: usize,
+    /// Area of the backbuffer drawn to since the last flush.
+    dirty: Option<Rectangle>,
+    flushes: usize,
+    stats: FrameStats,
+    /// Scratch row of one color that [`DrawTarget::fill_solid`] copies from.
+    solid_row: SolidRow,
+    /// Level every drawn color is scaled by, out of 255.
+    brightness: u8,
+}
+
+/// A row of pixels of one color, only rewritten when the color changes or it has to grow.
+struct SolidRow {
+    bytes: Box<[u8]>,
+    color: Option<Rgb888>,
+    /// Pixels at the start of `bytes` holding `color`.
+    filled: usize,
+}
+
+impl SolidRow {
+    fn new(width: usize, bytes_per_pixel: usize) -> Self {
+        Self {
+            bytes: vec![0; width * bytes_per_pixel].into_boxed_slice(),
+            color: None,
+            filled: 0,
+        }
+    }
+
+    /// The first `width` pixels of the row, in `color`.
+    fn get(
+        &mut self,
+        color: Rgb888,
+        format: PixelFormat,
+        bytes_per_pixel: usize,
+        width: usize,
+    ) -> &[u8] {
+        if self.color != Some(color) {
+            self.color = Some(color);
+            self.filled = 0;
+        }
+        let len = width * bytes_per_pixel;
+        if self.filled < width {
+            let start = self.filled * bytes_per_pixel;
+            for pixel in self.bytes[start..len].chunks_exact_mut(bytes_per_pixel) {
+                write_pixel(pixel, format, color.into());
+            }
+            self.filled = width;
+        }
+        &self.bytes[..len]
+    }
 }
 
 impl<'f> Display<'f> {
@@ -100,10 +172,27 @@ impl<'f> Display<'f> {
                     * framebuffer.info().bytes_per_pixel
             ]
             .into_boxed_slice(),
+            solid_row: SolidRow::new(framebuffer.info().width, framebuffer.info().bytes_per_pixel),
             framebuffer,
+            batch_depth: 0,
+            dirty: None,
+            flushes: 0,
+            stats: FrameStats::new(),
+            brightness: u8::MAX,
         }
     }
 
+    /// Scales every color drawn from now on by `level / 255`, for dimming the screen.
+    ///
+    /// What's already on screen keeps its brightness until it's redrawn.
+    pub fn set_brightness(&mut self, level: u8) {
+        self.brightness = level;
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
     #[inline(always)]
     pub fn get_info(&self) -> FrameBufferInfo {
         self.framebuffer.info()
@@ -111,6 +200,7 @@ impl<'f> Display<'f> {
 
     #[inline(always)]
     fn draw_pixel(&mut self, Pixel(Point { x, y }, color): Pixel<Rgb888>) {
+        let color = dim(color, self.brightness);
         // ignore any out of bounds pixels
         let info = self.framebuffer.info();
         let (width, height) = { (info.width, info.height) };
@@ -118,6 +208,10 @@ impl<'f> Display<'f> {
         let (x, y) = { (x as usize, y as usize) };
 
         if (0..width).contains(&x) && (0..height).contains(&y) {
+            self.mark_dirty(Rectangle::new(
+                Point::new(x as i32, y as i32),
+                Size::new(1, 1),
+            ));
             let color = Color {
                 red: color.r(),
                 green: color.g(),
@@ -135,42 +229,181 @@ impl<'f> Display<'f> {
             };
 
             // set pixel based on color format
-            let pixel_buffer = &mut self.backbuffer[byte_offset..];
-            match info.pixel_format {
-                PixelFormat::Rgb => {
-                    pixel_buffer[0] = color.red;
-                    pixel_buffer[1] = color.green;
-                    pixel_buffer[2] = color.blue;
-                }
-                PixelFormat::Bgr => {
-                    pixel_buffer[0] = color.blue;
-                    pixel_buffer[1] = color.green;
-                    pixel_buffer[2] = color.red;
-                }
-                PixelFormat::U8 => {
-                    // use a simple average-based grayscale transform
-                    let gray = color.red / 3 + color.green / 3 + color.blue / 3;
-                    pixel_buffer[0] = gray;
-                }
-                other => panic!("unknown pixel format {other:?}"),
+            write_pixel(
+                &mut self.backbuffer[byte_offset..],
+                info.pixel_format,
+                color,
+            );
+        }
+    }
+
+    /// Starts a batch of draws, [`Display::draw_frame`] does nothing until the matching
+    /// [`Display::end_frame`]. Batches can nest.
+    pub fn begin_frame(&mut self) {
+        self.batch_depth += 1;
+    }
+
+    /// Ends a batch, flushing only the area drawn to once the outermost batch ends.
+    pub fn end_frame(&mut self) {
+        self.batch_depth = self.batch_depth.saturating_sub(1);
+        if self.batch_depth == 0 {
+            if let Some(area) = self.dirty.take() {
+                self.flush(area);
             }
         }
     }
 
+    /// Copies the backbuffer to the screen, unless inside a batch.
     pub fn draw_frame(&mut self) {
+        if self.batch_depth > 0 {
+            return;
+        }
+        self.dirty = None;
+        self.flush(self.bounding_box());
+    }
+
+    /// Reads a pixel back from the backbuffer, grayscale formats come back gray.
+    pub fn pixel(&self, Point { x, y }: Point) -> Option<Rgb888> {
+        let info = self.get_info();
+        if !self.bounding_box().contains(Point { x, y }) {
+            return None;
+        }
+        let offset = (y as usize * info.width + x as usize) * info.bytes_per_pixel;
+        let bytes = &self.backbuffer[offset..];
+        match info.pixel_format {
+            PixelFormat::Rgb => Some(Rgb888::new(bytes[0], bytes[1], bytes[2])),
+            PixelFormat::Bgr => Some(Rgb888::new(bytes[2], bytes[1], bytes[0])),
+            PixelFormat::U8 => Some(Rgb888::new(bytes[0], bytes[0], bytes[0])),
+            _ => None,
+        }
+    }
+
+    /// Writes the backbuffer as an ASCII PPM (P3) image, one pixel per line.
+    ///
+    /// Only one pixel out of every `scale` in each direction is kept, since the whole screen
+    /// takes minutes to get through serial.
+    pub fn screenshot_ppm(&self, out: &mut impl Write, scale: usize) -> fmt::Result {
+        let scale = scale.max(1);
         let info = self.get_info();
-        for y in 0..info.height {
-            let wide_offset = (y * info.width) * info.bytes_per_pixel;
-            let offset = (y * info.stride) * info.bytes_per_pixel;
+        let (width, height) = (info.width.div_ceil(scale), info.height.div_ceil(scale));
+        write!(out, "P3\n{width} {height}\n255\n")?;
+        for y in (0..info.height).step_by(scale) {
+            for x in (0..info.width).step_by(scale) {
+                let color = self
+                    .pixel(Point::new(x as i32, y as i32))
+                    .unwrap_or(Rgb888::BLACK);
+                writeln!(out, "{} {} {}", color.r(), color.g(), color.b())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// How many times the backbuffer has been copied to the screen.
+    pub fn flush_count(&self) -> usize {
+        self.flushes
+    }
+
+    pub fn frame_stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    fn mark_dirty(&mut self, area: Rectangle) {
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => envelope(dirty, area),
+            None => area,
+        });
+    }
+
+    fn flush(&mut self, area: Rectangle) {
+        let info = self.get_info();
+        let area = self.bounding_box().intersection(&area);
+        let x = area.top_left.x as usize;
+        let width = area.size.width as usize;
+        for y in area.rows() {
+            let y = y as usize;
+            let wide_offset = (y * info.width + x) * info.bytes_per_pixel;
+            let offset = (y * info.stride + x) * info.bytes_per_pixel;
             unsafe {
                 let wide = self.backbuffer.as_mut_ptr().add(wide_offset);
                 let addr = self.framebuffer.buffer_mut().as_mut_ptr().add(offset);
-                core::ptr::copy_nonoverlapping(wide, addr, info.width * info.bytes_per_pixel);
+                core::ptr::copy_nonoverlapping(wide, addr, width * info.bytes_per_pixel);
             }
         }
+        self.flushes += 1;
+        self.stats.record(MONOTONIC_TIME.load(Ordering::Acquire));
+    }
+}
+
+/// Encodes `color` into the first bytes of `pixel_buffer` in the framebuffer's format.
+#[inline(always)]
+fn write_pixel(pixel_buffer: &mut [u8], format: PixelFormat, color: Color) {
+    match format {
+        PixelFormat::Rgb => {
+            pixel_buffer[0] = color.red;
+            pixel_buffer[1] = color.green;
+            pixel_buffer[2] = color.blue;
+        }
+        PixelFormat::Bgr => {
+            pixel_buffer[0] = color.blue;
+            pixel_buffer[1] = color.green;
+            pixel_buffer[2] = color.red;
+        }
+        PixelFormat::U8 => pixel_buffer[0] = luma(color),
+        other => panic!("unknown pixel format {other:?}"),
     }
 }
 
+/// Perceived brightness of `color` with the BT.601 weights, in 8.8 fixed point.
+///
+/// The weights add up to 256 so grays map to themselves.
+#[inline(always)]
+pub(crate) fn luma(color: Color) -> u8 {
+    ((77 * color.red as u16 + 150 * color.green as u16 + 29 * color.blue as u16) >> 8) as u8
+}
+
+/// `color` scaled by `brightness / 255`, black stays black and full brightness is unchanged.
+#[inline(always)]
+fn dim(color: Rgb888, brightness: u8) -> Rgb888 {
+    if brightness == u8::MAX {
+        return color;
+    }
+    let scale = |channel: u8| (channel as u16 * brightness as u16 / 255) as u8;
+    Rgb888::new(scale(color.r()), scale(color.g()), scale(color.b()))
+}
+
+/// Fills `buffer` with the bytes of `pattern` repeated, 8 at a time with `rep stosq`.
+fn fill_wide(buffer: &mut [u8], pattern: u64) {
+    let bytes = pattern.to_le_bytes();
+    // SAFETY: any bit pattern is a valid u64
+    let (head, words, tail) = unsafe { buffer.align_to_mut::<u64>() };
+    for (i, byte) in head.iter_mut().enumerate() {
+        *byte = bytes[i % 8];
+    }
+    // The words start `head.len()` bytes into the pattern
+    let shift = head.len();
+    let word = pattern.rotate_right(8 * shift as u32);
+    // SAFETY: writes exactly `words`, the direction flag is clear as the ABI requires
+    unsafe {
+        core::arch::asm!(
+            "rep stosq",
+            inout("rcx") words.len() => _,
+            inout("rdi") words.as_mut_ptr() => _,
+            in("rax") word,
+            options(nostack, preserves_flags),
+        );
+    }
+    for (i, byte) in tail.iter_mut().enumerate() {
+        *byte = bytes[(shift + i) % 8];
+    }
+}
+
+/// The smallest rectangle containing both `a` and `b`.
+fn envelope(a: Rectangle, b: Rectangle) -> Rectangle {
+    let top_left = a.top_left.component_min(b.top_left);
+    let bottom_right = (a.top_left + a.size).component_max(b.top_left + b.size);
+    Rectangle::with_corners(top_left, bottom_right - Point::new(1, 1))
+}
+
 impl<'f> DrawTarget for Display<'f> {
     type Color = Rgb888;
 
@@ -186,6 +419,49 @@ impl<'f> DrawTarget for Display<'f> {
         Ok(())
     }
 
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        let intersection = self.bounding_box().intersection(area);
+        if intersection == Rectangle::zero() {
+            return Ok(());
+        }
+        self.mark_dirty(intersection);
+
+        let info = self.get_info();
+        let bpp = info.bytes_per_pixel;
+        let width = area.size.width as usize;
+        // Colors to skip before and after the visible part of each row
+        let skip_left = (intersection.top_left.x - area.top_left.x) as usize;
+        let visible = intersection.size.width as usize;
+        let skip_right = width - skip_left - visible;
+
+        let brightness = self.brightness;
+        let mut colors = colors.into_iter();
+        for y in area.rows() {
+            if !intersection.rows().contains(&y) {
+                // Rows above or below the screen, drop the whole row
+                if colors.nth(width - 1).is_none() {
+                    break;
+                }
+                continue;
+            }
+            if skip_left > 0 && colors.nth(skip_left - 1).is_none() {
+                break;
+            }
+            let row_start = (y as usize * info.width + intersection.top_left.x as usize) * bpp;
+            let row = &mut self.backbuffer[row_start..row_start + visible * bpp];
+            for (pixel, color) in row.chunks_exact_mut(bpp).zip(colors.by_ref().take(visible)) {
+                write_pixel(pixel, info.pixel_format, dim(color, brightness).into());
+            }
+            if skip_right > 0 && colors.nth(skip_right - 1).is_none() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
     fn fill_solid(
         &mut self,
         area: &embedded_graphics::primitives::Rectangle,
@@ -195,38 +471,16 @@ impl<'f> DrawTarget for Display<'f> {
         if intersection == Rectangle::zero() {
             return Ok(());
         }
+        self.mark_dirty(intersection);
 
-        let color: Color = color.into();
+        let color = dim(color, self.brightness);
         let info = self.framebuffer.info();
         let range = intersection.columns();
         let width = (range.end - range.start) as usize;
-
-        let vec: alloc::vec::Vec<u32>;
-        let vec2: alloc::vec::Vec<u8>;
-
-        let wide = match info.pixel_format {
-            PixelFormat::Rgb => {
-                let color =
-                    color.red as u32 | (color.green as u32) << 8 | (color.blue as u32) << 16;
-                debug_assert_eq!(info.bytes_per_pixel, 4);
-                vec = vec![color; width];
-                vec.as_ptr() as *const u8
-            }
-            PixelFormat::Bgr => {
-                let color =
-                    color.blue as u32 | (color.green as u32) << 8 | (color.red as u32) << 16;
-                debug_assert_eq!(info.bytes_per_pixel, 4);
-                vec = vec![color; width];
-                vec.as_ptr() as *const u8
-            }
-            PixelFormat::U8 => {
-                let gray = color.red / 3 + color.green / 3 + color.blue / 3;
-                debug_assert_eq!(info.bytes_per_pixel, 1);
-                vec2 = vec![gray; width];
-                vec2.as_ptr()
-            }
-            _ => todo!(),
-        };
+        let wide = self
+            .solid_row
+            .get(color, info.pixel_format, info.bytes_per_pixel, width)
+            .as_ptr();
         let x = range.start as usize;
 
         for y in intersection.rows() {
@@ -240,40 +494,34 @@ impl<'f> DrawTarget for Display<'f> {
     }
 
     fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
-        let color: Color = color.into();
+        self.mark_dirty(self.bounding_box());
         let info = self.get_info();
+        let bpp = info.bytes_per_pixel;
+        let buffer = &mut self.backbuffer[..info.width * info.height * bpp];
+
+        let mut pixel = [0; 4];
+        write_pixel(
+            &mut pixel,
+            info.pixel_format,
+            dim(color, self.brightness).into(),
+        );
+        let pixel = &pixel[..bpp];
+        if pixel.iter().all(|&byte| byte == 0) {
+            // Black is the common case and needs no pattern at all
+            buffer.fill(0);
+            return Ok(());
+        }
 
-        let vec: alloc::vec::Vec<u32>;
-        let vec2: alloc::vec::Vec<u8>;
-
-        let wide = match info.pixel_format {
-            PixelFormat::Rgb => {
-                let color =
-                    color.red as u32 | (color.green as u32) << 8 | (color.blue as u32) << 16;
-                debug_assert_eq!(info.bytes_per_pixel, 4);
-                vec = vec![color; info.width];
-                vec.as_ptr() as *const u8
-            }
-            PixelFormat::Bgr => {
-                let color =
-                    color.blue as u32 | (color.green as u32) << 8 | (color.red as u32) << 16;
-                debug_assert_eq!(info.bytes_per_pixel, 4);
-                vec = vec![color; info.width];
-                vec.as_ptr() as *const u8
-            }
-            PixelFormat::U8 => {
-                let gray = color.red / 3 + color.green / 3 + color.blue / 3;
-                debug_assert_eq!(info.bytes_per_pixel, 1);
-                vec2 = vec![gray; info.width];
-                vec2.as_ptr()
+        match bpp {
+            1 => buffer.fill(pixel[0]),
+            4 => {
+                let pixel = u32::from_ne_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]) as u64;
+                fill_wide(buffer, pixel << 32 | pixel);
             }
-            _ => todo!(),
-        };
-        for y in 0..info.height {
-            let offset = (y * info.width) * info.bytes_per_pixel;
-            unsafe {
-                let addr = self.backbuffer.as_mut_ptr().add(offset);
-                core::ptr::copy_nonoverlapping(wide, addr, info.width * info.bytes_per_pixel);
+            _ => {
+                for chunk in buffer.chunks_exact_mut(bpp) {
+                    chunk.copy_from_slice(pixel);
+                }
             }
         }
         Ok(())
@@ -287,3 +535,229 @@ impl<'f> OriginDimensions for Display<'f> {
         Size::new(info.width as u32, info.height as u32)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use alloc::{format, string::String};
+    use embedded_graphics::{
+        pixelcolor::Rgb888,
+        prelude::*,
+        primitives::{Circle, PrimitiveStyle, Rectangle},
+    };
+
+    use super::{blend, luma, Color, DISPLAY};
+    use crate::testing::Bench;
+
+    #[test_case]
+    static CLEAR_SCREEN: Bench = Bench {
+        name: "kernel::framebuffer::test::clear_screen",
+        iterations: 20,
+        bench: || {
+            let _ = DISPLAY.get().spin_lock().as_mut().clear(Rgb888::BLACK);
+        },
+    };
+
+    #[test_case]
+    static CLEAR_SCREEN_COLOR: Bench = Bench {
+        name: "kernel::framebuffer::test::clear_screen_color",
+        iterations: 20,
+        bench: || {
+            let _ = DISPLAY.get().spin_lock().as_mut().clear(Rgb888::CSS_TEAL);
+        },
+    };
+
+    #[test_case]
+    fn fill_wide_keeps_phase() {
+        let pattern = u64::from_le_bytes([1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut buffer = [0u8; 40];
+        // Unaligned on both ends so the head and tail are written bytewise
+        super::fill_wide(&mut buffer[3..37], pattern);
+        for (i, &byte) in buffer[3..37].iter().enumerate() {
+            assert_eq!(byte, (i % 8) as u8 + 1);
+        }
+        assert_eq!(&buffer[..3], &[0; 3]);
+        assert_eq!(&buffer[37..], &[0; 3]);
+    }
+
+    #[test_case]
+    fn clear_fills_every_pixel() {
+        let mut display = DISPLAY.get().spin_lock();
+        let size = display.bounding_box().size;
+        // Gray reads back the same from grayscale framebuffers
+        let color = Rgb888::new(30, 30, 30);
+        let _ = display.clear(color);
+        for point in [
+            Point::zero(),
+            Point::new(size.width as i32 - 1, 0),
+            Point::new(size.width as i32 / 2, size.height as i32 / 2),
+            Point::new(size.width as i32 - 1, size.height as i32 - 1),
+        ] {
+            assert_eq!(display.pixel(point), Some(color));
+        }
+        let _ = display.clear(Rgb888::BLACK);
+        assert!(display.backbuffer.iter().all(|&byte| byte == 0));
+    }
+
+    #[test_case]
+    fn fill_solid_reuses_row() {
+        let mut display = DISPLAY.get().spin_lock();
+        // Gray reads back the same from grayscale framebuffers
+        let dark = Rgb888::new(60, 60, 60);
+        let light = Rgb888::new(90, 90, 90);
+        let narrow = Rectangle::new(Point::new(0, 0), Size::new(4, 2));
+        let wide = Rectangle::new(Point::new(0, 2), Size::new(12, 2));
+
+        let _ = display.fill_solid(&narrow, dark);
+        // Same color but wider, so the cached row has to grow
+        let _ = display.fill_solid(&wide, dark);
+        assert!(wide
+            .points()
+            .all(|point| display.pixel(point) == Some(dark)));
+        let _ = display.fill_solid(&narrow, light);
+        assert!(narrow
+            .points()
+            .all(|point| display.pixel(point) == Some(light)));
+        assert_eq!(display.pixel(Point::new(11, 3)), Some(dark));
+    }
+
+    #[test_case]
+    fn batch_flushes_once() {
+        let mut display = DISPLAY.get().spin_lock();
+        let flushes = display.flush_count();
+
+        display.begin_frame();
+        let style = PrimitiveStyle::with_fill(Rgb888::RED);
+        let _ = Rectangle::new(Point::new(4, 4), Size::new(16, 8))
+            .into_styled(style)
+            .draw(display.as_mut());
+        display.draw_frame();
+        let _ = Circle::new(Point::new(40, 20), 10)
+            .into_styled(style)
+            .draw(display.as_mut());
+        let _ = Pixel(Point::new(2, 2), Rgb888::GREEN).draw(display.as_mut());
+        display.draw_frame();
+        assert_eq!(display.flush_count(), flushes);
+
+        display.end_frame();
+        assert_eq!(display.flush_count(), flushes + 1);
+        display.draw_frame();
+        assert!(display.frame_stats().average_ticks().is_some());
+    }
+
+    #[test_case]
+    fn fill_contiguous_blits_gradient() {
+        let mut display = DISPLAY.get().spin_lock();
+        let info = display.get_info();
+        // 7 * 30 still fits a u8
+        let gradient = |i: usize| Rgb888::new(i as u8 * 30, 0, 255 - i as u8 * 30);
+
+        // Starts one column off screen so the first column is clipped
+        let area = Rectangle::new(Point::new(-1, 2), Size::new(4, 2));
+        let _ = display.fill_contiguous(&area, (0..8).map(gradient));
+
+        for (i, point) in area.points().enumerate() {
+            if point.x < 0 {
+                continue;
+            }
+            let offset = (point.y as usize * info.width + point.x as usize) * info.bytes_per_pixel;
+            let mut expected = [0; 4];
+            super::write_pixel(&mut expected, info.pixel_format, Color::from(gradient(i)));
+            assert_eq!(
+                &display.backbuffer[offset..offset + info.bytes_per_pixel],
+                &expected[..info.bytes_per_pixel]
+            );
+        }
+    }
+
+    #[test_case]
+    fn fill_contiguous_matches_fill_solid() {
+        let mut display = DISPLAY.get().spin_lock();
+        let info = display.get_info();
+        let color = Rgb888::new(12, 200, 99);
+        let solid = Rectangle::new(Point::new(8, 40), Size::new(5, 3));
+        let contiguous = Rectangle::new(Point::new(20, 40), Size::new(5, 3));
+
+        let _ = display.fill_solid(&solid, color);
+        let _ = display.fill_contiguous(&contiguous, core::iter::repeat(color));
+
+        let bytes = |point: Point| {
+            let offset = (point.y as usize * info.width + point.x as usize) * info.bytes_per_pixel;
+            &display.backbuffer[offset..offset + info.bytes_per_pixel]
+        };
+        for (a, b) in solid.points().zip(contiguous.points()) {
+            assert_eq!(bytes(a), bytes(b));
+        }
+    }
+
+    #[test_case]
+    fn blend_endpoints() {
+        let fg = Rgb888::new(200, 100, 0);
+        assert_eq!(blend(fg, Rgb888::BLACK, 255), fg);
+        assert_eq!(blend(fg, Rgb888::BLACK, 0), Rgb888::BLACK);
+        assert_eq!(blend(fg, Rgb888::WHITE, 128), Rgb888::new(227, 177, 127));
+    }
+
+    #[test_case]
+    fn luma_weights_primaries() {
+        assert_eq!(luma(Color::from(Rgb888::RED)), 76);
+        assert_eq!(luma(Color::from(Rgb888::GREEN)), 149);
+        assert_eq!(luma(Color::from(Rgb888::BLUE)), 28);
+        assert_eq!(luma(Color::from(Rgb888::WHITE)), 255);
+        assert_eq!(luma(Color::from(Rgb888::new(0x80, 0x80, 0x80))), 0x80);
+    }
+
+    #[test_case]
+    fn screenshot_reads_backbuffer() {
+        let mut display = DISPLAY.get().spin_lock();
+        let size = display.bounding_box().size;
+        // Gray reads back the same from grayscale framebuffers
+        let _ = display.clear(Rgb888::new(40, 40, 40));
+        let _ = display.fill_solid(
+            &Rectangle::new(Point::zero(), Size::new(1, 1)),
+            Rgb888::new(200, 200, 200),
+        );
+
+        let scale = 64;
+        let mut out = String::new();
+        display.screenshot_ppm(&mut out, scale).unwrap();
+        let _ = display.clear(Rgb888::BLACK);
+
+        let (width, height) = (
+            (size.width as usize).div_ceil(scale),
+            (size.height as usize).div_ceil(scale),
+        );
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some("P3"));
+        assert_eq!(lines.next(), Some(format!("{width} {height}").as_str()));
+        assert_eq!(lines.next(), Some("255"));
+        assert_eq!(lines.next(), Some("200 200 200"));
+        assert_eq!(lines.next(), Some("40 40 40"));
+        assert_eq!(lines.count(), width * height - 2);
+    }
+
+    #[test_case]
+    fn brightness_scales_writes() {
+        let mut display = DISPLAY.get().spin_lock();
+        let area = Rectangle::new(Point::zero(), Size::new(4, 1));
+        // Gray reads back the same from grayscale framebuffers
+        let gray = Rgb888::new(200, 200, 200);
+
+        display.set_brightness(128);
+        let _ = display.fill_solid(&area, gray);
+        let dimmed = Rgb888::new(100, 100, 100);
+        assert_eq!(display.pixel(Point::zero()), Some(dimmed));
+        let _ = display.fill_contiguous(&area, [gray; 4]);
+        assert_eq!(display.pixel(Point::new(3, 0)), Some(dimmed));
+        Pixel(Point::new(1, 0), gray)
+            .draw(display.as_mut())
+            .unwrap();
+        assert_eq!(display.pixel(Point::new(1, 0)), Some(dimmed));
+        let _ = display.fill_solid(&area, Rgb888::BLACK);
+        assert_eq!(display.pixel(Point::zero()), Some(Rgb888::BLACK));
+
+        display.set_brightness(u8::MAX);
+        let _ = display.fill_solid(&area, gray);
+        assert_eq!(display.pixel(Point::zero()), Some(gray));
+        let _ = display.clear(Rgb888::BLACK);
+    }
+}
@@ -12,11 +12,24 @@ use x86_64::{
 
 use crate::util::once::Lazy;
 
+// Interrupt stack table slots. An exception with a slot always starts at the top of its stack,
+// so its handler must not raise the same exception again.
+
+/// Double faults, which are what a fault on a broken stack escalates to.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Page faults, the first thing a kernel stack overflow hits is the guard page.
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+/// General protection faults, which can also come from a bad stack pointer.
+pub const GENERAL_PROTECTION_FAULT_IST_INDEX: u16 = 2;
+/// Slot in the TSS privilege stack table the CPU loads RSP from when entering ring 0.
+const RING0_STACK_INDEX: usize = 0;
 
 static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
     let mut tss = TaskStateSegment::new();
     tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = create_stack();
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = create_stack();
+    tss.interrupt_stack_table[GENERAL_PROTECTION_FAULT_IST_INDEX as usize] = create_stack();
+    tss.privilege_stack_table[RING0_STACK_INDEX] = create_stack();
     tss
 });
 
@@ -33,12 +46,19 @@ static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
     let mut gdt = GlobalDescriptorTable::new();
     let kernel_code_selector = gdt.append(Descriptor::kernel_code_segment());
     let kernel_data_selector = gdt.append(Descriptor::kernel_data_segment());
+    // sysret expects user data right before user code
+    let user_data_selector = gdt.append(Descriptor::user_data_segment());
+    let user_code_selector = gdt.append(Descriptor::user_code_segment());
     let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
     (
         gdt,
         Selectors {
             kernel_code_selector,
             kernel_data_selector,
+            user: UserSelectors {
+                code: user_code_selector,
+                data: user_data_selector,
+            },
             tss_selector,
         },
     )
@@ -47,9 +67,30 @@ static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
 struct Selectors {
     kernel_code_selector: SegmentSelector,
     kernel_data_selector: SegmentSelector,
+    user: UserSelectors,
     tss_selector: SegmentSelector,
 }
 
+/// Ring 3 segments, their RPL is already 3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserSelectors {
+    pub code: SegmentSelector,
+    pub data: SegmentSelector,
+}
+
+pub fn user_selectors() -> UserSelectors {
+    GDT.1.user
+}
+
+pub(crate) fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.kernel_code_selector, GDT.1.kernel_data_selector)
+}
+
+/// The stack the CPU switches to when an interrupt or syscall arrives from ring 3.
+pub fn ring0_stack() -> VirtAddr {
+    TSS.privilege_stack_table[RING0_STACK_INDEX]
+}
+
 #[instrument(name = "gdt_init")]
 pub fn init() {
     GDT.0.load();
@@ -60,3 +101,22 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use x86_64::PrivilegeLevel;
+
+    use super::{kernel_selectors, user_selectors};
+
+    #[test_case]
+    fn user_selectors_are_ring3() {
+        let user = user_selectors();
+        assert_eq!(user.code.rpl(), PrivilegeLevel::Ring3);
+        assert_eq!(user.data.rpl(), PrivilegeLevel::Ring3);
+
+        let (kernel_code, _) = kernel_selectors();
+        assert_eq!(kernel_code.rpl(), PrivilegeLevel::Ring0);
+        // sysret loads SS from the selector before CS
+        assert_eq!(user.code.index(), user.data.index() + 1);
+    }
+}
@@ -1,29 +1,94 @@
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use num_enum::IntoPrimitive;
-use raw_cpuid::{CpuId, Hypervisor};
+use raw_cpuid::Hypervisor;
 use tracing::error;
 use x86_64::{
     instructions::port::Port,
     structures::idt::{
-        InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode, SelectorErrorCode,
+        ExceptionVector, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+        SelectorErrorCode,
     },
+    VirtAddr,
 };
 
 use crate::{
     apic::LAPIC,
+    cpu,
+    debug::gdb::{self, Registers},
     gdt,
     keyboard::add_scancode,
+    memory::mapping,
+    mouse,
     pic::PICS,
     println,
     rtc::RTC,
+    serial::{self, ComPort},
+    testing,
+    time::{self, TimeSource},
     util::{
         once::Lazy,
-        r#async::sleep_future::{wake_sleep, MONOTONIC_TIME},
+        r#async::{
+            mutex::Mutex,
+            sleep_future::{wake_sleep, MONOTONIC_TIME},
+        },
     },
 };
 
 pub const INTERRUPT_START: u8 = 32;
+pub const VECTOR_COUNT: usize = 256;
+
+/// How many times each vector's handler has run.
+static COUNTS: [AtomicU64; VECTOR_COUNT] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; VECTOR_COUNT]
+};
+
+/// Snapshot of how many times each interrupt vector fired, indexed by vector.
+pub fn counts() -> [u64; VECTOR_COUNT] {
+    core::array::from_fn(|vector| COUNTS[vector].load(Ordering::Relaxed))
+}
+
+#[inline(always)]
+fn count(vector: u8) {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// CPU state of the exception that caused the current panic.
+#[derive(Debug, Clone, Copy)]
+pub struct ExceptionContext {
+    pub exception: &'static str,
+    pub instruction_pointer: VirtAddr,
+    pub stack_pointer: VirtAddr,
+    /// Cr2 for page faults.
+    pub fault_address: Option<VirtAddr>,
+}
+
+static EXCEPTION_CONTEXT: Mutex<Option<ExceptionContext>> = Mutex::new(None);
+
+/// The exception an exception handler panicked with, if that's how the panic started.
+///
+/// Never blocks, so the panic handler can call it.
+pub fn exception_context() -> Option<ExceptionContext> {
+    *EXCEPTION_CONTEXT.try_lock()?
+}
+
+/// Saves the exception for [`exception_context`] right before its handler panics.
+fn record_exception(
+    exception: &'static str,
+    stack_frame: &InterruptStackFrame,
+    fault_address: Option<VirtAddr>,
+) {
+    if let Some(mut context) = EXCEPTION_CONTEXT.try_lock() {
+        *context = Some(ExceptionContext {
+            exception,
+            instruction_pointer: stack_frame.instruction_pointer,
+            stack_pointer: stack_frame.stack_pointer,
+            fault_address,
+        });
+    }
+}
 
 fn notify_end_of_interrupt(index: InterruptIndex) {
     if let Ok(lapic) = LAPIC.try_get() {
@@ -39,17 +104,36 @@ fn notify_end_of_interrupt(index: InterruptIndex) {
 pub enum InterruptIndex {
     Timer = INTERRUPT_START,
     Keyboard,
+    Serial = INTERRUPT_START + 4,
     Clock = INTERRUPT_START + 8,
+    Mouse = INTERRUPT_START + 12,
     LapicErr = INTERRUPT_START + 17, //49
     Spurious = 0xff,
 }
 
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
-    idt.general_protection_fault
-        .set_handler_fn(general_protection_fault_handler);
+    // Both run on their own stacks so overflowing the kernel stack can still be reported
+    unsafe {
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler)
+            .set_stack_index(gdt::GENERAL_PROTECTION_FAULT_IST_INDEX);
+        idt.page_fault
+            .set_handler_fn(page_fault_handler)
+            .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+    }
     idt.breakpoint.set_handler_fn(breakpoint_handler);
-    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded
+        .set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
+    idt.x87_floating_point
+        .set_handler_fn(x87_floating_point_handler);
+    idt.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
     idt.invalid_tss.set_handler_fn(invalid_tss_handler);
     idt.segment_not_present
         .set_handler_fn(segment_not_present_handler);
@@ -60,6 +144,8 @@ static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     }
     idt[InterruptIndex::Timer as u8].set_handler_fn(timer_interrupt_handler);
     idt[InterruptIndex::Keyboard as u8].set_handler_fn(keyboard_interrupt_handler);
+    idt[InterruptIndex::Serial as u8].set_handler_fn(serial_interrupt_handler);
+    idt[InterruptIndex::Mouse as u8].set_handler_fn(mouse_interrupt_handler);
     idt[InterruptIndex::LapicErr as u8].set_handler_fn(lapic_err_interrupt_handler);
     idt[InterruptIndex::Spurious as u8].set_handler_fn(spurious_interrupt_handler);
     idt[InterruptIndex::Clock as u8]
@@ -77,6 +163,7 @@ extern "x86-interrupt" fn general_protection_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    count(ExceptionVector::GeneralProtection as u8);
     println!(
         "encountered a general protection fault, error code {} =",
         error_code
@@ -85,17 +172,76 @@ extern "x86-interrupt" fn general_protection_fault_handler(
     println!("tbl: {}", (error_code >> 1) & 0b11);
     println!("e: {}", error_code & 1);
 
+    record_exception("general protection fault", &stack_frame, None);
     panic!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    count(ExceptionVector::Breakpoint as u8);
+    if gdb::attached() {
+        gdb::serve(&mut ComPort::Com2, &Registers::from_frame(&stack_frame));
+        return;
+    }
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+/// Logs an exception nothing can recover from and panics with its name.
+///
+/// The x86-interrupt ABI only hands over the interrupt stack frame, so that's the register dump.
+fn fatal_exception(exception: &'static str, stack_frame: &InterruptStackFrame) -> ! {
+    error!(
+        "{exception} at {:?}\n{stack_frame:#?}",
+        stack_frame.instruction_pointer
+    );
+    record_exception(exception, stack_frame, None);
+    panic!(
+        "EXCEPTION: {exception} at {:?}",
+        stack_frame.instruction_pointer
+    );
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    count(ExceptionVector::Division as u8);
+    fatal_exception("divide error", &stack_frame);
+}
+
+// INTO and BOUND don't exist in long mode, so these two shouldn't fire
+extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    count(ExceptionVector::Overflow as u8);
+    fatal_exception("overflow", &stack_frame);
+}
+
+extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
+    count(ExceptionVector::BoundRange as u8);
+    fatal_exception("bound range exceeded", &stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    count(ExceptionVector::InvalidOpcode as u8);
+    fatal_exception("invalid opcode", &stack_frame);
+}
+
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    count(ExceptionVector::DeviceNotAvailable as u8);
+    fatal_exception("device not available", &stack_frame);
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    count(ExceptionVector::X87FloatingPoint as u8);
+    fatal_exception("x87 floating point", &stack_frame);
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    count(ExceptionVector::SimdFloatingPoint as u8);
+    fatal_exception("SIMD floating point", &stack_frame);
+}
+
 extern "x86-interrupt" fn double_fault_hander(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    count(ExceptionVector::Double as u8);
+    record_exception("double fault", &stack_frame, None);
     panic!(
         "EXCEPTION: DOUBLE FAULT\n{:#?}\nerror: {_error_code}",
         stack_frame
@@ -106,8 +252,35 @@ extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    count(ExceptionVector::Page as u8);
     use x86_64::registers::control::Cr2;
 
+    if let Ok(addr) = Cr2::read()
+        && mapping::is_stack_guard(addr)
+    {
+        record_exception("stack overflow", &stack_frame, Some(addr));
+        panic!(
+            "STACK GUARD: kernel stack overflowed into its guard page at {:p}\n{:#?}",
+            addr, stack_frame
+        );
+    }
+    // Not-present faults in a lazy region just need a frame, the instruction is retried on return
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && let Ok(addr) = Cr2::read()
+        && mapping::handle_lazy_fault(addr)
+    {
+        return;
+    }
+    // Writes to copy-on-write pages get their own frame and are retried the same way
+    if error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && let Ok(addr) = Cr2::read()
+        && mapping::handle_cow_fault(addr)
+    {
+        return;
+    }
+
+    record_exception("page fault", &stack_frame, Cr2::read().ok());
     panic!(
         "EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\n{:#?}",
         Cr2::read(),
@@ -117,6 +290,7 @@ extern "x86-interrupt" fn page_fault_handler(
 }
 
 extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    count(ExceptionVector::InvalidTss as u8);
     error!("Invalid TSS at segment selector: {error_code:#?}\n{stack_frame:#?}");
 }
 
@@ -124,10 +298,10 @@ extern "x86-interrupt" fn segment_not_present_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    count(ExceptionVector::SegmentNotPresent as u8);
     let error_code = SelectorErrorCode::new_truncate(error_code);
-    let cpu = CpuId::new();
-    let index = match cpu.get_hypervisor_info() {
-        Some(hypervisor) if hypervisor.identify() == Hypervisor::QEMU => error_code.index() / 2,
+    let index = match cpu::features().hypervisor {
+        Some(Hypervisor::QEMU) => error_code.index() / 2,
         _ => error_code.index(),
     };
     error!(
@@ -139,18 +313,30 @@ extern "x86-interrupt" fn segment_not_present_handler(
     );
 }
 
+/// Advances [`MONOTONIC_TIME`] by a tick and wakes whatever was waiting on it.
+fn tick() {
+    let curr_time = MONOTONIC_TIME.fetch_add(1, Ordering::AcqRel);
+    testing::watchdog_tick(curr_time);
+    wake_sleep(curr_time);
+}
+
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    count(InterruptIndex::Timer as u8);
+    if time::time_source() == TimeSource::Pit {
+        tick();
+    }
     notify_end_of_interrupt(InterruptIndex::Timer);
 }
 
 extern "x86-interrupt" fn clock_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    let curr_time = MONOTONIC_TIME.fetch_add(1, Ordering::AcqRel);
-    wake_sleep(curr_time);
+    count(InterruptIndex::Clock as u8);
+    tick();
     notify_end_of_interrupt(InterruptIndex::Clock);
     RTC.spin_lock().clear_interrup_mask();
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    count(InterruptIndex::Keyboard as u8);
     let mut port = Port::new(0x60);
 
     let scancode: u8 = unsafe { port.read() };
@@ -159,10 +345,69 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     notify_end_of_interrupt(InterruptIndex::Keyboard);
 }
 
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    count(InterruptIndex::Mouse as u8);
+    let mut port = Port::new(0x60);
+
+    let byte: u8 = unsafe { port.read() };
+    mouse::add_byte(byte);
+
+    notify_end_of_interrupt(InterruptIndex::Mouse);
+}
+
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    count(InterruptIndex::Serial as u8);
+    serial::receive_pending();
+
+    notify_end_of_interrupt(InterruptIndex::Serial);
+}
+
 extern "x86-interrupt" fn lapic_err_interrupt_handler(stack_frame: InterruptStackFrame) {
+    count(InterruptIndex::LapicErr as u8);
+    record_exception("lapic error", &stack_frame, None);
     panic!("EXCEPTION: LAPIC ERROR\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: InterruptStackFrame) {
+    count(InterruptIndex::Spurious as u8);
+    record_exception("spurious interrupt", &stack_frame, None);
     panic!("EXCEPTION: SPURIOUS INTERRUPT\n{:#?}", stack_frame);
 }
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use x86_64::structures::idt::ExceptionVector;
+
+    use super::{counts, exception_context};
+    use crate::testing::ShouldPanic;
+
+    #[test_case]
+    fn breakpoint_is_counted() {
+        let before = counts()[ExceptionVector::Breakpoint as usize];
+        x86_64::instructions::interrupts::int3();
+        assert_eq!(counts()[ExceptionVector::Breakpoint as usize], before + 1);
+    }
+
+    static INVALID_OPCODES: AtomicU64 = AtomicU64::new(0);
+
+    #[test_case]
+    static INVALID_OPCODE_RECORDED: ShouldPanic = ShouldPanic {
+        name: "kernel::interrupts::test::invalid_opcode_recorded",
+        test: || {
+            let before = counts()[ExceptionVector::InvalidOpcode as usize];
+            INVALID_OPCODES.store(before, Ordering::SeqCst);
+            unsafe { core::arch::asm!("ud2") }
+        },
+        // The handler's panic is the one checked, so nothing can record over it first
+        check: Some(|_| {
+            let context = exception_context().expect("handler should record its exception");
+            assert_eq!(context.exception, "invalid opcode");
+            assert_eq!(
+                counts()[ExceptionVector::InvalidOpcode as usize],
+                INVALID_OPCODES.load(Ordering::SeqCst) + 1
+            );
+        }),
+    };
+}
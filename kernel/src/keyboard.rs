@@ -1,12 +1,19 @@
 use core::{
+    future,
     pin::Pin,
     task::{Context, Poll},
 };
 
-use crate::{util::once::OnceLock, vga_print};
+use crate::{
+    util::{
+        once::OnceLock,
+        r#async::stream::{QueueStream, Stream, StreamExt},
+    },
+    vga_print,
+};
 use crossbeam_queue::ArrayQueue;
-use futures::{task::AtomicWaker, Stream, StreamExt};
-use pc_keyboard::{layouts, Keyboard, ScancodeSet1};
+use futures::task::AtomicWaker;
+use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
 use tracing::warn;
 
 static SCANCODE_QUEUE: OnceLock<ArrayQueue<u8>> = OnceLock::new();
@@ -23,7 +30,7 @@ pub(crate) fn add_scancode(scancode: u8) {
 }
 
 pub struct ScancodeStream {
-    _private: (),
+    inner: QueueStream<u8>,
 }
 
 impl ScancodeStream {
@@ -31,7 +38,9 @@ impl ScancodeStream {
         SCANCODE_QUEUE
             .try_init_once(|| ArrayQueue::new(100))
             .expect("ScancodeStream::new should only be called once");
-        ScancodeStream { _private: () }
+        ScancodeStream {
+            inner: QueueStream::new(SCANCODE_QUEUE.get(), &WAKER),
+        }
     }
 }
 
@@ -44,24 +53,35 @@ impl Default for ScancodeStream {
 impl Stream for ScancodeStream {
     type Item = u8;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let queue = SCANCODE_QUEUE.try_get().expect("not initialized");
-
-        if let Some(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
-        }
-
-        WAKER.register(cx.waker());
-        match queue.pop() {
-            Some(scancode) => {
-                WAKER.take();
-                Poll::Ready(Some(scancode))
-            }
-            None => Poll::Pending,
-        }
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
     }
 }
 
+/// The ASCII characters typed on the keyboard, other keys are dropped.
+///
+/// # Panics
+/// Panics if the scancodes are already being read, see [`ScancodeStream::new`].
+pub fn typed_bytes() -> impl Stream<Item = u8> {
+    let mut keyboard = Keyboard::new(
+        ScancodeSet1::new(),
+        layouts::Us104Key,
+        pc_keyboard::HandleControl::Ignore,
+    );
+    ScancodeStream::new().filter_map(move |scancode| {
+        let byte = match keyboard.add_byte(scancode) {
+            Ok(Some(key_event)) => match keyboard.process_keyevent(key_event) {
+                Some(DecodedKey::Unicode(character)) if character.is_ascii() => {
+                    Some(character as u8)
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        future::ready(byte)
+    })
+}
+
 pub async fn print_keypresses() {
     let mut scancodes = ScancodeStream::new();
     let mut keyboard = Keyboard::new(
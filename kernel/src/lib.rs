@@ -2,6 +2,7 @@
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 #![feature(allocator_api)]
 #![feature(const_mut_refs)]
 #![feature(error_in_core)]
@@ -15,32 +16,44 @@ extern crate alloc;
 pub mod acpi;
 pub mod allocator;
 pub mod apic;
+pub mod cpu;
+pub mod debug;
+pub mod diagnostics;
 pub mod display;
 pub mod framebuffer;
 pub mod gdt;
 pub mod interrupts;
 pub mod keyboard;
 pub mod memory;
+pub mod mouse;
+pub mod panic_screen;
+pub mod pci;
 pub mod pic;
+pub mod pit;
 pub mod qemu;
 pub mod rtc;
 pub mod serial;
+pub mod shell;
+pub mod speaker;
+pub mod syscall;
 pub mod task;
 pub mod testing;
+pub mod time;
 pub mod tracer;
 pub mod util;
 pub mod vga_buffer;
 
-use acpi::{KERNEL_ACPI_ADDR, KERNEL_ACPI_LEN};
-use allocator::{KERNEL_HEAP_ADDR, KERNEL_HEAP_LEN};
+use allocator::{KERNEL_HEAP_ADDR, KERNEL_HEAP_LEN, KERNEL_HEAP_MAX_LEN};
 use apic::{KERNEL_APIC_ADDR, KERNEL_APIC_LEN};
 #[cfg(test)]
 use bootloader_api::entry_point;
 use bootloader_api::{config::Mapping, BootInfo, BootloaderConfig};
 use embedded_graphics::{pixelcolor::Rgb888, prelude::*};
 use framebuffer::DISPLAY;
-use tracing::{span, trace, Level};
-use util::once::OnceLock;
+use memory::mapping::{self, KERNEL_MMIO_ADDR, KERNEL_MMIO_LEN};
+use time::TimeSource;
+use tracing::{span, trace, warn, Level};
+use util::{backtrace, once::OnceLock};
 use x86_64::{
     structures::paging::{Page, Size4KiB},
     VirtAddr,
@@ -51,17 +64,47 @@ pub static PHYS_OFFSET: OnceLock<u64> = OnceLock::new();
 pub static KERNEL_CODE_ADDR: OnceLock<VirtAddr> = OnceLock::new();
 pub static KERNEL_CODE_LEN: OnceLock<usize> = OnceLock::new();
 
+/// Knobs for [`init_with`] that would otherwise need a rebuild.
+#[derive(Debug, Clone, Copy)]
+pub struct InitOptions {
+    /// Drives [`MONOTONIC_TIME`](util::r#async::sleep_future::MONOTONIC_TIME).
+    pub time_source: TimeSource,
+    /// How large to make the heap, clamped to the usable memory.
+    pub heap_len: usize,
+}
+
+impl Default for InitOptions {
+    fn default() -> Self {
+        Self {
+            time_source: TimeSource::default(),
+            heap_len: KERNEL_HEAP_LEN,
+        }
+    }
+}
+
 pub fn init(boot_info: &'static mut BootInfo) {
+    init_with(boot_info, InitOptions::default());
+}
+
+/// [`init`], with `options` in place of the defaults.
+pub fn init_with(boot_info: &'static mut BootInfo, options: InitOptions) {
+    let InitOptions {
+        time_source,
+        heap_len,
+    } = options;
+    time::set_time_source(time_source);
     let kernel_code_addr = VirtAddr::new(boot_info.kernel_image_offset);
     let kernel_code_len = boot_info.kernel_len;
     let kernel_heap_addr = (kernel_code_addr + kernel_code_len).align_up(Page::<Size4KiB>::SIZE);
-    let kernel_heap_len = KERNEL_HEAP_LEN;
-    let kernel_acpi_addr =
-        (kernel_heap_addr + kernel_heap_len as u64).align_up(Page::<Size4KiB>::SIZE);
-    let kernel_acpi_len = KERNEL_ACPI_LEN;
+    let usable = memory::usable_bytes(&boot_info.memory_regions);
+    let kernel_heap_len = allocator::clamp_heap_len(heap_len, usable);
+    // The heap grows into the rest of its reserved space
     let kernel_apic_addr =
-        (kernel_acpi_addr + kernel_acpi_len as u64).align_up(Page::<Size4KiB>::SIZE);
+        (kernel_heap_addr + KERNEL_HEAP_MAX_LEN as u64).align_up(Page::<Size4KiB>::SIZE);
     let kernel_apic_len = KERNEL_APIC_LEN;
+    let kernel_mmio_addr =
+        (kernel_apic_addr + kernel_apic_len as u64).align_up(Page::<Size4KiB>::SIZE);
+    let kernel_mmio_len = KERNEL_MMIO_LEN;
 
     let phys_offset = boot_info.physical_memory_offset.into_option().unwrap();
 
@@ -69,20 +112,33 @@ pub fn init(boot_info: &'static mut BootInfo) {
     println!("kernel_code_len: {:#x}", kernel_code_len);
     println!("kernel_heap_addr: {:p}", kernel_heap_addr);
     println!("kernel_heap_len: {:#x}", kernel_heap_len);
-    println!("kernel_acpi_addr: {:p}", kernel_acpi_addr);
-    println!("kernel_acpi_len: {:#x}", kernel_acpi_len);
     println!("kernel_apic_addr: {:p}", kernel_apic_addr);
     println!("kernel_apic_len: {:#x}", kernel_apic_len);
+    println!("kernel_mmio_addr: {:p}", kernel_mmio_addr);
+    println!("kernel_mmio_len: {:#x}", kernel_mmio_len);
 
     KERNEL_CODE_ADDR.init_once(|| kernel_code_addr);
     KERNEL_CODE_LEN.init_once(|| kernel_code_len as usize);
     KERNEL_HEAP_ADDR.init_once(|| kernel_heap_addr);
-    KERNEL_ACPI_ADDR.init_once(|| kernel_acpi_addr);
+    allocator::set_heap_len(kernel_heap_len);
     KERNEL_APIC_ADDR.init_once(|| kernel_apic_addr);
+    KERNEL_MMIO_ADDR.init_once(|| kernel_mmio_addr);
 
     PHYS_OFFSET.init_once(|| phys_offset);
 
     memory::init(&boot_info.memory_regions).expect("page alloc failed to be created");
+    // The bootloader leaves the whole kernel file in memory, symbols included
+    let kernel_elf = unsafe {
+        core::slice::from_raw_parts(
+            (phys_offset + boot_info.kernel_addr) as *const u8,
+            boot_info.kernel_len as usize,
+        )
+    };
+    let symbols = backtrace::init_symbols(kernel_elf, kernel_code_addr.as_u64());
+    // The heap past its first few pages is mapped by the page fault handler, so it has to be
+    // loaded before anything large is allocated
+    gdt::init();
+    interrupts::init_idt();
     // I don't really want to support a target with no display
     framebuffer::init(boot_info.framebuffer.as_mut().unwrap());
     let _ = DISPLAY.get().spin_lock().as_mut().clear(Rgb888::BLACK);
@@ -91,10 +147,31 @@ pub fn init(boot_info: &'static mut BootInfo) {
     let init_span = span!(Level::TRACE, "kernel_init");
     let _guard = init_span.enter();
 
-    gdt::init();
+    if kernel_heap_len < heap_len {
+        warn!(
+            requested = heap_len,
+            usable, "heap clamped to {:#x} bytes", kernel_heap_len
+        );
+    }
+    trace!(heap_len = kernel_heap_len, "init heap");
+    match symbols {
+        Ok(()) => trace!("init kernel symbols"),
+        Err(err) => warn!("backtraces will not have symbols: {err}"),
+    }
     trace!("init gdt");
-    interrupts::init_idt();
     trace!("init idt");
+    // Anything on this stack tells us where it is
+    let on_stack = 0u8;
+    mapping::init_stack_guard(
+        VirtAddr::from_ptr(&on_stack),
+        BOOTLOADER_CONFIG.kernel_stack_size,
+    );
+    trace!("init stack guard");
+    match syscall::init() {
+        Ok(()) => trace!("init syscall"),
+        Err(err) => warn!("syscall/sysret unavailable: {err}"),
+    }
+    cpu::init();
     // Unwrapping is okay because if we don't have rsdp we don't know how to boot
     let platform_info = acpi::init(*boot_info.rsdp_addr.as_ref().unwrap());
     trace!("init acpi");
@@ -107,8 +184,22 @@ pub fn init(boot_info: &'static mut BootInfo) {
         pic::init();
         trace!("no apic, legacy pic mode init");
     }
-    rtc::init();
-    trace!("init rtc");
+    match time_source {
+        TimeSource::Rtc => {
+            rtc::init();
+            trace!("init rtc");
+        }
+        TimeSource::Pit => {
+            pit::init(rtc::TIMER_FREQ as u32);
+            trace!(frequency = pit::frequency(), "init pit");
+        }
+    }
+    pci::init();
+    trace!("init pci");
+    match mouse::init() {
+        Ok(()) => trace!("init mouse"),
+        Err(err) => warn!("no PS/2 mouse: {err}"),
+    }
 }
 
 pub const BOOTLOADER_CONFIG: BootloaderConfig = {
@@ -127,6 +218,8 @@ pub fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
     use util::hlt_loop;
 
     init(boot_info); // new
+    // Span timing would log on every clock tick now that tests run with interrupts enabled
+    tracer::SHOULD_TIME_SPANS.store(false, core::sync::atomic::Ordering::Relaxed);
     test_main();
     hlt_loop()
 }
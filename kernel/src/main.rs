@@ -2,56 +2,49 @@
 #![no_main]
 #![feature(custom_test_frameworks)]
 #![feature(never_type)]
+#![feature(let_chains)]
 #![test_runner(kernel::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
 
-use core::{panic::PanicInfo, time::Duration};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use alloc::string::ToString;
 use bootloader_api::{entry_point, BootInfo};
-use embedded_graphics::{
-    mono_font::{ascii::FONT_9X15, MonoTextStyle},
-    pixelcolor::Rgb888,
-    prelude::*,
-    text::{Baseline, Text},
-};
 use kernel::{
-    framebuffer::DISPLAY,
-    keyboard::print_keypresses,
-    println,
+    display::{self, clock},
+    panic_screen, println,
     qemu::exit_qemu,
-    rtc::RTC,
-    task::{run, spawn},
-    tracer::SHOULD_USE_SCREEN,
-    util::r#async::sleep,
+    rtc::{self, RTC},
+    shell,
+    task::{block_on, run, spawn, spawn_prioritized, Priority},
+    tracer::{SHOULD_TIME_SPANS, SHOULD_USE_SCREEN},
+    util::backtrace,
     vga_println, BOOTLOADER_CONFIG,
 };
 use tracing::{error, info, span, Level};
 
+/// Set when the panic handler starts so a panic while reporting one doesn't recurse.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        // Anything more could panic again, so just stop
+        exit_qemu(kernel::qemu::QemuExitCode::Failed);
+        loop {}
+    }
+    kernel::allocator::stop_page_lock_check();
     if tracing::event_enabled!(Level::ERROR) {
         error!("{}", info);
     } else {
         println!("{}", info);
     }
-    if let Ok(disp) = DISPLAY.try_get() {
-        // This is safe because we are literally shutting down
-        // No one else should be writing to it.
-        unsafe { disp.force_unlock() };
-        let mut disp = disp.spin_lock();
-        let _ = disp.clear(Rgb888::BLACK);
-        let info = info.to_string();
-        let text = Text::with_baseline(
-            &info,
-            Point::zero(),
-            MonoTextStyle::new(&FONT_9X15, Rgb888::RED),
-            Baseline::Top,
-        );
-        let _ = text.draw(disp.as_mut());
-    }
+    println!("Backtrace:\n{}", backtrace::capture());
+    panic_screen::show(info);
     exit_qemu(kernel::qemu::QemuExitCode::Failed);
     loop {}
 }
@@ -61,20 +54,29 @@ entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     kernel::init(boot_info);
 
-    SHOULD_USE_SCREEN.store(false, core::sync::atomic::Ordering::Relaxed);
+    SHOULD_USE_SCREEN.store(false, Ordering::Relaxed);
+    // Only interested in boot timings, the clock redraws would flood the log
+    SHOULD_TIME_SPANS.store(false, Ordering::Relaxed);
 
     let main_span = span!(Level::TRACE, "kernel_main");
     let _span = main_span.enter();
 
-    let utc_date = RTC.spin_lock().read_date_time();
-    info!(%utc_date);
+    // The executor isn't running yet, so wait for the RTC here instead of spinning on its lock
+    let utc_date = block_on(async { RTC.lock().await.read_date_time() });
+    let local_date = rtc::to_local(utc_date);
+    info!(%utc_date, %local_date);
 
-    spawn(print_keypresses());
+    // The shell reads the keyboard, and echoes what's typed
+    spawn(shell::run());
 
-    spawn(async {
-        sleep(Duration::from_secs(3)).await;
-        kernel::display::clock::draw_clock().await;
-    });
+    // The clock animates, so keep chatty tasks from delaying its frames
+    spawn_prioritized(
+        async {
+            display::splash().await;
+            clock::draw_clock(true, true, clock::DEFAULT_FONT).await;
+        },
+        Priority::High,
+    );
 
     #[cfg(test)]
     test_main();
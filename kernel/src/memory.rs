@@ -1,14 +1,11 @@
-use core::{
-    mem::{self},
-    ops::Range,
-};
+use core::ops::Range;
 
-use alloc::vec::Vec;
 use bootloader_api::info::{MemoryRegion, MemoryRegionKind, MemoryRegions};
-use itertools::Itertools;
 use x86_64::{
-    structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB},
-    PhysAddr,
+    structures::paging::{
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
 };
 
 use crate::{
@@ -21,6 +18,20 @@ use crate::{
 
 pub mod mapping;
 
+/// Panics with the first page that is both writable and executable.
+///
+/// Walks every mapping, so it's meant for debugging and tests rather than hot paths.
+pub fn assert_wx() {
+    let everything = VirtAddr::zero()..VirtAddr::new(u64::MAX);
+    if let Some(mapping) = mapping::find_wx(everything) {
+        panic!("W^X violation at {:p}: {}", mapping.virt, mapping);
+    }
+}
+
+/// End of the memory 32 bit DMA can reach.
+const DMA32_LIMIT: u64 = 1 << 32;
+
+/// Like [`mapping::MAPPER`], nothing may allocate on the heap while this is held.
 pub static PAGE_ALLOCATOR: OnceLock<Mutex<SmartFrameAllocator>> = OnceLock::new();
 
 pub fn init(memory_regions: &'static MemoryRegions) -> Result<(), TryInitError> {
@@ -30,6 +41,93 @@ pub fn init(memory_regions: &'static MemoryRegions) -> Result<(), TryInitError>
     Ok(())
 }
 
+/// Bytes of memory the bootloader left usable, an upper bound on what [`init`] can hand out.
+pub fn usable_bytes(memory_regions: &MemoryRegions) -> u64 {
+    memory_regions
+        .iter()
+        .filter(|r| r.kind == MemoryRegionKind::Usable)
+        .map(|r| {
+            let start = PhysAddr::new(r.start).align_up(Size4KiB::SIZE).as_u64();
+            r.end.saturating_sub(start) & !(Size4KiB::SIZE - 1)
+        })
+        .sum()
+}
+
+/// Physically contiguous, uncached memory for device DMA, see [`alloc_dma`].
+///
+/// The frames are unmapped and freed on drop, so the device must be done with them by then.
+#[derive(Debug)]
+pub struct DmaRegion {
+    virt: VirtAddr,
+    frames: PhysFrameRange<Size4KiB>,
+}
+
+impl DmaRegion {
+    pub fn virt(&self) -> VirtAddr {
+        self.virt
+    }
+
+    /// The address to program into the device.
+    pub fn phys(&self) -> PhysAddr {
+        self.frames.start.start_address()
+    }
+
+    /// Size in bytes, rounded up to whole frames.
+    pub fn len(&self) -> usize {
+        (self.frames.end.start_address() - self.frames.start.start_address()) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.virt.as_mut_ptr()
+    }
+}
+
+impl Drop for DmaRegion {
+    fn drop(&mut self) {
+        mapping::unmap_mmio(self.virt, self.len()).expect("DMA region should still be mapped");
+        unsafe {
+            PAGE_ALLOCATOR
+                .get()
+                .spin_lock()
+                .deallocate_contiguous(self.frames)
+        };
+    }
+}
+
+/// Allocates `size` bytes of zeroed, physically contiguous memory mapped uncached.
+///
+/// With `below_4g` the whole region is below 4GiB for devices that only take 32 bit addresses.
+pub fn alloc_dma(size: usize, below_4g: bool) -> Option<DmaRegion> {
+    let count = size.div_ceil(Size4KiB::SIZE as usize);
+    let frames = {
+        let mut allocator = PAGE_ALLOCATOR.get().spin_lock();
+        if below_4g {
+            allocator.allocate_contiguous_below(count, PhysAddr::new(DMA32_LIMIT))?
+        } else {
+            allocator.allocate_contiguous(count)?
+        }
+    };
+    let len = count * Size4KiB::SIZE as usize;
+    let virt = match mapping::map_mmio(frames.start.start_address(), len) {
+        Ok(virt) => virt,
+        Err(_) => {
+            unsafe {
+                PAGE_ALLOCATOR
+                    .get()
+                    .spin_lock()
+                    .deallocate_contiguous(frames)
+            };
+            return None;
+        }
+    };
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, len) };
+    Some(DmaRegion { virt, frames })
+}
+
 pub struct BootInfoFrameAllocator {
     memory_map_iter: core::slice::Iter<'static, MemoryRegion>,
     current_region: Option<Range<u64>>,
@@ -90,9 +188,18 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     }
 }
 
+/// Most disjoint free ranges [`SmartFrameAllocator`] tracks, frames freed past it are lost.
+///
+/// Fixed so freeing never allocates, see [`mapping::MAPPER`] for why.
+pub const MAX_FREE_RANGES: usize = 512;
+
 #[derive(Debug)]
 pub struct SmartFrameAllocator {
-    memory_ranges: Vec<Range<u64>>,
+    /// Sorted and never adjacent, only the first `range_count` are used.
+    ranges: [Range<u64>; MAX_FREE_RANGES],
+    range_count: usize,
+    total_frames: u64,
+    lost_frames: u64,
 }
 
 impl SmartFrameAllocator {
@@ -107,61 +214,272 @@ impl SmartFrameAllocator {
 
         allocator::init(&mut allocator);
 
-        // Now that the allocator is setup we can use a vec
-        let mut memory_ranges = Vec::new();
-
+        let mut frames = Self::empty(usable_bytes(memory_map) / Size4KiB::SIZE);
+        // Everything else is firmware, devices or the bootloader's own allocations, like the
+        // kernel image and the page tables
         for region in allocator
             .memory_map_iter
-            .filter(|r| r.kind != MemoryRegionKind::Usable)
+            .filter(|r| r.kind == MemoryRegionKind::Usable)
         {
-            let range = region.start..region.end;
-            memory_ranges.push(range);
+            frames.free_range(region.start..region.end);
         }
         if let Some(range) = allocator.current_region {
-            memory_ranges.push(range);
+            frames.free_range(range);
+        }
+        frames
+    }
+
+    fn empty(total_frames: u64) -> Self {
+        const EMPTY: Range<u64> = 0..0;
+        Self {
+            ranges: [EMPTY; MAX_FREE_RANGES],
+            range_count: 0,
+            total_frames,
+            lost_frames: 0,
         }
+    }
 
-        Self { memory_ranges }
+    /// Allocates `count` physically consecutive frames.
+    ///
+    /// The run is carved out of a single free range, so this fails if no range is large enough
+    /// even if the total free memory would be.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrameRange<Size4KiB>> {
+        self.carve_contiguous(count, u64::MAX)
     }
 
-    fn coallesce(&mut self) {
-        self.memory_ranges.sort_by_key(|r| r.start);
-        let coallesced = mem::take(&mut self.memory_ranges)
-            .into_iter()
-            .coalesce(|x, y| {
-                if x.end == y.start {
-                    Ok(x.start..y.end)
-                } else if y.end == x.start {
-                    Ok(y.start..x.end)
-                } else {
-                    Err((x, y))
-                }
+    /// Like [`Self::allocate_contiguous`], but the whole run ends at or below `limit`.
+    pub fn allocate_contiguous_below(
+        &mut self,
+        count: usize,
+        limit: PhysAddr,
+    ) -> Option<PhysFrameRange<Size4KiB>> {
+        self.carve_contiguous(count, limit.as_u64())
+    }
+
+    fn carve_contiguous(&mut self, count: usize, limit: u64) -> Option<PhysFrameRange<Size4KiB>> {
+        if count == 0 {
+            return None;
+        }
+        let size = count as u64 * Size4KiB::SIZE;
+        let index = self.memory_ranges().iter().position(|r| {
+            let start = PhysAddr::new(r.start).align_up(Size4KiB::SIZE).as_u64();
+            start + size <= r.end.min(limit)
+        })?;
+
+        let range = &mut self.ranges[index];
+        let start = PhysAddr::new(range.start).align_up(Size4KiB::SIZE);
+        range.start = start.as_u64() + size;
+        if range.is_empty() {
+            self.remove_range(index);
+        }
+
+        let start = PhysFrame::containing_address(start);
+        Some(PhysFrame::range(start, start + count as u64))
+    }
+
+    /// Every usable frame the bootloader reported, free or not.
+    pub fn total_frames(&self) -> u64 {
+        self.total_frames
+    }
+
+    /// Number of 4KiB frames left to hand out.
+    pub fn free_frames(&self) -> u64 {
+        self.memory_ranges()
+            .iter()
+            .map(|r| {
+                let start = PhysAddr::new(r.start).align_up(Size4KiB::SIZE).as_u64();
+                r.end.saturating_sub(start) / Size4KiB::SIZE
             })
-            .filter(|r| r.start != r.end)
-            .collect();
+            .sum()
+    }
+
+    /// Frames freed while all [`MAX_FREE_RANGES`] were taken, which can't be handed out again.
+    pub fn lost_frames(&self) -> u64 {
+        self.lost_frames
+    }
+
+    fn memory_ranges(&self) -> &[Range<u64>] {
+        &self.ranges[..self.range_count]
+    }
 
-        self.memory_ranges = coallesced;
+    /// Returns a run of frames from [`Self::allocate_contiguous`].
+    ///
+    /// # Safety
+    /// The caller must guarantee that the frames are no longer in use.
+    pub unsafe fn deallocate_contiguous(&mut self, frames: PhysFrameRange<Size4KiB>) {
+        let start = frames.start.start_address().as_u64();
+        let end = frames.end.start_address().as_u64();
+        self.free_range(start..end);
+    }
+
+    /// Puts `freed` back in order, merging it with the ranges either side it touches.
+    fn free_range(&mut self, freed: Range<u64>) {
+        if freed.is_empty() {
+            return;
+        }
+        let next = self
+            .memory_ranges()
+            .partition_point(|r| r.start < freed.start);
+        let joins_prev = next > 0 && self.ranges[next - 1].end == freed.start;
+        let joins_next = next < self.range_count && self.ranges[next].start == freed.end;
+        match (joins_prev, joins_next) {
+            (true, true) => {
+                self.ranges[next - 1].end = self.ranges[next].end;
+                self.remove_range(next);
+            }
+            (true, false) => self.ranges[next - 1].end = freed.end,
+            (false, true) => self.ranges[next].start = freed.start,
+            (false, false) if self.range_count == MAX_FREE_RANGES => {
+                self.lost_frames += (freed.end - freed.start) / Size4KiB::SIZE;
+            }
+            (false, false) => {
+                self.ranges[next..=self.range_count].rotate_right(1);
+                self.ranges[next] = freed;
+                self.range_count += 1;
+            }
+        }
+    }
+
+    fn remove_range(&mut self, index: usize) {
+        self.ranges[index..self.range_count].rotate_left(1);
+        self.range_count -= 1;
     }
 }
 
 unsafe impl<S: PageSize> FrameAllocator<S> for SmartFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
-        for range in self.memory_ranges.iter_mut() {
-            let (start, end) = (range.start, range.end);
-            let new_start = start + S::SIZE;
-            if new_start <= end {
-                range.start = new_start;
-                return Some(PhysFrame::containing_address(PhysAddr::new(start)));
-            }
+        let index = self
+            .memory_ranges()
+            .iter()
+            .position(|r| r.start + S::SIZE <= r.end)?;
+        let range = &mut self.ranges[index];
+        let start = range.start;
+        range.start += S::SIZE;
+        if range.is_empty() {
+            self.remove_range(index);
         }
-        None
+        Some(PhysFrame::containing_address(PhysAddr::new(start)))
     }
 }
 
 impl<S: PageSize> FrameDeallocator<S> for SmartFrameAllocator {
     unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
         let address = frame.start_address().as_u64();
-        self.memory_ranges.push(address..address + S::SIZE);
-        self.coallesce();
+        self.free_range(address..address + S::SIZE);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use x86_64::structures::paging::{FrameAllocator, PageSize, Size4KiB, Translate};
+
+    use super::{
+        alloc_dma, assert_wx, mapping::MAPPER, SmartFrameAllocator, MAX_FREE_RANGES, PAGE_ALLOCATOR,
+    };
+
+    #[test_case]
+    fn allocate_contiguous() {
+        let mut allocator = PAGE_ALLOCATOR.get().spin_lock();
+        let frames = allocator.allocate_contiguous(8).unwrap();
+        let start = frames.start.start_address().as_u64();
+        let end = frames.end.start_address().as_u64();
+
+        assert_eq!(frames.count(), 8);
+        for (i, frame) in frames.enumerate() {
+            assert_eq!(
+                frame.start_address().as_u64(),
+                start + i as u64 * Size4KiB::SIZE
+            );
+        }
+        // The run must have been taken out of the free ranges
+        assert!(!allocator
+            .memory_ranges()
+            .iter()
+            .any(|r| r.start < end && start < r.end));
+
+        unsafe { allocator.deallocate_contiguous(frames) };
+        assert!(allocator
+            .memory_ranges()
+            .iter()
+            .any(|r| r.start <= start && end <= r.end));
+    }
+
+    #[test_case]
+    fn freed_ranges_merge_in_place() {
+        const PAGE: u64 = Size4KiB::SIZE;
+        let mut frames = SmartFrameAllocator::empty(0);
+        frames.free_range(4 * PAGE..5 * PAGE);
+        frames.free_range(0..PAGE);
+        frames.free_range(2 * PAGE..3 * PAGE);
+        assert_eq!(
+            frames.memory_ranges(),
+            [0..PAGE, 2 * PAGE..3 * PAGE, 4 * PAGE..5 * PAGE]
+        );
+
+        frames.free_range(PAGE..2 * PAGE);
+        frames.free_range(3 * PAGE..4 * PAGE);
+        assert_eq!(frames.memory_ranges().len(), 1);
+        assert_eq!(frames.memory_ranges()[0], 0..5 * PAGE);
+
+        // Taking the last frame of a range drops it entirely
+        let frames_left = frames.free_frames();
+        for _ in 0..frames_left {
+            FrameAllocator::<Size4KiB>::allocate_frame(&mut frames).unwrap();
+        }
+        assert!(frames.memory_ranges().is_empty());
+    }
+
+    #[test_case]
+    fn full_range_list_loses_frames() {
+        const PAGE: u64 = Size4KiB::SIZE;
+        let mut frames = SmartFrameAllocator::empty(0);
+        for i in 0..MAX_FREE_RANGES as u64 {
+            frames.free_range(2 * i * PAGE..(2 * i + 1) * PAGE);
+        }
+        let past_end = 2 * MAX_FREE_RANGES as u64 * PAGE;
+        frames.free_range(past_end..past_end + PAGE);
+        assert_eq!(frames.lost_frames(), 1);
+
+        // Filling a gap still merges
+        frames.free_range(PAGE..2 * PAGE);
+        assert_eq!(frames.memory_ranges()[0], 0..3 * PAGE);
+        assert_eq!(frames.lost_frames(), 1);
+    }
+
+    #[test_case]
+    fn dma_region_is_contiguous() {
+        let mut region = alloc_dma(64 * 1024, true).unwrap();
+        assert_eq!(region.len(), 64 * 1024);
+        assert!(region.phys().as_u64() + region.len() as u64 <= 1 << 32);
+        assert_eq!(unsafe { *region.as_mut_ptr().add(100) }, 0);
+
+        let mapper = MAPPER.spin_lock();
+        for offset in (0..region.len() as u64).step_by(Size4KiB::SIZE as usize) {
+            assert_eq!(
+                mapper.translate_addr(region.virt() + offset),
+                Some(region.phys() + offset)
+            );
+        }
+        drop(mapper);
+
+        let (start, end) = (
+            region.phys().as_u64(),
+            region.phys().as_u64() + region.len() as u64,
+        );
+        drop(region);
+        assert!(PAGE_ALLOCATOR
+            .get()
+            .spin_lock()
+            .memory_ranges()
+            .iter()
+            .any(|r| r.start <= start && end <= r.end));
+    }
+
+    #[test_case]
+    fn nothing_writable_and_executable() {
+        // Touch the heap so some of its lazy pages are mapped too
+        let heap = alloc::vec![0u8; 64 * 1024];
+        assert_wx();
+        drop(heap);
     }
 }
@@ -1,19 +1,644 @@
+use core::{fmt, ops::Range};
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use thiserror::Error;
+use tracing::{debug, warn};
 use x86_64::{
     registers::control::Cr3,
-    structures::paging::{page_table::FrameError, OffsetPageTable, PageTable},
+    structures::paging::{
+        mapper::{
+            FlagUpdateError, MapToError, MappedFrame, TranslateError, TranslateResult, UnmapError,
+        },
+        page_table::FrameError,
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+    },
     PhysAddr, VirtAddr,
 };
 
 use crate::{
-    util::{once::Lazy, r#async::mutex::Mutex},
+    memory::PAGE_ALLOCATOR,
+    util::{
+        once::{Lazy, OnceLock},
+        r#async::mutex::Mutex,
+    },
     PHYS_OFFSET,
 };
 
+/// The active page tables.
+///
+/// Nothing may allocate on the heap while holding this or [`PAGE_ALLOCATOR`]. The heap grows
+/// into lazily mapped pages, and [`handle_lazy_fault`] can't map them with either lock taken.
+/// Debug builds assert this on every allocation.
 pub static MAPPER: Lazy<Mutex<OffsetPageTable>> = Lazy::new(|| {
     let phys_mem_offset = VirtAddr::new(*PHYS_OFFSET.get());
     unsafe { Mutex::new(get_active_l4_table(phys_mem_offset)) }
 });
 
+const MAX_LAZY_REGIONS: usize = 8;
+
+/// Virtual ranges that are backed by frames on first access instead of up front.
+///
+/// This is a fixed array rather than a `Vec` since the heap itself is registered here before it
+/// can be used.
+static LAZY_REGIONS: Mutex<[Option<Range<VirtAddr>>; MAX_LAZY_REGIONS]> = {
+    const EMPTY: Option<Range<VirtAddr>> = None;
+    Mutex::new([EMPTY; MAX_LAZY_REGIONS])
+};
+
+#[derive(Error, Debug)]
+pub enum LazyRegionError {
+    #[error("No free slot to register lazy region {0:?}")]
+    TooManyRegions(Range<VirtAddr>),
+    #[error("Lazy region {0:?} overlaps an already registered region")]
+    Overlapping(Range<VirtAddr>),
+}
+
+/// Registers `region` to be mapped on demand by the page fault handler.
+///
+/// The region must not be mapped yet. Pages are mapped present and writable the first time
+/// they are touched.
+pub fn register_lazy_region(region: Range<VirtAddr>) -> Result<(), LazyRegionError> {
+    let mut regions = LAZY_REGIONS.spin_lock();
+    if regions
+        .iter()
+        .flatten()
+        .any(|r| r.start < region.end && region.start < r.end)
+    {
+        return Err(LazyRegionError::Overlapping(region));
+    }
+    match regions.iter_mut().find(|r| r.is_none()) {
+        Some(slot) => {
+            *slot = Some(region);
+            Ok(())
+        }
+        None => Err(LazyRegionError::TooManyRegions(region)),
+    }
+}
+
+/// Backs the page containing `addr` with a fresh frame if it lies in a lazy region.
+///
+/// Called from the page fault handler. Returns `false` if the fault isn't ours to handle, in
+/// which case it's a genuine bug. Since we may have interrupted a holder of the page table or
+/// frame allocator locks, they are only ever tried, never waited on. Holders must not allocate
+/// for that reason, see [`MAPPER`].
+pub(crate) fn handle_lazy_fault(addr: VirtAddr) -> bool {
+    let Some(regions) = LAZY_REGIONS.try_lock() else {
+        return false;
+    };
+    if !regions.iter().flatten().any(|r| r.contains(&addr)) {
+        return false;
+    }
+    drop(regions);
+
+    let (Some(mut mapper), Some(mut page_allocator)) = (
+        MAPPER.try_lock(),
+        PAGE_ALLOCATOR.try_get().ok().and_then(|a| a.try_lock()),
+    ) else {
+        panic!("lazy region fault at {addr:p} while the page tables or frame allocator are busy");
+    };
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let frame = page_allocator
+        .allocate_frame()
+        .expect("out of frames for lazy region");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    unsafe {
+        mapper
+            .map_to(page, frame, flags, &mut *page_allocator)
+            .expect("lazy page should not be mapped yet")
+            .flush();
+    }
+    true
+}
+
+/// Unmapped page right below the boot stack, a fault in it means the stack overflowed.
+static STACK_GUARD: OnceLock<Page> = OnceLock::new();
+
+/// Finds the guard page below the stack `stack_pointer` is on, which is at most `stack_len`
+/// bytes long, so faults in it are reported as overflows.
+///
+/// The bootloader leaves the page below the kernel stack unmapped, this only locates it.
+pub fn init_stack_guard(stack_pointer: VirtAddr, stack_len: u64) {
+    let top = Page::<Size4KiB>::containing_address(stack_pointer);
+    let pages = stack_len / Size4KiB::SIZE + 1;
+    let guard = {
+        let mapper = MAPPER.spin_lock();
+        (0..=pages)
+            .map(|i| top - i)
+            .find(|&page| matches!(mapper.translate_page(page), Err(TranslateError::PageNotMapped)))
+    };
+    // Logged only now, the logger allocates
+    match guard {
+        Some(guard) => {
+            debug!(?guard, "kernel stack guard");
+            STACK_GUARD.init_once(|| guard);
+        }
+        None => warn!("no unmapped page below the kernel stack, overflows will corrupt memory"),
+    }
+}
+
+/// Whether `addr` is in the page below the kernel stack.
+pub(crate) fn is_stack_guard(addr: VirtAddr) -> bool {
+    STACK_GUARD
+        .try_get()
+        .is_ok_and(|&guard| Page::containing_address(addr) == guard)
+}
+
+/// Page table bit, free for OS use, marking a read-only page as copy-on-write.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Number of pages sharing each copy-on-write frame.
+static COW_REFS: Mutex<BTreeMap<PhysFrame, usize>> = Mutex::new(BTreeMap::new());
+
+#[derive(Error, Debug)]
+pub enum CowError {
+    #[error("Source page {0:?} isn't mapped to a 4KiB frame")]
+    SourceNotMapped(Page),
+    #[error("Destination page {0:?} is already mapped")]
+    DestinationMapped(Page),
+    #[error("Couldn't mark source page copy-on-write: {0:?}")]
+    FlagUpdateFailed(FlagUpdateError),
+    #[error("Couldn't map destination page: {0:?}")]
+    MapFailed(MapToError<Size4KiB>),
+}
+
+/// Maps `dst` to the frame behind `src`, with both copy-on-write.
+///
+/// Both pages become read-only. The first write to either gets its own copy of the frame from
+/// the page fault handler, while the last page still sharing the frame just takes it over.
+///
+/// # Safety
+/// The caller must guarantee that nothing relies on writes through `src` being visible through
+/// `dst` or the other way around.
+pub unsafe fn share_cow(src: Page, dst: Page) -> Result<(), CowError> {
+    let frame = {
+        let mapper = MAPPER.spin_lock();
+        let TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            ..
+        } = mapper.translate(src.start_address())
+        else {
+            return Err(CowError::SourceNotMapped(src));
+        };
+        if mapper.translate_page(dst).is_ok() {
+            return Err(CowError::DestinationMapped(dst));
+        }
+        frame
+    };
+    // Counted before the pages are shared since inserting allocates, and a lazy heap fault
+    // can't be handled while MAPPER is held
+    *COW_REFS.spin_lock().entry(frame).or_insert(1) += 1;
+
+    let shared = map_cow(src, dst, frame);
+    if shared.is_err() {
+        release_cow_ref(frame);
+    }
+    shared
+}
+
+/// The page table half of [`share_cow`], checking `src` is still backed by `frame`.
+unsafe fn map_cow(src: Page, dst: Page, frame: PhysFrame) -> Result<(), CowError> {
+    let mut mapper = MAPPER.spin_lock();
+    let flags = match mapper.translate(src.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(mapped),
+            flags,
+            ..
+        } if mapped == frame => flags,
+        _ => return Err(CowError::SourceNotMapped(src)),
+    };
+    if mapper.translate_page(dst).is_ok() {
+        return Err(CowError::DestinationMapped(dst));
+    }
+
+    let flags = (flags - PageTableFlags::WRITABLE) | COW;
+    mapper
+        .update_flags(src, flags)
+        .map_err(CowError::FlagUpdateFailed)?
+        .flush();
+    mapper
+        .map_to(dst, frame, flags, &mut *PAGE_ALLOCATOR.get().spin_lock())
+        .map_err(CowError::MapFailed)?
+        .flush();
+    Ok(())
+}
+
+/// Takes back a reference counted by [`share_cow`] that didn't end up shared.
+fn release_cow_ref(frame: PhysFrame) {
+    let mut refs = COW_REFS.spin_lock();
+    match refs.get(&frame).copied() {
+        Some(sharers) if sharers > 2 => {
+            refs.insert(frame, sharers - 1);
+        }
+        _ => {
+            refs.remove(&frame);
+        }
+    }
+}
+
+/// Resolves a write to a copy-on-write page.
+///
+/// Called from the page fault handler with the same locking rules as [`handle_lazy_fault`].
+/// Returns `false` if the page isn't copy-on-write.
+pub(crate) fn handle_cow_fault(addr: VirtAddr) -> bool {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let (Some(mut mapper), Some(mut page_allocator), Some(mut refs)) = (
+        MAPPER.try_lock(),
+        PAGE_ALLOCATOR.try_get().ok().and_then(|a| a.try_lock()),
+        COW_REFS.try_lock(),
+    ) else {
+        panic!("write fault at {addr:p} while the page tables or frame allocator are busy");
+    };
+
+    let TranslateResult::Mapped {
+        frame: MappedFrame::Size4KiB(frame),
+        flags,
+        ..
+    } = mapper.translate(addr)
+    else {
+        return false;
+    };
+    if !flags.contains(COW) {
+        return false;
+    }
+    let flags = (flags - COW) | PageTableFlags::WRITABLE;
+
+    let sharers = refs.get(&frame).copied().unwrap_or(1);
+    if sharers <= 1 {
+        // Nobody else has the frame anymore, so it's ours to write
+        refs.remove(&frame);
+        unsafe { mapper.update_flags(page, flags) }
+            .expect("cow page should be mapped")
+            .flush();
+        return true;
+    }
+
+    let copy: PhysFrame = page_allocator
+        .allocate_frame()
+        .expect("out of frames for copy-on-write");
+    let phys_offset = VirtAddr::new(*PHYS_OFFSET.get());
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            (phys_offset + frame.start_address().as_u64()).as_ptr::<u8>(),
+            (phys_offset + copy.start_address().as_u64()).as_mut_ptr::<u8>(),
+            Size4KiB::SIZE as usize,
+        );
+        mapper
+            .unmap(page)
+            .expect("cow page should be mapped")
+            .1
+            .flush();
+        mapper
+            .map_to(page, copy, flags, &mut *page_allocator)
+            .expect("cow page was just unmapped")
+            .flush();
+    }
+    match sharers - 1 {
+        1 => refs.remove(&frame),
+        remaining => refs.insert(frame, remaining),
+    };
+    true
+}
+
+pub static KERNEL_MMIO_ADDR: OnceLock<VirtAddr> = OnceLock::new();
+pub const KERNEL_MMIO_LEN: usize = 64 * 1024 * 1024;
+
+/// Virtual ranges handed out to [`map_mmio`].
+pub static MMIO_REGIONS: Lazy<Mutex<VirtRegionAllocator>> = Lazy::new(|| {
+    let start = *KERNEL_MMIO_ADDR.get();
+    Mutex::new(VirtRegionAllocator::new(
+        start..start + KERNEL_MMIO_LEN as u64,
+    ))
+});
+
+/// Hands out non-overlapping, page aligned virtual ranges from a fixed window.
+#[derive(Debug)]
+pub struct VirtRegionAllocator {
+    window: Range<VirtAddr>,
+    /// Allocated regions, sorted by start address.
+    regions: Vec<Range<VirtAddr>>,
+}
+
+impl VirtRegionAllocator {
+    pub const fn new(window: Range<VirtAddr>) -> Self {
+        Self {
+            window,
+            regions: Vec::new(),
+        }
+    }
+
+    /// Reserves `len` bytes rounded up to whole pages, first fit.
+    pub fn allocate(&mut self, len: u64) -> Option<Range<VirtAddr>> {
+        if len == 0 {
+            return None;
+        }
+        let len = x86_64::align_up(len, Size4KiB::SIZE);
+
+        let mut start = self.window.start.align_up(Size4KiB::SIZE);
+        let mut index = 0;
+        for region in &self.regions {
+            if start + len <= region.start {
+                break;
+            }
+            start = region.end;
+            index += 1;
+        }
+        if start + len > self.window.end {
+            return None;
+        }
+
+        let region = start..start + len;
+        self.regions.insert(index, region.clone());
+        Some(region)
+    }
+
+    /// How many regions are handed out right now.
+    pub fn region_count(&self) -> usize {
+        self.regions.len()
+    }
+
+    /// Releases a region returned by [`Self::allocate`], returning whether it was allocated.
+    pub fn deallocate(&mut self, region: &Range<VirtAddr>) -> bool {
+        match self
+            .regions
+            .binary_search_by_key(&region.start, |r| r.start)
+        {
+            Ok(index) if self.regions[index] == *region => {
+                self.regions.remove(index);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum MapMmioError {
+    #[error("No room left in the mmio window for {0:#x} bytes")]
+    OutOfVirtualSpace(u64),
+    #[error("Couldn't map mmio page: {0:?}")]
+    MapFailed(MapToError<Size4KiB>),
+}
+
+/// Maps `len` bytes of device memory at `phys` into the mmio window.
+///
+/// The returned address keeps the offset of `phys` into its page. Pages are mapped uncached and
+/// not executable.
+pub fn map_mmio(phys: PhysAddr, len: usize) -> Result<VirtAddr, MapMmioError> {
+    let phys_start = phys.align_down(Size4KiB::SIZE);
+    let offset = phys - phys_start;
+    let size = offset + len as u64;
+
+    let region = MMIO_REGIONS
+        .spin_lock()
+        .allocate(size)
+        .ok_or(MapMmioError::OutOfVirtualSpace(size))?;
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE;
+    let start_page = Page::<Size4KiB>::containing_address(region.start);
+    let start_frame = PhysFrame::<Size4KiB>::containing_address(phys_start);
+    let pages = (region.end - region.start) / Size4KiB::SIZE;
+
+    let mut mapper = MAPPER.spin_lock();
+    for i in 0..pages {
+        let res = unsafe {
+            mapper.map_to(
+                start_page + i,
+                start_frame + i,
+                flags,
+                &mut *PAGE_ALLOCATOR.get().spin_lock(),
+            )
+        };
+        match res {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                for mapped in 0..i {
+                    mapper.unmap(start_page + mapped).unwrap().1.flush();
+                }
+                MMIO_REGIONS.spin_lock().deallocate(&region);
+                return Err(MapMmioError::MapFailed(err));
+            }
+        }
+    }
+
+    Ok(region.start + offset)
+}
+
+#[derive(Error, Debug)]
+pub enum UnmapMmioError {
+    #[error("No mmio region mapped at {0:p}, was it already unmapped?")]
+    NotMapped(VirtAddr),
+    #[error("Couldn't unmap mmio page: {0:?}")]
+    UnmapFailed(UnmapError),
+}
+
+/// Unmaps a region returned by [`map_mmio`] and frees its virtual range for reuse.
+///
+/// `virt` and `len` must be the values the region was mapped with.
+pub fn unmap_mmio(virt: VirtAddr, len: usize) -> Result<(), UnmapMmioError> {
+    let start = virt.align_down(Size4KiB::SIZE);
+    let end = (virt + len as u64).align_up(Size4KiB::SIZE);
+    let region = start..end;
+
+    if !MMIO_REGIONS.spin_lock().deallocate(&region) {
+        return Err(UnmapMmioError::NotMapped(virt));
+    }
+
+    let start_page = Page::<Size4KiB>::containing_address(start);
+    let end_page = Page::<Size4KiB>::containing_address(end);
+    let mut mapper = MAPPER.spin_lock();
+    for page in Page::range(start_page, end_page) {
+        mapper
+            .unmap(page)
+            .map_err(UnmapMmioError::UnmapFailed)?
+            .1
+            .flush();
+    }
+    Ok(())
+}
+
+/// A run of virtually and physically contiguous pages with the same flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub virt: VirtAddr,
+    pub phys: PhysAddr,
+    pub len: u64,
+    pub flags: PageTableFlags,
+}
+
+impl Mapping {
+    /// Grows this run by `next` if it continues it exactly.
+    fn extend(&mut self, next: &Mapping) -> bool {
+        let continues = self.virt + self.len == next.virt
+            && self.phys + self.len == next.phys
+            && self.flags == next.flags;
+        if continues {
+            self.len += next.len;
+        }
+        continues
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}..{:#x} -> {:#x} ({} KiB)",
+            self.virt,
+            self.virt + self.len,
+            self.phys,
+            self.len / 1024
+        )?;
+        const NAMES: [(PageTableFlags, &str); 9] = [
+            (PageTableFlags::PRESENT, "P"),
+            (PageTableFlags::WRITABLE, "W"),
+            (PageTableFlags::USER_ACCESSIBLE, "U"),
+            (PageTableFlags::WRITE_THROUGH, "WT"),
+            (PageTableFlags::NO_CACHE, "NC"),
+            (PageTableFlags::HUGE_PAGE, "H"),
+            (PageTableFlags::GLOBAL, "G"),
+            (COW, "COW"),
+            (PageTableFlags::NO_EXECUTE, "NX"),
+        ];
+        for (flag, name) in NAMES {
+            if self.flags.contains(flag) {
+                write!(f, " {name}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Calls `f` with every mapping overlapping `range` in the active page tables, in address
+/// order, with contiguous pages of identical flags coalesced.
+///
+/// Flags are the effective ones: writable only if every level allows writes, and no-execute if
+/// any level forbids execution. Accessed and dirty bits are ignored so they don't split runs.
+pub fn for_each_mapping(range: Range<VirtAddr>, mut f: impl FnMut(Mapping)) {
+    let mapper = MAPPER.spin_lock();
+    let mut run: Option<Mapping> = None;
+    walk_table(
+        mapper.level_4_table(),
+        4,
+        0,
+        PageTableFlags::WRITABLE,
+        &range,
+        mapper.phys_offset(),
+        &mut |mapping| {
+            if run.as_mut().is_some_and(|current| current.extend(&mapping)) {
+                return;
+            }
+            if let Some(done) = run.replace(mapping) {
+                f(done);
+            }
+        },
+    );
+    if let Some(done) = run {
+        f(done);
+    }
+}
+
+/// The first mapping in `range` that is both writable and executable.
+pub fn find_wx(range: Range<VirtAddr>) -> Option<Mapping> {
+    let mut found = None;
+    for_each_mapping(range, |mapping| {
+        let writable = mapping.flags.contains(PageTableFlags::WRITABLE);
+        let executable = !mapping.flags.contains(PageTableFlags::NO_EXECUTE);
+        if found.is_none() && writable && executable {
+            found = Some(mapping);
+        }
+    });
+    found
+}
+
+/// Mappings [`dump_mappings`] collects per walk, logging can't happen with MAPPER held.
+const DUMP_BATCH: usize = 32;
+
+/// Logs the mappings of `range` at `DEBUG`, see [`for_each_mapping`].
+///
+/// The logger allocates, so the mappings are gathered a batch at a time and logged once the
+/// walk has let go of MAPPER.
+pub fn dump_mappings(range: Range<VirtAddr>) {
+    debug!("page mappings in {:?}..{:?}", range.start, range.end);
+    let mut start = range.start;
+    loop {
+        let mut batch = [None; DUMP_BATCH];
+        let mut len = 0;
+        for_each_mapping(start..range.end, |mapping| {
+            if let Some(slot) = batch.get_mut(len) {
+                *slot = Some(mapping);
+                len += 1;
+            }
+        });
+        for mapping in batch.iter().flatten() {
+            debug!("{}", mapping);
+        }
+        let Some(last) = batch[len.saturating_sub(1)].filter(|_| len == DUMP_BATCH) else {
+            return;
+        };
+        // Truncating steps over the non-canonical hole, wrapping past the top ends the dump
+        let end = VirtAddr::new_truncate(last.virt.as_u64().wrapping_add(last.len));
+        if end <= last.virt {
+            return;
+        }
+        start = end;
+    }
+}
+
+fn walk_table(
+    table: &PageTable,
+    level: u8,
+    base: u64,
+    parent: PageTableFlags,
+    range: &Range<VirtAddr>,
+    phys_offset: VirtAddr,
+    f: &mut impl FnMut(Mapping),
+) {
+    let span = Size4KiB::SIZE << (9 * (level - 1));
+    for (index, entry) in table.iter().enumerate() {
+        // Level 4 entries from 256 up are the sign extended upper half
+        let start = VirtAddr::new_truncate(base + index as u64 * span);
+        let end = start.as_u64().wrapping_add(span - 1);
+        if end < range.start.as_u64() || start >= range.end {
+            continue;
+        }
+        let mut flags = entry.flags() - (PageTableFlags::ACCESSED | PageTableFlags::DIRTY);
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        if !parent.contains(PageTableFlags::WRITABLE) {
+            flags.remove(PageTableFlags::WRITABLE);
+        }
+        if parent.contains(PageTableFlags::NO_EXECUTE) {
+            flags.insert(PageTableFlags::NO_EXECUTE);
+        }
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            f(Mapping {
+                virt: start,
+                phys: entry.addr(),
+                len: span,
+                flags,
+            });
+        } else {
+            let next = phys_offset + entry.addr().as_u64();
+            let next = unsafe { &*next.as_ptr::<PageTable>() };
+            walk_table(
+                next,
+                level - 1,
+                start.as_u64(),
+                flags,
+                range,
+                phys_offset,
+                f,
+            );
+        }
+    }
+}
+
 /// Initialize a new OffsetPageTable.
 ///
 /// # Safety
@@ -90,3 +715,150 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
     // calculate the physical address by adding the page offset
     Some(frame.start_address() + u64::from(addr.page_offset()))
 }
+
+#[cfg(test)]
+mod test {
+    use x86_64::{
+        structures::paging::{
+            mapper::{MappedFrame, TranslateResult},
+            FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame,
+            Size4KiB, Translate,
+        },
+        PhysAddr, VirtAddr,
+    };
+
+    use super::{
+        for_each_mapping, map_mmio, share_cow, unmap_mmio, UnmapMmioError, VirtRegionAllocator,
+        COW, MAPPER, MMIO_REGIONS,
+    };
+    use crate::memory::PAGE_ALLOCATOR;
+
+    #[test_case]
+    fn mmio_regions_dont_overlap() {
+        let first = map_mmio(PhysAddr::new(0xfee0_0000), 0x1000).unwrap();
+        // Unaligned and spanning a page boundary
+        let second = map_mmio(PhysAddr::new(0xfed0_0ff0), 0x20).unwrap();
+
+        assert_eq!(u64::from(first.page_offset()), 0);
+        assert_eq!(u64::from(second.page_offset()), 0xff0);
+        let first_range = first..first + 0x1000u64;
+        let second_range = second.align_down(0x1000u64)..second.align_down(0x1000u64) + 0x2000u64;
+        assert!(first_range.end <= second_range.start || second_range.end <= first_range.start);
+    }
+
+    #[test_case]
+    fn unmap_mmio_frees_range() {
+        let len = 2 * Size4KiB::SIZE as usize;
+        let virt = map_mmio(PhysAddr::new(0xfed0_0000), len).unwrap();
+        assert!(MAPPER.spin_lock().translate_addr(virt).is_some());
+
+        unmap_mmio(virt, len).unwrap();
+        assert!(MAPPER.spin_lock().translate_addr(virt).is_none());
+        assert!(!MMIO_REGIONS
+            .spin_lock()
+            .regions
+            .iter()
+            .any(|r| r.contains(&virt)));
+        assert!(matches!(
+            unmap_mmio(virt, len),
+            Err(UnmapMmioError::NotMapped(_))
+        ));
+
+        // The range can be handed out again
+        let again = map_mmio(PhysAddr::new(0xfed0_0000), len).unwrap();
+        unmap_mmio(again, len).unwrap();
+    }
+
+    #[test_case]
+    fn cow_write_copies() {
+        let region = MMIO_REGIONS
+            .spin_lock()
+            .allocate(2 * Size4KiB::SIZE)
+            .unwrap();
+        let first = Page::<Size4KiB>::containing_address(region.start);
+        let second = first + 1;
+        let frame: PhysFrame = PAGE_ALLOCATOR.get().spin_lock().allocate_frame().unwrap();
+        unsafe {
+            MAPPER
+                .spin_lock()
+                .map_to(
+                    first,
+                    frame,
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+                    &mut *PAGE_ALLOCATOR.get().spin_lock(),
+                )
+                .unwrap()
+                .flush();
+        }
+        let first_ptr = first.start_address().as_mut_ptr::<u64>();
+        let second_ptr = second.start_address().as_mut_ptr::<u64>();
+        unsafe { first_ptr.write_volatile(1) };
+
+        unsafe { share_cow(first, second).unwrap() };
+        let frame_of = |page: Page| match MAPPER.spin_lock().translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } => (frame, flags),
+            _ => panic!("page should be mapped"),
+        };
+        assert_eq!(frame_of(first).0, frame_of(second).0);
+        assert!(frame_of(second).1.contains(COW));
+
+        // Writing through one mapping leaves the other untouched
+        unsafe { first_ptr.write_volatile(2) };
+        assert_eq!(unsafe { second_ptr.read_volatile() }, 1);
+        assert_ne!(frame_of(first).0, frame_of(second).0);
+
+        // The last sharer takes the original frame over without copying
+        unsafe { second_ptr.write_volatile(3) };
+        assert_eq!(frame_of(second).0, frame);
+        assert!(frame_of(second).1.contains(PageTableFlags::WRITABLE));
+        assert_eq!(unsafe { first_ptr.read_volatile() }, 2);
+
+        for page in [first, second] {
+            let (frame, flush) = MAPPER.spin_lock().unmap(page).unwrap();
+            flush.flush();
+            unsafe { PAGE_ALLOCATOR.get().spin_lock().deallocate_frame(frame) };
+        }
+        MMIO_REGIONS.spin_lock().deallocate(&region);
+    }
+
+    #[test_case]
+    fn virt_region_reuse() {
+        let window = VirtAddr::new(0x1000_0000)..VirtAddr::new(0x1000_4000);
+        let mut regions = VirtRegionAllocator::new(window);
+
+        let a = regions.allocate(0x1000).unwrap();
+        let b = regions.allocate(0x2001).unwrap();
+        assert_eq!(a.end, b.start);
+        assert_eq!(b.end - b.start, 0x3000);
+        assert!(regions.allocate(0x1000).is_none());
+
+        assert!(regions.deallocate(&a));
+        assert!(!regions.deallocate(&a));
+        assert_eq!(regions.allocate(0x1000), Some(a));
+    }
+
+    #[test_case]
+    fn mmio_mapping_coalesces() {
+        let phys = PhysAddr::new(0xfeb0_0000);
+        let virt = map_mmio(phys, 3 * 0x1000).unwrap();
+        // The callback runs with MAPPER held, so nothing in it may allocate
+        let (mut count, mut first) = (0, None);
+        for_each_mapping(virt..virt + 3 * 0x1000u64, |mapping| {
+            count += 1;
+            first.get_or_insert(mapping);
+        });
+        unmap_mmio(virt, 3 * 0x1000).unwrap();
+
+        assert_eq!(count, 1);
+        let mapping = first.unwrap();
+        assert_eq!((mapping.virt, mapping.phys), (virt, phys));
+        assert_eq!(mapping.len, 3 * 0x1000);
+        assert!(mapping
+            .flags
+            .contains(PageTableFlags::NO_CACHE | PageTableFlags::NO_EXECUTE));
+    }
+}
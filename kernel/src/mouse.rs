@@ -0,0 +1,280 @@
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crossbeam_queue::ArrayQueue;
+use futures::{task::AtomicWaker, Stream};
+use thiserror::Error;
+use tracing::{instrument, warn};
+use x86_64::instructions::port::Port;
+
+use crate::util::{once::OnceLock, r#async::mutex::Mutex};
+
+const PS2_DATA: u16 = 0x60;
+const PS2_COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const CONTROLLER_ENABLE_AUX: u8 = 0xa8;
+const CONTROLLER_READ_CONFIG: u8 = 0x20;
+const CONTROLLER_WRITE_CONFIG: u8 = 0x60;
+const CONTROLLER_WRITE_AUX: u8 = 0xd4;
+
+const CONFIG_AUX_INTERRUPT: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+
+const MOUSE_SET_DEFAULTS: u8 = 0xf6;
+const MOUSE_SET_SAMPLE_RATE: u8 = 0xf3;
+const MOUSE_ENABLE_REPORTING: u8 = 0xf4;
+const MOUSE_ACK: u8 = 0xfa;
+
+const SAMPLE_RATE: u8 = 100;
+/// Status polls before giving up on the controller.
+const MAX_POLLS: usize = 100_000;
+
+#[derive(Error, Debug)]
+pub enum MouseInitError {
+    #[error("PS/2 controller didn't respond")]
+    Timeout,
+    #[error("Mouse answered {1:#x} to command {0:#x} instead of acknowledging")]
+    NoAck(u8, u8),
+}
+
+/// Enables the PS/2 auxiliary port and has the mouse start reporting movement on IRQ12.
+#[instrument(name = "mouse_init", err)]
+pub fn init() -> Result<(), MouseInitError> {
+    unsafe {
+        controller_command(CONTROLLER_ENABLE_AUX)?;
+
+        controller_command(CONTROLLER_READ_CONFIG)?;
+        let config = read_data()?;
+        let config = (config | CONFIG_AUX_INTERRUPT) & !CONFIG_AUX_CLOCK_DISABLED;
+        controller_command(CONTROLLER_WRITE_CONFIG)?;
+        write_data(config)?;
+
+        mouse_command(MOUSE_SET_DEFAULTS)?;
+        mouse_command(MOUSE_SET_SAMPLE_RATE)?;
+        mouse_command(SAMPLE_RATE)?;
+        mouse_command(MOUSE_ENABLE_REPORTING)?;
+    }
+    Ok(())
+}
+
+unsafe fn wait_for(mask: u8, set: bool) -> Result<(), MouseInitError> {
+    let mut status = Port::<u8>::new(PS2_COMMAND);
+    for _ in 0..MAX_POLLS {
+        if (status.read() & mask != 0) == set {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(MouseInitError::Timeout)
+}
+
+unsafe fn controller_command(command: u8) -> Result<(), MouseInitError> {
+    wait_for(STATUS_INPUT_FULL, false)?;
+    Port::<u8>::new(PS2_COMMAND).write(command);
+    Ok(())
+}
+
+unsafe fn write_data(data: u8) -> Result<(), MouseInitError> {
+    wait_for(STATUS_INPUT_FULL, false)?;
+    Port::<u8>::new(PS2_DATA).write(data);
+    Ok(())
+}
+
+unsafe fn read_data() -> Result<u8, MouseInitError> {
+    wait_for(STATUS_OUTPUT_FULL, true)?;
+    Ok(Port::<u8>::new(PS2_DATA).read())
+}
+
+unsafe fn mouse_command(command: u8) -> Result<(), MouseInitError> {
+    controller_command(CONTROLLER_WRITE_AUX)?;
+    write_data(command)?;
+    match read_data()? {
+        MOUSE_ACK => Ok(()),
+        other => Err(MouseInitError::NoAck(command, other)),
+    }
+}
+
+/// Buttons held during a [`MouseEvent`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// Relative movement since the previous event, `dy` is positive upwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub buttons: MouseButtons,
+}
+
+const PACKET_LEFT: u8 = 1 << 0;
+const PACKET_RIGHT: u8 = 1 << 1;
+const PACKET_MIDDLE: u8 = 1 << 2;
+/// Always set in the first byte of a packet, used to resync after a dropped byte.
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+
+/// Assembles the standard 3-byte PS/2 mouse packets.
+#[derive(Debug, Default)]
+pub struct PacketDecoder {
+    bytes: [u8; 3],
+    len: usize,
+}
+
+impl PacketDecoder {
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; 3],
+            len: 0,
+        }
+    }
+
+    /// Adds a byte, returning the event once a whole packet has arrived.
+    pub fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        if self.len == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+            // Out of sync, this can't be the start of a packet
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.bytes.len() {
+            return None;
+        }
+        self.len = 0;
+
+        let [flags, x, y] = self.bytes;
+        if flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) != 0 {
+            return None;
+        }
+        let delta = |value: u8, negative: bool| {
+            if negative {
+                value as i16 - 0x100
+            } else {
+                value as i16
+            }
+        };
+        Some(MouseEvent {
+            dx: delta(x, flags & PACKET_X_SIGN != 0),
+            dy: delta(y, flags & PACKET_Y_SIGN != 0),
+            buttons: MouseButtons {
+                left: flags & PACKET_LEFT != 0,
+                right: flags & PACKET_RIGHT != 0,
+                middle: flags & PACKET_MIDDLE != 0,
+            },
+        })
+    }
+}
+
+static DECODER: Mutex<PacketDecoder> = Mutex::new(PacketDecoder::new());
+static EVENT_QUEUE: OnceLock<ArrayQueue<MouseEvent>> = OnceLock::new();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Called from the mouse interrupt handler with the byte read from the data port.
+pub(crate) fn add_byte(byte: u8) {
+    // Only the interrupt handler decodes, so this can't be contended
+    let Some(event) = DECODER.try_lock().and_then(|mut d| d.add_byte(byte)) else {
+        return;
+    };
+    if let Ok(queue) = EVENT_QUEUE.try_get() {
+        if queue.push(event).is_err() {
+            warn!("mouse queue full; dropping mouse input");
+        } else {
+            WAKER.wake();
+        }
+    }
+}
+
+/// Returns a stream of decoded mouse events.
+///
+/// # Panics
+/// Panics if called more than once.
+pub fn mouse_events() -> MouseEventStream {
+    MouseEventStream::new()
+}
+
+pub struct MouseEventStream {
+    _private: (),
+}
+
+impl MouseEventStream {
+    pub fn new() -> Self {
+        EVENT_QUEUE
+            .try_init_once(|| ArrayQueue::new(100))
+            .expect("MouseEventStream::new should only be called once");
+        MouseEventStream { _private: () }
+    }
+}
+
+impl Default for MouseEventStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for MouseEventStream {
+    type Item = MouseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let queue = EVENT_QUEUE.try_get().expect("not initialized");
+
+        if let Some(event) = queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Some(event) => {
+                WAKER.take();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MouseButtons, MouseEvent, PacketDecoder};
+
+    #[test_case]
+    fn decode_packet() {
+        let mut decoder = PacketDecoder::new();
+        assert_eq!(decoder.add_byte(0b0011_1001), None);
+        assert_eq!(decoder.add_byte(0xfe), None);
+        assert_eq!(
+            decoder.add_byte(0x05),
+            Some(MouseEvent {
+                dx: -2,
+                dy: -251,
+                buttons: MouseButtons {
+                    left: true,
+                    right: false,
+                    middle: false,
+                },
+            })
+        );
+    }
+
+    #[test_case]
+    fn decode_resyncs() {
+        let mut decoder = PacketDecoder::new();
+        // A stray byte without the always-one bit is dropped
+        assert_eq!(decoder.add_byte(0x00), None);
+        assert_eq!(decoder.add_byte(0b0000_1010), None);
+        assert_eq!(decoder.add_byte(3), None);
+        let event = decoder.add_byte(4).unwrap();
+        assert_eq!((event.dx, event.dy), (3, 4));
+        assert!(event.buttons.right);
+    }
+}
@@ -0,0 +1,132 @@
+use core::{
+    fmt::{self, Display, Write},
+    panic::PanicInfo,
+};
+
+use embedded_graphics::{
+    mono_font::{ascii::FONT_9X15, MonoTextStyle},
+    pixelcolor::Rgb888,
+    prelude::*,
+    text::{Baseline, Text},
+};
+
+use crate::{framebuffer::DISPLAY, interrupts, tracer, util::r#async::mutex::Mutex};
+
+/// Text of the panic screen, kept out of the heap since that may be what broke.
+static PANIC_TEXT: Mutex<PanicText> = Mutex::new(PanicText::new());
+
+const PANIC_TEXT_LEN: usize = 8 * 1024;
+
+/// Fixed size buffer that truncates instead of failing when full.
+pub struct PanicText {
+    buf: [u8; PANIC_TEXT_LEN],
+    len: usize,
+}
+
+impl PanicText {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; PANIC_TEXT_LEN],
+            len: 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only whole chars are ever copied in
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+}
+
+impl Default for PanicText {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for PanicText {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(self.buf.len() - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.buf[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+/// Writes the panic report: the message, the exception that caused it if any, the entered
+/// spans and the recent log.
+pub fn write_report(out: &mut impl Write, message: &dyn Display) -> fmt::Result {
+    write!(out, "{message}")?;
+    if let Some(context) = interrupts::exception_context() {
+        write!(
+            out,
+            "\n\n{} at rip {:#x}, rsp {:#x}",
+            context.exception,
+            context.instruction_pointer.as_u64(),
+            context.stack_pointer.as_u64()
+        )?;
+        if let Some(addr) = context.fault_address {
+            write!(out, "\naccessing {:#x} (Cr2)", addr.as_u64())?;
+        }
+    }
+    out.write_str("\n\nSpan stack:\n")?;
+    tracer::dump_span_stack(out)?;
+    out.write_str("\n\nRecent log:\n")?;
+    tracer::dump_recent(out)
+}
+
+/// Draws the panic report in red over the whole screen.
+///
+/// Does nothing if the display isn't set up yet or a report is already being written.
+pub fn show(info: &PanicInfo) {
+    let Ok(disp) = DISPLAY.try_get() else {
+        return;
+    };
+    let Some(mut text) = PANIC_TEXT.try_lock() else {
+        return;
+    };
+    let _ = write_report(&mut *text, info);
+
+    // This is safe because we are literally shutting down
+    // No one else should be writing to it.
+    unsafe { disp.force_unlock() };
+    let mut disp = disp.spin_lock();
+    let _ = disp.clear(Rgb888::BLACK);
+    let text = Text::with_baseline(
+        text.as_str(),
+        Point::zero(),
+        MonoTextStyle::new(&FONT_9X15, Rgb888::RED),
+        Baseline::Top,
+    );
+    let _ = text.draw(disp.as_mut());
+    disp.draw_frame();
+}
+
+/// The last report drawn by [`show`], if it isn't being written right now.
+pub fn with_text<R>(f: impl FnOnce(&str) -> R) -> Option<R> {
+    PANIC_TEXT.try_lock().map(|text| f(text.as_str()))
+}
+
+#[cfg(test)]
+mod test {
+    use tracing::{info, info_span};
+
+    use super::{write_report, PanicText};
+
+    #[test_case]
+    fn report_lists_spans_and_log() {
+        let span = info_span!("panic_report");
+        let _span = span.enter();
+        info!("before the report");
+
+        let mut text = PanicText::new();
+        write_report(&mut text, &"boom").unwrap();
+        let text = text.as_str();
+        assert!(text.starts_with("boom"));
+        assert!(text.contains("Span stack:\n"));
+        assert!(text.contains("panic_report"));
+        assert!(text.contains("before the report"));
+    }
+}
@@ -0,0 +1,227 @@
+use core::ops::RangeInclusive;
+
+use alloc::vec::Vec;
+use tracing::{info, instrument, warn};
+use x86_64::{instructions::port::Port, PhysAddr, VirtAddr};
+
+use crate::{
+    memory::mapping::{map_mmio, unmap_mmio},
+    util::{once::OnceLock, r#async::mutex::Mutex},
+};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+/// ECAM config space per bus: 32 devices of 8 functions of 4KiB each.
+const ECAM_BUS_LEN: usize = 1 << 20;
+
+const VENDOR_NONE: u16 = 0xffff;
+const HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+const HEADER_TYPE_MASK: u8 = 0x7f;
+
+const OFFSET_ID: u8 = 0x00;
+const OFFSET_CLASS: u8 = 0x08;
+const OFFSET_HEADER: u8 = 0x0c;
+const OFFSET_BAR0: u8 = 0x10;
+
+/// A range of buses with memory mapped config space, from the ACPI MCFG table.
+#[derive(Debug, Clone)]
+pub struct EcamRegion {
+    pub segment_group: u16,
+    pub buses: RangeInclusive<u8>,
+    pub base: PhysAddr,
+}
+
+static ECAM_REGIONS: OnceLock<Vec<EcamRegion>> = OnceLock::new();
+/// Serializes the address/data port pair.
+static CONFIG_PORTS: Mutex<()> = Mutex::new(());
+
+/// Devices found by [`init`].
+pub static DEVICES: OnceLock<Vec<PciDevice>> = OnceLock::new();
+
+/// Records the MCFG regions so config space is read through memory instead of ports.
+pub(crate) fn init_ecam(regions: Vec<EcamRegion>) {
+    ECAM_REGIONS.init_once(|| regions);
+}
+
+/// A PCI function and the fields of its config space header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    /// Raw BARs, only the first two exist for bridges and the rest are 0.
+    pub bars: [u32; 6],
+}
+
+/// Enumerates the bus and logs what is on it.
+#[instrument(name = "pci_init")]
+pub fn init() {
+    let devices = enumerate();
+    for device in &devices {
+        info!(
+            "{:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}.{:02x}",
+            device.bus,
+            device.device,
+            device.function,
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.subclass
+        );
+    }
+    DEVICES.init_once(|| devices);
+}
+
+/// Scans every function on buses 0-255 of segment group 0.
+pub fn enumerate() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..=u8::MAX {
+        let config = BusConfig::new(bus);
+        for device in 0..DEVICES_PER_BUS {
+            if config.vendor_id(device, 0) == VENDOR_NONE {
+                continue;
+            }
+            let functions = if config.header_type(device, 0) & HEADER_MULTI_FUNCTION != 0 {
+                FUNCTIONS_PER_DEVICE
+            } else {
+                1
+            };
+            for function in 0..functions {
+                if config.vendor_id(device, function) != VENDOR_NONE {
+                    devices.push(config.device(device, function));
+                }
+            }
+        }
+    }
+    devices
+}
+
+/// Config space access for one bus, through ECAM if the MCFG covers it or ports otherwise.
+struct BusConfig {
+    bus: u8,
+    ecam: Option<VirtAddr>,
+}
+
+impl BusConfig {
+    fn new(bus: u8) -> Self {
+        let region = ECAM_REGIONS.try_get().ok().and_then(|regions| {
+            regions
+                .iter()
+                .find(|r| r.segment_group == 0 && r.buses.contains(&bus))
+        });
+        let ecam = region.and_then(|region| {
+            let offset = (bus - region.buses.start()) as u64 * ECAM_BUS_LEN as u64;
+            map_mmio(region.base + offset, ECAM_BUS_LEN)
+                .inspect_err(|err| warn!("Falling back to port io for bus {bus}: {err}"))
+                .ok()
+        });
+        Self { bus, ecam }
+    }
+
+    fn read(&self, device: u8, function: u8, offset: u8) -> u32 {
+        let offset = offset & 0xfc;
+        match self.ecam {
+            Some(base) => {
+                let addr = base + ((device as u64) << 15 | (function as u64) << 12 | offset as u64);
+                unsafe { addr.as_ptr::<u32>().read_volatile() }
+            }
+            None => read_port(self.bus, device, function, offset),
+        }
+    }
+
+    fn vendor_id(&self, device: u8, function: u8) -> u16 {
+        self.read(device, function, OFFSET_ID) as u16
+    }
+
+    fn header_type(&self, device: u8, function: u8) -> u8 {
+        (self.read(device, function, OFFSET_HEADER) >> 16) as u8
+    }
+
+    fn device(&self, device: u8, function: u8) -> PciDevice {
+        let id = self.read(device, function, OFFSET_ID);
+        let class = self.read(device, function, OFFSET_CLASS);
+        let header_type = self.header_type(device, function);
+        let bar_count = match header_type & HEADER_TYPE_MASK {
+            0 => 6,
+            1 => 2,
+            _ => 0,
+        };
+        let mut bars = [0; 6];
+        for (i, bar) in bars.iter_mut().enumerate().take(bar_count) {
+            *bar = self.read(device, function, OFFSET_BAR0 + 4 * i as u8);
+        }
+
+        PciDevice {
+            bus: self.bus,
+            device,
+            function,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            bars,
+        }
+    }
+}
+
+impl Drop for BusConfig {
+    fn drop(&mut self) {
+        if let Some(base) = self.ecam {
+            if let Err(err) = unmap_mmio(base, ECAM_BUS_LEN) {
+                warn!("Failed to unmap ecam for bus {}: {err}", self.bus);
+            }
+        }
+    }
+}
+
+fn read_port(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let address = CONFIG_ENABLE
+        | (bus as u32) << 16
+        | (device as u32) << 11
+        | (function as u32) << 8
+        | offset as u32;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _ports = CONFIG_PORTS.spin_lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(address);
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{enumerate, read_port, BusConfig, OFFSET_ID, VENDOR_NONE};
+
+    #[test_case]
+    fn finds_host_bridge() {
+        let devices = enumerate();
+        // Both QEMU machines put the host bridge at 00:00.0
+        let host = devices
+            .iter()
+            .find(|d| (d.bus, d.device, d.function) == (0, 0, 0))
+            .expect("no host bridge");
+        assert_ne!(host.vendor_id, VENDOR_NONE);
+        assert_eq!(host.class, 0x06);
+    }
+
+    #[test_case]
+    fn ecam_matches_ports() {
+        let config = BusConfig::new(0);
+        assert_eq!(config.read(0, 0, OFFSET_ID), read_port(0, 0, 0, OFFSET_ID));
+    }
+}
@@ -0,0 +1,61 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use tracing::instrument;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::{apic::LAPIC, pic::PICS};
+
+const PIT_CHANNEL_0: u16 = 0x40;
+pub(crate) const PIT_COMMAND: u16 = 0x43;
+pub(crate) const PIT_FREQ: u32 = 1_193_182;
+
+/// Channel 0, lobyte/hibyte access, mode 2 (rate generator), binary.
+const PIT_CHANNEL_0_RATE: u8 = 0b0011_0100;
+
+/// Rate channel 0 actually fires at, 0 until [`init`].
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+/// Programs channel 0 to interrupt on IRQ0 at about `freq_hz`.
+///
+/// The rate is only as exact as [`divisor`] allows, [`frequency`] has what it ended up at.
+#[instrument(name = "pit_init")]
+pub fn init(freq_hz: u32) {
+    let divisor = divisor(freq_hz);
+    let [low, high] = divisor.to_le_bytes();
+    interrupts::without_interrupts(|| unsafe {
+        Port::<u8>::new(PIT_COMMAND).write(PIT_CHANNEL_0_RATE);
+        let mut channel = Port::<u8>::new(PIT_CHANNEL_0);
+        channel.write(low);
+        channel.write(high);
+
+        // The IO APIC routes IRQ0 itself, only the legacy PIC needs unmasking
+        if LAPIC.try_get().is_err() {
+            let mut pics = PICS.spin_lock();
+            let [master, slave] = pics.read_masks();
+            pics.write_masks(master & !1, slave);
+        }
+    });
+    FREQUENCY.store(PIT_FREQ / divisor as u32, Ordering::Release);
+}
+
+/// Rate channel 0 was programmed to by [`init`], 0 if it wasn't.
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Acquire)
+}
+
+/// PIT reload value for `freq_hz`, clamped to what the 16 bit counter can hold.
+pub(crate) fn divisor(freq_hz: u32) -> u16 {
+    (PIT_FREQ / freq_hz.max(1)).clamp(1, u16::MAX as u32) as u16
+}
+
+#[cfg(test)]
+mod test {
+    use super::divisor;
+
+    #[test_case]
+    fn divisor_clamps() {
+        assert_eq!(divisor(1000), 1193);
+        assert_eq!(divisor(0), u16::MAX);
+        assert_eq!(divisor(u32::MAX), 1);
+    }
+}
@@ -1,11 +1,14 @@
-use core::time::Duration;
+use core::{
+    sync::atomic::{AtomicI32, Ordering},
+    time::Duration,
+};
 
-use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, TimeDelta};
 use thiserror::Error;
-use tracing::{instrument, warn};
+use tracing::{error, instrument, warn};
 use x86_64::instructions::{interrupts, port::Port};
 
-use crate::util::r#async::mutex::IntMutex;
+use crate::util::{once::OnceLock, r#async::mutex::IntMutex};
 
 const NMI_ENABLE: bool = true;
 
@@ -14,6 +17,35 @@ pub const TIMER_PERIOD: Duration = Duration::from_micros(112);
 pub const TIMER_FREQ: usize = 8192;
 pub static RTC: IntMutex<Rtc> = IntMutex::new(Rtc::new());
 
+/// CMOS register holding the century as reported by the ACPI FADT, `None` if there isn't one.
+pub static CENTURY_REGISTER: OnceLock<Option<u8>> = OnceLock::new();
+/// Reads [`Rtc::read_date_time`] makes before giving up on the clock.
+const MAX_READ_ATTEMPTS: usize = 8;
+/// Century assumed when there's no century register to read.
+const DEFAULT_CENTURY: u8 = 20;
+
+/// Minutes added to the RTC's UTC time wherever local time is shown.
+static UTC_OFFSET_MINUTES: AtomicI32 = AtomicI32::new(0);
+
+/// Sets the offset of local time from UTC, negative for west of Greenwich.
+pub fn set_utc_offset(offset_minutes: i32) {
+    UTC_OFFSET_MINUTES.store(offset_minutes, Ordering::Relaxed);
+}
+
+pub fn utc_offset() -> i32 {
+    UTC_OFFSET_MINUTES.load(Ordering::Relaxed)
+}
+
+/// Converts a UTC time from the RTC into local time using [`utc_offset`].
+pub fn to_local(utc: NaiveDateTime) -> NaiveDateTime {
+    apply_utc_offset(utc, utc_offset())
+}
+
+/// Shifts `utc` by `offset_minutes`, carrying into the date when it crosses midnight.
+pub fn apply_utc_offset(utc: NaiveDateTime, offset_minutes: i32) -> NaiveDateTime {
+    utc + TimeDelta::minutes(offset_minutes.into())
+}
+
 #[derive(Debug)]
 pub struct Rtc {
     command: Port<u8>,
@@ -66,15 +98,25 @@ impl Rtc {
 
         self.write_cmos_reg(STATUS_REG_B_NUM, status_reg);
     }
+    /// Reads the date and time, retrying implausible readings a few times.
+    ///
+    /// Falls back to the Unix epoch if the clock never gives a valid reading.
     #[instrument]
     pub fn read_date_time(&mut self) -> NaiveDateTime {
-        loop {
-            if let Ok(time) = self.try_read_date_time() {
-                return time;
+        for _ in 1..MAX_READ_ATTEMPTS {
+            match self.try_read_date_time() {
+                Ok(time) => return time,
+                Err(err) => warn!("failed to get time: {}", err),
             }
-            warn!("failed to get time");
             core::hint::spin_loop();
         }
+        self.try_read_date_time().unwrap_or_else(|err| {
+            error!(
+                "RTC gave no valid time in {} reads, last: {}",
+                MAX_READ_ATTEMPTS, err
+            );
+            NaiveDateTime::default()
+        })
     }
 
     pub fn try_read_date_time(&mut self) -> Result<NaiveDateTime, FromNaiveDateTimeError> {
@@ -86,19 +128,20 @@ impl Rtc {
             let mut day = rtc_ref.read_cmos_reg(0x07);
             let mut month = rtc_ref.read_cmos_reg(0x08);
             let mut year = rtc_ref.read_cmos_reg(0x09);
-            let mut century = rtc_ref.read_cmos_reg(0x32);
+            let century_register = CENTURY_REGISTER.try_get().ok().copied().flatten();
 
             // Convert BCD to binary values if necessary
             // It shouldn't be, because by now we configured RTC but it seems necessary regardless
             let register_b = rtc_ref.read_cmos_reg(0x0B);
-            if register_b & 0x04 == 0 {
+            let binary = register_b & 0x04 != 0;
+            let century = rtc_ref.read_century(century_register, binary);
+            if !binary {
                 seconds = (seconds & 0x0F) + ((seconds / 16) * 10);
                 minutes = (minutes & 0x0F) + ((minutes / 16) * 10);
                 hours = ((hours & 0x0F) + (((hours & 0x70) / 16) * 10)) | (hours & 0x80);
                 day = (day & 0x0F) + ((day / 16) * 10);
                 month = (month & 0x0F) + ((month / 16) * 10);
                 year = (year & 0x0F) + ((year / 16) * 10);
-                century = (century & 0x0F) + ((century / 16) * 10);
             }
 
             RTCDateTime {
@@ -115,6 +158,34 @@ impl Rtc {
         })
     }
 
+    /// Reads the century from `register`, or assumes [`DEFAULT_CENTURY`] without one.
+    fn read_century(&mut self, register: Option<u8>, binary: bool) -> u8 {
+        let Some(register) = register else {
+            return DEFAULT_CENTURY;
+        };
+        let century = self.read_cmos_reg(register);
+        if binary {
+            century
+        } else {
+            (century & 0x0F) + ((century / 16) * 10)
+        }
+    }
+
+    /// Reads the configuration the firmware keeps in CMOS next to the clock.
+    pub fn read_cmos_config(&mut self) -> CmosConfig {
+        let floppies = self.read_cmos_reg(0x10);
+        let read_word = |rtc: &mut Self, low: u8| {
+            u16::from_le_bytes([rtc.read_cmos_reg(low), rtc.read_cmos_reg(low + 1)])
+        };
+        CmosConfig {
+            floppy_a: FloppyType::from(floppies >> 4),
+            floppy_b: FloppyType::from(floppies & 0x0F),
+            equipment: self.read_cmos_reg(0x14),
+            base_memory_kib: read_word(self, 0x15),
+            extended_memory_kib: read_word(self, 0x17),
+        }
+    }
+
     fn select_reg(&mut self, reg: u8) {
         // This is the first operation in any handling of rtc so this should always check if
         // interrupts are disable before doing rtc stuff
@@ -173,7 +244,62 @@ fn get_nmi_mask() -> u8 {
     }
 }
 
-#[derive(Debug)]
+/// Drive type of a floppy drive as recorded in CMOS register 0x10.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloppyType {
+    None,
+    Kib360,
+    Mib1_2,
+    Kib720,
+    Mib1_44,
+    Mib2_88,
+    Unknown(u8),
+}
+
+impl From<u8> for FloppyType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::None,
+            1 => Self::Kib360,
+            2 => Self::Mib1_2,
+            3 => Self::Kib720,
+            4 => Self::Mib1_44,
+            5 => Self::Mib2_88,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+/// Firmware configuration bytes from CMOS, see [`Rtc::read_cmos_config`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CmosConfig {
+    pub floppy_a: FloppyType,
+    pub floppy_b: FloppyType,
+    /// Equipment byte, register 0x14.
+    pub equipment: u8,
+    /// Conventional memory below 1MiB.
+    pub base_memory_kib: u16,
+    /// Memory from 1MiB up to 64MiB.
+    pub extended_memory_kib: u16,
+}
+
+impl CmosConfig {
+    /// Number of floppy drives from the equipment byte.
+    pub fn floppy_drives(&self) -> u8 {
+        if self.equipment & 1 == 0 {
+            0
+        } else {
+            (self.equipment >> 6) + 1
+        }
+    }
+
+    /// Whether the equipment byte lists a math coprocessor.
+    pub fn has_fpu(&self) -> bool {
+        self.equipment & (1 << 1) != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct RTCDateTime {
     pub seconds: u8,
     pub minutes: u8,
@@ -185,7 +311,7 @@ pub struct RTCDateTime {
     pub century: u8,
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, PartialEq, Eq)]
 #[error("Error converting RTC time to NaiveDateTime")]
 pub enum FromNaiveDateTimeError {
     #[error("Invalid Date: {month}/{day}/{year}")]
@@ -201,15 +327,137 @@ impl TryFrom<RTCDateTime> for NaiveDateTime {
         let year = value.century as i32 * 100 + value.year as i32;
         let month = value.month as u32;
         let day = value.day as u32;
-        let date = NaiveDate::from_ymd_opt(year, month, day)
-            .ok_or(FromNaiveDateTimeError::InvalidDate { year, month, day })?;
+        let invalid_date = FromNaiveDateTimeError::InvalidDate { year, month, day };
+        // A two digit year past 99 means the registers weren't in the format we assumed
+        if value.year >= 100 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return Err(invalid_date);
+        }
+        let date = NaiveDate::from_ymd_opt(year, month, day).ok_or(invalid_date)?;
 
         let hour = value.hours as u32;
         let min = value.minutes as u32;
         let sec = value.seconds as u32;
-        let time = NaiveTime::from_hms_opt(hour, min, sec)
-            .ok_or(FromNaiveDateTimeError::InvalidTime { hour, min, sec })?;
+        let invalid_time = FromNaiveDateTimeError::InvalidTime { hour, min, sec };
+        // Also catches the PM bit of the 12 hour format
+        if hour >= 24 || min >= 60 || sec >= 60 {
+            return Err(invalid_time);
+        }
+        let time = NaiveTime::from_hms_opt(hour, min, sec).ok_or(invalid_time)?;
 
         Ok(NaiveDateTime::new(date, time))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use chrono::{NaiveDate, NaiveDateTime};
+
+    use super::{
+        apply_utc_offset, FloppyType, FromNaiveDateTimeError, RTCDateTime, DEFAULT_CENTURY, RTC,
+    };
+
+    fn date_time(y: i32, m: u32, d: u32, h: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(h, min, 0)
+            .unwrap()
+    }
+
+    #[test_case]
+    fn ist_offset() {
+        let utc = date_time(2024, 4, 21, 12, 45);
+        assert_eq!(apply_utc_offset(utc, 330), date_time(2024, 4, 21, 18, 15));
+    }
+
+    #[test_case]
+    fn offset_wraps_days() {
+        let utc = date_time(2024, 12, 31, 20, 0);
+        assert_eq!(apply_utc_offset(utc, 330), date_time(2025, 1, 1, 1, 30));
+        let utc = date_time(2024, 3, 1, 2, 0);
+        assert_eq!(apply_utc_offset(utc, -300), date_time(2024, 2, 29, 21, 0));
+    }
+
+    #[test_case]
+    fn cmos_config_reads() {
+        let config = RTC.spin_lock().read_cmos_config();
+        // Every PC has some conventional memory, at most 640KiB of it
+        assert!(config.base_memory_kib > 0 && config.base_memory_kib <= 640);
+        // QEMU fills the equipment byte in from the same drives it lists in register 0x10
+        let listed = [config.floppy_a, config.floppy_b]
+            .iter()
+            .filter(|&&drive| drive != FloppyType::None)
+            .count();
+        assert_eq!(config.floppy_drives() as usize, listed);
+        assert!(!matches!(config.floppy_a, FloppyType::Unknown(_)));
+    }
+
+    #[test_case]
+    fn century_from_given_register() {
+        let mut rtc = RTC.spin_lock();
+        // Any register works to check the index is honored, this one is unused by the clock
+        let register = 0x48;
+        let raw = rtc.read_cmos_reg(register);
+        assert_eq!(rtc.read_century(Some(register), true), raw);
+        assert_eq!(rtc.read_century(None, true), DEFAULT_CENTURY);
+        assert_eq!(rtc.read_century(None, false), DEFAULT_CENTURY);
+    }
+
+    #[test_case]
+    fn implausible_readings_rejected() {
+        let valid = RTCDateTime {
+            seconds: 30,
+            minutes: 15,
+            hours: 9,
+            weekday: 3,
+            day: 14,
+            month: 5,
+            year: 24,
+            century: 20,
+        };
+        assert_eq!(
+            NaiveDateTime::try_from(valid),
+            Ok(date_time(2024, 5, 14, 9, 15) + chrono::TimeDelta::seconds(30))
+        );
+
+        // PM bit of a 12 hour clock left in the hours
+        let pm = RTCDateTime {
+            hours: 0x80 | 9,
+            ..valid
+        };
+        assert_eq!(
+            NaiveDateTime::try_from(pm),
+            Err(FromNaiveDateTimeError::InvalidTime {
+                hour: 0x89,
+                min: 15,
+                sec: 30
+            })
+        );
+        let seconds = RTCDateTime {
+            seconds: 60,
+            ..valid
+        };
+        assert!(matches!(
+            NaiveDateTime::try_from(seconds),
+            Err(FromNaiveDateTimeError::InvalidTime { .. })
+        ));
+
+        // Month still in BCD
+        let month = RTCDateTime {
+            month: 0x12,
+            ..valid
+        };
+        assert_eq!(
+            NaiveDateTime::try_from(month),
+            Err(FromNaiveDateTimeError::InvalidDate {
+                year: 2024,
+                month: 0x12,
+                day: 14
+            })
+        );
+        let day = RTCDateTime { day: 0, ..valid };
+        assert!(matches!(
+            NaiveDateTime::try_from(day),
+            Err(FromNaiveDateTimeError::InvalidDate { .. })
+        ));
+    }
+}
@@ -1,24 +1,181 @@
+use core::{
+    fmt,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use crossbeam_queue::ArrayQueue;
+use futures::task::AtomicWaker;
+use tracing::warn;
 use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+use crate::util::{
+    once::{Lazy, OnceLock},
+    r#async::{
+        mutex::Mutex,
+        stream::{QueueStream, Stream, StreamExt},
+    },
+};
 
-use crate::util::{once::Lazy, r#async::mutex::Mutex};
+const SERIAL1_ADDR: u16 = 0x3f8;
+const SERIAL2_ADDR: u16 = 0x2f8;
 
-const SERIAL_ADDR: u16 = 0x3f8;
+const LINE_STATUS_OFFSET: u16 = 5;
+const LINE_STATUS_DATA_READY: u8 = 1;
 
-pub static SERIAL1: Lazy<Mutex<SerialPort>> = Lazy::new(|| {
-    let mut serial_port = unsafe { SerialPort::new(SERIAL_ADDR) };
+pub static SERIAL1: Lazy<Mutex<SerialPort>> = Lazy::new(|| init_port(SERIAL1_ADDR));
+pub static SERIAL2: Lazy<Mutex<SerialPort>> = Lazy::new(|| init_port(SERIAL2_ADDR));
+
+fn init_port(addr: u16) -> Mutex<SerialPort> {
+    let mut serial_port = unsafe { SerialPort::new(addr) };
     serial_port.init();
     Mutex::new(serial_port)
-});
+}
+
+/// Selects which serial port output is routed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+}
+
+impl ComPort {
+    pub fn port(self) -> &'static Mutex<SerialPort> {
+        match self {
+            ComPort::Com1 => &SERIAL1,
+            ComPort::Com2 => &SERIAL2,
+        }
+    }
+
+    /// Bytes handed to this port so far, by the print macros or [`Self::write_byte`].
+    pub fn bytes_written(self) -> usize {
+        BYTES_WRITTEN[self as usize].load(Ordering::Relaxed)
+    }
+
+    /// Spins until a byte arrives, for when interrupts can't be relied on like in a debugger.
+    ///
+    /// On COM1 this races the interrupt handler for the byte, so it's meant for COM2.
+    pub fn read_byte(self) -> u8 {
+        x86_64::instructions::interrupts::without_interrupts(|| self.port().spin_lock().receive())
+    }
+
+    /// Sends `byte` as is, without the backspace translation of [`fmt::Write`].
+    pub fn write_byte(self, byte: u8) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            self.port().spin_lock().send_raw(byte)
+        });
+        BYTES_WRITTEN[self as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Indexed by [`ComPort`], see [`ComPort::bytes_written`].
+static BYTES_WRITTEN: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+/// Counts what passes through to the port for [`BYTES_WRITTEN`].
+struct Counted<'a, W> {
+    inner: &'a mut W,
+    len: usize,
+}
+
+impl<W: fmt::Write> fmt::Write for Counted<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.len += s.len();
+        self.inner.write_str(s)
+    }
+}
+
+/// Writes straight to the port, taking the lock for each write so long outputs don't hold it.
+impl fmt::Write for ComPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        _print_to(*self, format_args!("{s}"));
+        Ok(())
+    }
+}
+
+static RECEIVE_QUEUE: OnceLock<ArrayQueue<u8>> = OnceLock::new();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Drains the COM1 receive FIFO into the byte queue.
+///
+/// Called from the serial interrupt handler. This doesn't take the [`SERIAL1`] lock since it
+/// only touches the receive side of the UART.
+pub(crate) fn receive_pending() {
+    let mut line_status = Port::<u8>::new(SERIAL1_ADDR + LINE_STATUS_OFFSET);
+    let mut data = Port::<u8>::new(SERIAL1_ADDR);
+
+    while unsafe { line_status.read() } & LINE_STATUS_DATA_READY != 0 {
+        // Reading has to happen regardless of there being a consumer to clear the interrupt
+        let byte = unsafe { data.read() };
+        add_byte(byte);
+    }
+}
+
+pub(crate) fn add_byte(byte: u8) {
+    if let Ok(queue) = RECEIVE_QUEUE.try_get() {
+        if queue.push(byte).is_err() {
+            warn!("serial queue full; dropping serial input");
+        } else {
+            WAKER.wake();
+        }
+    }
+}
+
+/// Returns a stream of the bytes received on COM1.
+///
+/// # Panics
+/// Panics if called more than once.
+pub fn bytes() -> SerialByteStream {
+    SerialByteStream::new()
+}
+
+pub struct SerialByteStream {
+    inner: QueueStream<u8>,
+}
+
+impl SerialByteStream {
+    pub fn new() -> Self {
+        RECEIVE_QUEUE
+            .try_init_once(|| ArrayQueue::new(100))
+            .expect("SerialByteStream::new should only be called once");
+        SerialByteStream {
+            inner: QueueStream::new(RECEIVE_QUEUE.get(), &WAKER),
+        }
+    }
+}
+
+impl Default for SerialByteStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for SerialByteStream {
+    type Item = u8;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
+    _print_to(ComPort::Com1, args);
+}
+
+#[doc(hidden)]
+pub fn _print_to(port: ComPort, args: ::core::fmt::Arguments) {
     use core::fmt::Write;
 
     x86_64::instructions::interrupts::without_interrupts(|| {
-        SERIAL1
-            .spin_lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
+        let mut serial = port.port().spin_lock();
+        let mut counted = Counted {
+            inner: &mut *serial,
+            len: 0,
+        };
+        counted.write_fmt(args).expect("Printing to serial failed");
+        BYTES_WRITTEN[port as usize].fetch_add(counted.len, Ordering::Relaxed);
     });
 }
 
@@ -38,3 +195,67 @@ macro_rules! println {
     ($fmt:expr, $($arg:tt)*) => ($crate::print!(
         concat!($fmt, "\n"), $($arg)*));
 }
+
+/// Prints to the host through the given [`ComPort`](crate::serial::ComPort).
+#[macro_export]
+macro_rules! sprint {
+    ($port:expr, $($arg:tt)*) => {
+        $crate::serial::_print_to($port, format_args!($($arg)*));
+    };
+}
+
+/// Prints to the host through the given [`ComPort`](crate::serial::ComPort), appending a
+/// newline.
+#[macro_export]
+macro_rules! sprintln {
+    ($port:expr) => ($crate::sprint!($port, "\n"));
+    ($port:expr, $($arg:tt)*) => ($crate::sprint!(
+        $port, "{}\n", format_args!($($arg)*)));
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{FutureExt, StreamExt};
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    use super::{add_byte, bytes, ComPort, SERIAL1};
+
+    #[test_case]
+    fn ports_are_independent() {
+        assert!(!core::ptr::eq(ComPort::Com1.port(), ComPort::Com2.port()));
+
+        // Holding COM1 must not block output on COM2
+        let _com1 = SERIAL1.spin_lock();
+        sprintln!(ComPort::Com2, "com2 while com1 is held");
+        assert!(ComPort::Com2.port().try_lock().is_some());
+    }
+
+    #[test_case]
+    fn print_to_both() {
+        // Nothing else gets to print in between
+        without_interrupts(|| {
+            let before = [ComPort::Com1, ComPort::Com2].map(ComPort::bytes_written);
+            sprintln!(ComPort::Com1, "hello com1");
+            sprintln!(ComPort::Com2, "hello com2, {}", 42);
+            assert_eq!(
+                ComPort::Com1.bytes_written() - before[0],
+                "hello com1\n".len()
+            );
+            assert_eq!(
+                ComPort::Com2.bytes_written() - before[1],
+                "hello com2, 42\n".len()
+            );
+        });
+    }
+
+    #[test_case]
+    fn byte_stream_yields_received() {
+        let mut stream = bytes();
+        for &byte in b"hi" {
+            add_byte(byte);
+        }
+        assert_eq!(stream.next().now_or_never(), Some(Some(b'h')));
+        assert_eq!(stream.next().now_or_never(), Some(Some(b'i')));
+        assert_eq!(stream.next().now_or_never(), None);
+    }
+}
@@ -0,0 +1,287 @@
+use core::{
+    fmt::{self, Write},
+    mem,
+    str::SplitWhitespace,
+    sync::atomic::Ordering,
+};
+
+use alloc::string::String;
+use embedded_graphics::{pixelcolor::Rgb888, prelude::*};
+use futures::{stream, StreamExt};
+use itertools::Itertools;
+use thiserror::Error;
+
+use crate::{
+    allocator,
+    framebuffer::DISPLAY,
+    interrupts, keyboard,
+    memory::PAGE_ALLOCATOR,
+    rtc::{to_local, RTC, TIMER_FREQ},
+    serial::{self, ComPort},
+    task,
+    util::r#async::sleep_future::MONOTONIC_TIME,
+    vga_buffer,
+};
+
+const PROMPT: &str = "> ";
+const MAX_LINE_LEN: usize = 256;
+/// Downscale of `screenshot` when none is given, full size takes minutes over serial.
+const DEFAULT_SCREENSHOT_SCALE: usize = 4;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ShellError {
+    #[error("unknown command `{0}`, try `help`")]
+    UnknownCommand(String),
+    #[error("`{0}` doesn't take arguments")]
+    UnexpectedArgs(&'static str),
+    #[error("`{0}` isn't a valid scale, expected a whole number above 0")]
+    InvalidScale(String),
+    #[error("writing the output failed")]
+    Output(#[from] fmt::Error),
+}
+
+/// Writes to both COM1 and the screen, where the shell's output goes.
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial::_print_to(ComPort::Com1, format_args!("{s}"));
+        vga_buffer::_print(format_args!("{s}"));
+        Ok(())
+    }
+}
+
+/// Reads commands typed on COM1 or the keyboard and runs them, forever since neither ends.
+pub async fn run() {
+    let mut bytes = stream::select(serial::bytes(), keyboard::typed_bytes());
+    let mut editor = LineEditor::new();
+    let mut console = Console;
+    let _ = console.write_str(PROMPT);
+    while let Some(byte) = bytes.next().await {
+        let _ = match editor.push(byte) {
+            Edit::Typed(byte) => console.write_char(byte as char),
+            Edit::Erased => console.write_str("\x08 \x08"),
+            Edit::Line(line) => {
+                let _ = console.write_char('\n');
+                if let Err(err) = execute(&line, &mut console) {
+                    let _ = writeln!(console, "{}", err);
+                }
+                console.write_str(PROMPT)
+            }
+            Edit::Ignored => Ok(()),
+        };
+    }
+}
+
+/// Runs one command line, the first word picks the command and the rest are its arguments.
+///
+/// What the command prints goes to `out`.
+pub fn execute(line: &str, out: &mut impl Write) -> Result<(), ShellError> {
+    let mut words = line.split_whitespace();
+    let Some(command) = words.next() else {
+        return Ok(());
+    };
+    let no_args = |words: &mut SplitWhitespace, name| match words.next() {
+        Some(_) => Err(ShellError::UnexpectedArgs(name)),
+        None => Ok(()),
+    };
+
+    match command {
+        "help" => {
+            no_args(&mut words, "help")?;
+            writeln!(
+                out,
+                "commands: help echo [text] time uptime mem irqs clear screenshot [scale] panic"
+            )?;
+        }
+        "echo" => {
+            writeln!(out, "{}", words.join(" "))?;
+        }
+        "time" => {
+            no_args(&mut words, "time")?;
+            // Local like the clock face shows it
+            let now = to_local(RTC.spin_lock().read_date_time());
+            writeln!(out, "{now}")?;
+        }
+        "uptime" => {
+            no_args(&mut words, "uptime")?;
+            let ticks = MONOTONIC_TIME.load(Ordering::Acquire);
+            let millis = ticks as u64 * 1000 / TIMER_FREQ as u64;
+            writeln!(out, "{}.{:03}s", millis / 1000, millis % 1000)?;
+        }
+        "mem" => {
+            no_args(&mut words, "mem")?;
+            writeln!(out, "{}", allocator::stats())?;
+            if let Ok(frames) = PAGE_ALLOCATOR.try_get() {
+                let free = frames.spin_lock().free_frames();
+                writeln!(out, "frames: {} free ({} MiB)", free, free * 4 / 1024)?;
+            }
+            // The shell is itself a task, so the task list is locked while this runs
+            match task::try_live_task_count() {
+                Some(live) => {
+                    writeln!(out, "tasks: {} live", live)?;
+                }
+                None => {
+                    writeln!(out, "tasks: busy")?;
+                }
+            }
+            writeln!(
+                out,
+                "tasks: {} spawned, {} completed, {} ready",
+                task::spawned_task_count(),
+                task::completed_task_count(),
+                task::ready_task_count()
+            )?;
+        }
+        "irqs" => {
+            no_args(&mut words, "irqs")?;
+            for (vector, count) in interrupts::counts().into_iter().enumerate() {
+                if count > 0 {
+                    writeln!(out, "{:#04x}: {}", vector, count)?;
+                }
+            }
+        }
+        "clear" => {
+            no_args(&mut words, "clear")?;
+            if let Ok(display) = DISPLAY.try_get() {
+                let mut display = display.spin_lock();
+                let _ = display.as_mut().clear(Rgb888::BLACK);
+                display.draw_frame();
+            }
+        }
+        "screenshot" => {
+            let scale = match words.next() {
+                Some(arg) => match arg.parse() {
+                    Ok(scale) if scale > 0 => scale,
+                    _ => return Err(ShellError::InvalidScale(arg.into())),
+                },
+                None => DEFAULT_SCREENSHOT_SCALE,
+            };
+            no_args(&mut words, "screenshot")?;
+            if let Ok(display) = DISPLAY.try_get() {
+                let _ = display
+                    .spin_lock()
+                    .screenshot_ppm(&mut ComPort::Com1, scale);
+            }
+        }
+        "panic" => panic!("panic requested from the shell"),
+        other => return Err(ShellError::UnknownCommand(other.into())),
+    }
+    Ok(())
+}
+
+/// What a byte did to the line being typed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Edit {
+    Typed(u8),
+    Erased,
+    Line(String),
+    Ignored,
+}
+
+/// Collects typed bytes into lines, handling backspace.
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    line: String,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        Self {
+            line: String::new(),
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) -> Edit {
+        match byte {
+            b'\r' | b'\n' => Edit::Line(mem::take(&mut self.line)),
+            // Terminals send DEL for backspace
+            0x08 | 0x7f => match self.line.pop() {
+                Some(_) => Edit::Erased,
+                None => Edit::Ignored,
+            },
+            0x20..=0x7e if self.line.len() < MAX_LINE_LEN => {
+                self.line.push(byte as char);
+                Edit::Typed(byte)
+            }
+            _ => Edit::Ignored,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::Ordering;
+
+    use alloc::string::String;
+
+    use super::{execute, Edit, LineEditor, ShellError};
+    use crate::{rtc::TIMER_FREQ, util::r#async::sleep_future::MONOTONIC_TIME};
+
+    #[test_case]
+    fn line_editing() {
+        let mut editor = LineEditor::new();
+        for &byte in b"tiem" {
+            assert_eq!(editor.push(byte), Edit::Typed(byte));
+        }
+        assert_eq!(editor.push(0x7f), Edit::Erased);
+        assert_eq!(editor.push(0x7f), Edit::Erased);
+        assert_eq!(editor.push(0x1b), Edit::Ignored);
+        editor.push(b'm');
+        editor.push(b'e');
+        assert_eq!(editor.push(b'\r'), Edit::Line("time".into()));
+        assert_eq!(editor.push(0x7f), Edit::Ignored);
+    }
+
+    #[test_case]
+    fn dispatch() {
+        let out = &mut String::new();
+        assert_eq!(execute("   ", out), Ok(()));
+        assert_eq!(execute("uptime", out), Ok(()));
+        assert_eq!(execute("mem", out), Ok(()));
+        assert_eq!(
+            execute("uptime now", out),
+            Err(ShellError::UnexpectedArgs("uptime"))
+        );
+        assert_eq!(
+            execute("screenshot 0", out),
+            Err(ShellError::InvalidScale("0".into()))
+        );
+        assert_eq!(
+            execute("screenshot 2 3", out),
+            Err(ShellError::UnexpectedArgs("screenshot"))
+        );
+        assert_eq!(
+            execute("frobnicate 1 2", out),
+            Err(ShellError::UnknownCommand("frobnicate".into()))
+        );
+    }
+
+    #[test_case]
+    fn typed_uptime() {
+        let mut editor = LineEditor::new();
+        let mut out = String::new();
+        for &byte in b"uptime\n" {
+            if let Edit::Line(line) = editor.push(byte) {
+                execute(&line, &mut out).unwrap();
+            }
+        }
+        let seconds = MONOTONIC_TIME.load(Ordering::Acquire) / TIMER_FREQ;
+
+        let (whole, millis) = out
+            .strip_suffix("s\n")
+            .and_then(|uptime| uptime.split_once('.'))
+            .unwrap_or_else(|| panic!("`{out}` isn't an uptime"));
+        assert_eq!(millis.len(), 3);
+        assert!(millis.parse::<u32>().is_ok());
+        // The clock may tick between the command and reading it here
+        assert!(whole.parse::<usize>().unwrap() <= seconds);
+    }
+
+    #[test_case]
+    fn echo_joins_words() {
+        let mut out = String::new();
+        execute("echo  hello   world", &mut out).unwrap();
+        assert_eq!(out, "hello world\n");
+    }
+}
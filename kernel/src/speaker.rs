@@ -0,0 +1,77 @@
+use core::time::Duration;
+
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::{
+    pit::{divisor, PIT_COMMAND},
+    util::r#async::{mutex::Mutex, sleep},
+};
+
+const PIT_CHANNEL_2: u16 = 0x42;
+const SPEAKER_CONTROL: u16 = 0x61;
+
+/// Channel 2, lobyte/hibyte access, mode 3 (square wave), binary.
+const PIT_CHANNEL_2_SQUARE_WAVE: u8 = 0b1011_0110;
+/// Bit 0 gates the PIT into channel 2 and bit 1 connects it to the speaker.
+const SPEAKER_GATE: u8 = 0b11;
+
+pub const BELL_FREQ: u32 = 880;
+pub const BELL_DURATION: Duration = Duration::from_millis(100);
+
+/// Held for the length of a beep so overlapping beeps play one after another.
+static SPEAKER: Mutex<()> = Mutex::new(());
+
+/// Plays a square wave at `freq_hz` through the PC speaker for `dur`.
+pub async fn beep(freq_hz: u32, dur: Duration) {
+    let _speaker = SPEAKER.lock().await;
+    play(freq_hz);
+    sleep(dur).await;
+    stop();
+}
+
+fn play(freq_hz: u32) {
+    let [low, high] = divisor(freq_hz).to_le_bytes();
+    interrupts::without_interrupts(|| unsafe {
+        Port::<u8>::new(PIT_COMMAND).write(PIT_CHANNEL_2_SQUARE_WAVE);
+        let mut channel = Port::<u8>::new(PIT_CHANNEL_2);
+        channel.write(low);
+        channel.write(high);
+
+        let mut control = Port::<u8>::new(SPEAKER_CONTROL);
+        let prev = control.read();
+        control.write(prev | SPEAKER_GATE);
+    });
+}
+
+fn stop() {
+    interrupts::without_interrupts(|| unsafe {
+        let mut control = Port::<u8>::new(SPEAKER_CONTROL);
+        let prev = control.read();
+        control.write(prev & !SPEAKER_GATE);
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use core::{pin::pin, task::Context, time::Duration};
+
+    use futures::{task::noop_waker_ref, Future};
+    use x86_64::instructions::port::Port;
+
+    use super::{beep, SPEAKER_CONTROL, SPEAKER_GATE};
+
+    #[test_case]
+    fn beep_gates_speaker() {
+        let gate = || unsafe { Port::<u8>::new(SPEAKER_CONTROL).read() } & SPEAKER_GATE;
+
+        let mut beep = pin!(beep(440, Duration::from_millis(5)));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(beep.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(gate(), SPEAKER_GATE);
+        // Tests run with interrupts enabled so the clock wakes us
+        while beep.as_mut().poll(&mut cx).is_pending() {
+            x86_64::instructions::hlt();
+        }
+        assert_eq!(gate(), 0);
+    }
+}
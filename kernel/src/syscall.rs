@@ -0,0 +1,51 @@
+use core::arch::global_asm;
+
+use alloc::string::{String, ToString};
+use thiserror::Error;
+use tracing::instrument;
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    VirtAddr,
+};
+
+use crate::gdt;
+
+#[derive(Error, Debug)]
+pub enum SyscallInitError {
+    #[error("GDT layout doesn't fit syscall/sysret: {0}")]
+    InvalidSelectors(String),
+}
+
+/// Enables `syscall`/`sysret` and points `syscall` at [`syscall_entry`].
+///
+/// There is no userspace yet, so the entry is a stub that fails every call with `ENOSYS`.
+#[instrument(name = "syscall_init", err)]
+pub fn init() -> Result<(), SyscallInitError> {
+    let user = gdt::user_selectors();
+    let (kernel_code, kernel_data) = gdt::kernel_selectors();
+    Star::write(user.code, user.data, kernel_code, kernel_data)
+        .map_err(|err| SyscallInitError::InvalidSelectors(err.to_string()))?;
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // Interrupts stay off until the entry has switched to a kernel stack
+    SFMask::write(RFlags::INTERRUPT_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+    Ok(())
+}
+
+extern "C" {
+    /// Where `syscall` jumps to, with the user's rip in rcx and rflags in r11.
+    ///
+    /// Still on the user stack, so this doesn't touch memory at all.
+    fn syscall_entry();
+}
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    // Every call fails with ENOSYS until there is a dispatcher
+    "mov rax, -38",
+    "sysretq",
+);
@@ -1,25 +1,101 @@
-use core::task::{Context, Poll, Waker};
+use core::{
+    future::Future,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
 
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use alloc::{collections::BTreeMap, sync::Arc, task::Wake, vec::Vec};
 use crossbeam_queue::SegQueue;
 use tracing::warn;
 use x86_64::instructions::interrupts;
 
-use crate::util::r#async::mutex::Mutex;
+use crate::util::r#async::{mutex::Mutex, sleep_future::MONOTONIC_TIME};
 
-use super::{Task, TaskId};
+use super::{Priority, Task, TaskId};
 
 static EXECUTOR: Executor = Executor::new();
 
+/// How many high priority tasks are polled in a row while normal ones are waiting.
+pub const HIGH_PRIORITY_BATCH: usize = 16;
+
+/// Default for how many times a task can be polled in one pass of the executor.
+///
+/// One means a task that wakes itself waits for every other ready task and for the idle
+/// check before running again.
+pub const DEFAULT_POLL_BUDGET: usize = 1;
+
 pub struct Executor {
+    high_queue: SegQueue<TaskId>,
     task_queue: SegQueue<TaskId>,
     spawn_queue: SegQueue<Task>,
     task_waker_list: Mutex<BTreeMap<TaskId, (Task, Waker)>>,
+    polls: AtomicUsize,
+    spawned: AtomicUsize,
+    completed: AtomicUsize,
+    idle_ticks: AtomicUsize,
+    poll_budget: AtomicUsize,
+}
+
+/// A snapshot of an [`Executor`]'s counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExecutorMetrics {
+    /// Times any task has been polled.
+    pub polls: usize,
+    /// Tasks ever spawned.
+    pub spawned: usize,
+    /// Tasks that ran to completion.
+    pub completed: usize,
+    /// Tasks woken and waiting to be polled right now.
+    pub ready: usize,
+    /// RTC ticks spent halted waiting for a task to wake.
+    pub idle_ticks: usize,
+}
+
+pub fn spawn(task: impl Into<Task>) -> TaskId {
+    EXECUTOR.spawn(task)
+}
+
+pub fn spawn_prioritized(
+    future: impl Future<Output = ()> + Send + 'static,
+    priority: Priority,
+) -> TaskId {
+    EXECUTOR.spawn(Task::with_priority(future, priority))
+}
+
+/// Sets how many times a task can be polled in one pass of the global executor, see
+/// [`DEFAULT_POLL_BUDGET`].
+pub fn set_poll_budget(budget: usize) {
+    EXECUTOR.set_poll_budget(budget);
+}
+
+/// Counters of the global executor.
+pub fn metrics() -> ExecutorMetrics {
+    EXECUTOR.metrics()
+}
+
+/// Tasks spawned on the global executor that haven't completed, see [`Executor::live_tasks`].
+pub fn live_task_count() -> usize {
+    EXECUTOR.live_tasks()
 }
 
-pub fn spawn(task: impl Into<Task>) {
-    let task = task.into();
-    EXECUTOR.spawn_queue.push(task);
+/// Like [`live_task_count`] but gives up if the executor is busy, e.g. when called from a task.
+pub fn try_live_task_count() -> Option<usize> {
+    EXECUTOR.try_live_tasks()
+}
+
+/// Tasks woken and waiting to be polled by the global executor.
+pub fn ready_task_count() -> usize {
+    EXECUTOR.ready_tasks()
+}
+
+/// Tasks ever spawned on the global executor.
+pub fn spawned_task_count() -> usize {
+    EXECUTOR.spawned.load(Ordering::Relaxed)
+}
+
+/// Tasks that ran to completion on the global executor.
+pub fn completed_task_count() -> usize {
+    EXECUTOR.completed.load(Ordering::Relaxed)
 }
 
 pub fn run() -> ! {
@@ -32,17 +108,94 @@ pub fn run() -> ! {
 impl Executor {
     pub const fn new() -> Self {
         Self {
+            high_queue: SegQueue::new(),
             task_queue: SegQueue::new(),
             spawn_queue: SegQueue::new(),
             task_waker_list: Mutex::new(BTreeMap::new()),
+            polls: AtomicUsize::new(0),
+            spawned: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            idle_ticks: AtomicUsize::new(0),
+            poll_budget: AtomicUsize::new(DEFAULT_POLL_BUDGET),
         }
     }
 
+    /// Sets how many times a task can be polled in one pass, at least once.
+    pub fn set_poll_budget(&self, budget: usize) {
+        self.poll_budget.store(budget.max(1), Ordering::Relaxed);
+    }
+
+    pub fn spawn(&self, task: impl Into<Task>) -> TaskId {
+        let task = task.into();
+        let id = task.id;
+        self.spawned.fetch_add(1, Ordering::Relaxed);
+        self.spawn_queue.push(task);
+        id
+    }
+
+    /// Tasks spawned that haven't completed, including ones not yet picked up by a pass.
+    ///
+    /// Spins on the task list, which is held while a task is polled, so this deadlocks when
+    /// called from a task of this executor. Use [`Executor::try_live_tasks`] there.
+    pub fn live_tasks(&self) -> usize {
+        self.task_waker_list.spin_lock().len() + self.spawn_queue.len()
+    }
+
+    /// Like [`Executor::live_tasks`] but `None` if the task list is locked.
+    pub fn try_live_tasks(&self) -> Option<usize> {
+        let tasks = self.task_waker_list.try_lock()?;
+        Some(tasks.len() + self.spawn_queue.len())
+    }
+
+    /// Tasks woken and waiting to be polled.
+    pub fn ready_tasks(&self) -> usize {
+        self.high_queue.len() + self.task_queue.len()
+    }
+
+    pub fn metrics(&self) -> ExecutorMetrics {
+        ExecutorMetrics {
+            polls: self.polls.load(Ordering::Relaxed),
+            spawned: self.spawned.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            ready: self.ready_tasks(),
+            idle_ticks: self.idle_ticks.load(Ordering::Relaxed),
+        }
+    }
+
+    fn ready_queue(&self, priority: Priority) -> &SegQueue<TaskId> {
+        match priority {
+            Priority::High => &self.high_queue,
+            Priority::Normal => &self.task_queue,
+        }
+    }
+
+    /// Pops the next task to poll, high priority first unless `high_run` of them have been
+    /// polled in a row and a normal task is waiting.
+    fn next_ready(&self, high_run: &mut usize) -> Option<TaskId> {
+        if *high_run < HIGH_PRIORITY_BATCH {
+            if let Some(id) = self.high_queue.pop() {
+                *high_run += 1;
+                return Some(id);
+            }
+        }
+        match self.task_queue.pop() {
+            Some(id) => {
+                *high_run = 0;
+                Some(id)
+            }
+            None => self.high_queue.pop(),
+        }
+    }
+
+    /// Polls every ready task at most the poll budget times, so a task that keeps waking itself
+    /// can't hold up the others or the idle check. Tasks that run out wait for the next pass.
     fn run_ready_tasks(&'static self) {
         let Self {
-            task_queue,
             spawn_queue,
             task_waker_list,
+            polls,
+            completed,
+            ..
         } = self;
 
         // get the spawn queue
@@ -50,22 +203,35 @@ impl Executor {
             let mut task_waker = task_waker_list.spin_lock();
             while let Some(task) = spawn_queue.pop() {
                 let id = task.id;
-                task_waker.insert(task.id, (task, TaskWaker::new(id, task_queue).into()));
-                task_queue.push(id);
+                let queue = self.ready_queue(task.priority);
+                task_waker.insert(task.id, (task, TaskWaker::new(id, queue).into()));
+                queue.push(id);
             }
         }
 
-        while let Some(task_id) = task_queue.pop() {
+        let mut high_run = 0;
+        let budget = self.poll_budget.load(Ordering::Relaxed);
+        let mut polled = BTreeMap::new();
+        let mut deferred = Vec::new();
+        while let Some(task_id) = self.next_ready(&mut high_run) {
             let mut task_waker = task_waker_list.spin_lock();
             let Some((task, waker)) = task_waker.get_mut(&task_id) else {
                 warn!(task_id = task_id.0, "Task was woken up more than necessary");
                 continue;
             };
+            let polls_this_pass = polled.entry(task_id).or_insert(0);
+            if *polls_this_pass == budget {
+                deferred.push((task_id, task.priority));
+                continue;
+            }
+            *polls_this_pass += 1;
 
             let mut context = Context::from_waker(waker);
 
+            polls.fetch_add(1, Ordering::Relaxed);
             match task.poll(&mut context) {
                 Poll::Ready(()) => {
+                    completed.fetch_add(1, Ordering::Relaxed);
                     task_waker.remove(&task_id);
                 }
                 Poll::Pending => {
@@ -73,12 +239,19 @@ impl Executor {
                 }
             }
         }
+
+        for (task_id, priority) in deferred {
+            self.ready_queue(priority).push(task_id);
+        }
     }
 
     fn sleep_if_idle(&self) {
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if self.high_queue.is_empty() && self.task_queue.is_empty() {
+            let start = MONOTONIC_TIME.load(Ordering::Acquire);
             interrupts::enable_and_hlt();
+            let idle = MONOTONIC_TIME.load(Ordering::Acquire).wrapping_sub(start);
+            self.idle_ticks.fetch_add(idle, Ordering::Relaxed);
         } else {
             interrupts::enable();
         }
@@ -124,3 +297,152 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use futures::future::pending;
+
+    use crate::{
+        task::{Priority, Task},
+        util::r#async::{mutex::Mutex, yield_now},
+    };
+
+    use super::{Executor, HIGH_PRIORITY_BATCH};
+
+    #[test_case]
+    fn metrics_count_polls() {
+        static EXECUTOR: Executor = Executor::new();
+        EXECUTOR.spawn(async {});
+        EXECUTOR.spawn(async {});
+        EXECUTOR.spawn(async {
+            yield_now().await;
+        });
+        EXECUTOR.run_ready_tasks();
+        EXECUTOR.run_ready_tasks();
+
+        let metrics = EXECUTOR.metrics();
+        assert_eq!(metrics.spawned, 3);
+        assert_eq!(metrics.completed, 3);
+        assert_eq!(EXECUTOR.live_tasks(), 0);
+        // The yielding task needs a second poll, in the second pass
+        assert_eq!(metrics.polls, 4);
+        assert_eq!(metrics.ready, 0);
+        assert_eq!(metrics.idle_ticks, 0);
+    }
+
+    #[test_case]
+    fn high_priority_runs_first() {
+        static EXECUTOR: Executor = Executor::new();
+        static ORDER: Mutex<Vec<&str>> = Mutex::new(Vec::new());
+        for (name, priority) in [
+            ("normal 1", Priority::Normal),
+            ("high 1", Priority::High),
+            ("normal 2", Priority::Normal),
+            ("high 2", Priority::High),
+        ] {
+            EXECUTOR.spawn(Task::with_priority(
+                async move { ORDER.spin_lock().push(name) },
+                priority,
+            ));
+        }
+        EXECUTOR.run_ready_tasks();
+
+        assert_eq!(
+            *ORDER.spin_lock(),
+            ["high 1", "high 2", "normal 1", "normal 2"]
+        );
+    }
+
+    #[test_case]
+    fn busy_high_priority_doesnt_starve_normal() {
+        static EXECUTOR: Executor = Executor::new();
+        static HIGH_POLLS: AtomicUsize = AtomicUsize::new(0);
+        static SEEN_BY_NORMAL: AtomicUsize = AtomicUsize::new(usize::MAX);
+        for _ in 0..2 * HIGH_PRIORITY_BATCH {
+            EXECUTOR.spawn(Task::with_priority(
+                async {
+                    HIGH_POLLS.fetch_add(1, Ordering::SeqCst);
+                },
+                Priority::High,
+            ));
+        }
+        EXECUTOR.spawn(async {
+            SEEN_BY_NORMAL.store(HIGH_POLLS.load(Ordering::SeqCst), Ordering::SeqCst);
+        });
+        EXECUTOR.run_ready_tasks();
+
+        assert_eq!(SEEN_BY_NORMAL.load(Ordering::SeqCst), HIGH_PRIORITY_BATCH);
+        assert_eq!(HIGH_POLLS.load(Ordering::SeqCst), 2 * HIGH_PRIORITY_BATCH);
+    }
+
+    #[test_case]
+    fn self_waking_task_polled_once_per_pass() {
+        static EXECUTOR: Executor = Executor::new();
+        static SPINNER_POLLS: AtomicUsize = AtomicUsize::new(0);
+        static OTHER_POLLS: AtomicUsize = AtomicUsize::new(0);
+        EXECUTOR.spawn(async {
+            loop {
+                SPINNER_POLLS.fetch_add(1, Ordering::SeqCst);
+                yield_now().await;
+            }
+        });
+        EXECUTOR.spawn(async {
+            loop {
+                OTHER_POLLS.fetch_add(1, Ordering::SeqCst);
+                yield_now().await;
+            }
+        });
+
+        for pass in 1..=3 {
+            EXECUTOR.run_ready_tasks();
+            assert_eq!(SPINNER_POLLS.load(Ordering::SeqCst), pass);
+            assert_eq!(OTHER_POLLS.load(Ordering::SeqCst), pass);
+            assert_eq!(EXECUTOR.metrics().ready, 2);
+        }
+    }
+
+    #[test_case]
+    fn poll_budget_bounds_polls_per_pass() {
+        static EXECUTOR: Executor = Executor::new();
+        static POLLS: AtomicUsize = AtomicUsize::new(0);
+        EXECUTOR.set_poll_budget(3);
+        EXECUTOR.spawn(async {
+            loop {
+                POLLS.fetch_add(1, Ordering::SeqCst);
+                yield_now().await;
+            }
+        });
+
+        EXECUTOR.run_ready_tasks();
+        assert_eq!(POLLS.load(Ordering::SeqCst), 3);
+        EXECUTOR.run_ready_tasks();
+        assert_eq!(POLLS.load(Ordering::SeqCst), 6);
+    }
+
+    #[test_case]
+    fn live_tasks_tracks_unfinished() {
+        static EXECUTOR: Executor = Executor::new();
+        EXECUTOR.spawn(async {});
+        EXECUTOR.spawn(pending::<()>());
+        assert_eq!(EXECUTOR.live_tasks(), 2);
+
+        EXECUTOR.run_ready_tasks();
+        assert_eq!(EXECUTOR.try_live_tasks(), Some(1));
+        assert_eq!(EXECUTOR.ready_tasks(), 0);
+
+        let _tasks = EXECUTOR.task_waker_list.spin_lock();
+        assert_eq!(EXECUTOR.try_live_tasks(), None);
+    }
+
+    #[test_case]
+    fn spawn_returns_task_id() {
+        static EXECUTOR: Executor = Executor::new();
+        let task = Task::new(async {});
+        let expected = task.id();
+        assert_eq!(EXECUTOR.spawn(task), expected);
+        let next = EXECUTOR.spawn(async {});
+        assert!(next > expected);
+    }
+}
@@ -1,40 +1,79 @@
 use core::{
     future::Future,
-    pin::Pin,
+    pin::{pin, Pin},
     sync::atomic::AtomicU64,
     task::{Context, Poll},
 };
 
 use alloc::boxed::Box;
+use futures::task::noop_waker_ref;
+use x86_64::instructions::{hlt, interrupts};
 
 mod executor;
+pub use executor::completed_task_count;
+pub use executor::live_task_count;
+pub use executor::metrics;
+pub use executor::ready_task_count;
 pub use executor::run;
+pub use executor::set_poll_budget;
 pub use executor::spawn;
+pub use executor::spawn_prioritized;
+pub use executor::spawned_task_count;
+pub use executor::try_live_task_count;
+pub use executor::ExecutorMetrics;
+pub use executor::DEFAULT_POLL_BUDGET;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+/// Identifies a spawned task, unique for the lifetime of the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, core::sync::atomic::Ordering::Relaxed))
     }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// Which ready queue a task goes on when woken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Priority {
+    /// Polled before any normal task, up to a batch at a time.
+    High,
+    #[default]
+    Normal,
 }
 
 pub struct Task {
     id: TaskId,
+    priority: Priority,
     future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Self {
+        Self::with_priority(future, Priority::default())
+    }
+
+    pub fn with_priority(
+        future: impl Future<Output = ()> + Send + 'static,
+        priority: Priority,
+    ) -> Self {
         Self {
             id: TaskId::new(),
+            priority,
             future: Box::pin(future),
         }
     }
 
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
     fn poll(&mut self, context: &mut Context<'_>) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
@@ -46,6 +85,26 @@ impl<F: Future<Output = ()> + Send + 'static> From<F> for Task {
     }
 }
 
+/// Runs `future` to completion on the current thread, without the executor.
+///
+/// Safe to call before [`run`], e.g. during init. Everything that wakes a future here is an
+/// interrupt, so the future is polled again after each one instead of using a real waker.
+/// With interrupts disabled it spins instead of halting forever.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut context = Context::from_waker(noop_waker_ref());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        if interrupts::are_enabled() {
+            hlt();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! loop_yield {
     ($($body:tt)*) => {
@@ -55,3 +114,24 @@ macro_rules! loop_yield {
         }
     };
 }
+
+#[cfg(test)]
+mod test {
+    use core::{sync::atomic::Ordering, time::Duration};
+
+    use crate::util::r#async::{sleep, sleep_future::MONOTONIC_TIME};
+
+    use super::block_on;
+
+    #[test_case]
+    fn block_on_ready() {
+        assert_eq!(block_on(async { 42 }), 42);
+    }
+
+    #[test_case]
+    fn block_on_sleep() {
+        let start = MONOTONIC_TIME.load(Ordering::Acquire);
+        block_on(sleep(Duration::from_millis(5)));
+        assert!(MONOTONIC_TIME.load(Ordering::Acquire) > start);
+    }
+}
@@ -1,36 +1,220 @@
-use core::panic::PanicInfo;
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use x86_64::instructions::interrupts;
 
 use crate::{
     print, println,
     qemu::{exit_qemu, QemuExitCode},
-    util::hlt_loop,
+    rtc::TIMER_FREQ,
+    util::{
+        backtrace, hlt_loop,
+        once::OnceLock,
+        r#async::{mutex::Mutex, sleep_future::MONOTONIC_TIME},
+    },
 };
 
+/// How long a test may run before the watchdog fails it, unless changed with
+/// [`set_default_timeout`] or [`set_timeout`].
+pub const DEFAULT_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+static TESTS: OnceLock<&'static [&'static dyn Testable]> = OnceLock::new();
+static CURRENT_TEST: AtomicUsize = AtomicUsize::new(0);
+/// Set while a [`ShouldPanic`] test runs so the panic handler treats the panic as a pass.
+static EXPECTING_PANIC: AtomicBool = AtomicBool::new(false);
+static DEFAULT_TIMEOUT_TICKS: AtomicUsize = AtomicUsize::new(to_ticks(DEFAULT_TEST_TIMEOUT));
+/// Tick at which the running test is considered hung, `usize::MAX` when disarmed.
+static DEADLINE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+const fn to_ticks(duration: Duration) -> usize {
+    (duration.as_micros() * TIMER_FREQ as u128 / 1_000_000) as usize
+}
+
+/// Sets the timeout for every test that doesn't pick its own.
+pub fn set_default_timeout(timeout: Duration) {
+    DEFAULT_TIMEOUT_TICKS.store(to_ticks(timeout), Ordering::SeqCst);
+}
+
+/// Sets the timeout of the running test, counted from now.
+pub fn set_timeout(timeout: Duration) {
+    arm_watchdog(to_ticks(timeout));
+}
+
+fn arm_watchdog(ticks: usize) {
+    let now = MONOTONIC_TIME.load(Ordering::Acquire);
+    DEADLINE.store(now.saturating_add(ticks), Ordering::SeqCst);
+}
+
+/// Fails the run if the current test is past its deadline.
+///
+/// Called from the RTC interrupt, so only tests that leave interrupts enabled can be caught.
+pub fn watchdog_tick(now: usize) {
+    if now < DEADLINE.load(Ordering::Relaxed) {
+        return;
+    }
+    DEADLINE.store(usize::MAX, Ordering::SeqCst);
+
+    let name = TESTS
+        .try_get()
+        .ok()
+        .and_then(|tests| tests.get(CURRENT_TEST.load(Ordering::SeqCst)))
+        .map_or("test", |test| test.name());
+    println!("[timeout]\n");
+    println!("Error: {} didn't finish in time\n", name);
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop()
+}
+
 pub fn test_runner(tests: &[&dyn Testable]) {
     println!("Running {} tests", tests.len());
-    for test in tests {
+    // SAFETY: `run_tests_from` never returns, so the caller's frame holding the slice outlives
+    // every use of it, including from the panic handler
+    let tests = unsafe {
+        core::mem::transmute::<&[&dyn Testable], &'static [&'static dyn Testable]>(tests)
+    };
+    TESTS.init_once(|| tests);
+    run_tests_from(0);
+}
+
+/// Runs the tests starting at `index` and exits QEMU.
+///
+/// The panic handler resumes here after an expected panic, since there is no unwinding to get
+/// back to the loop. Tests run with interrupts enabled so the watchdog can fire.
+fn run_tests_from(index: usize) -> ! {
+    let tests = *TESTS.get();
+    for (i, test) in tests.iter().enumerate().skip(index) {
+        CURRENT_TEST.store(i, Ordering::SeqCst);
+        arm_watchdog(DEFAULT_TIMEOUT_TICKS.load(Ordering::SeqCst));
+        interrupts::enable();
         test.run();
+        interrupts::disable();
+        DEADLINE.store(usize::MAX, Ordering::SeqCst);
     }
     exit_qemu(QemuExitCode::Success);
+    hlt_loop()
 }
 
-pub trait Testable {
+pub trait Testable: Sync {
+    fn name(&self) -> &'static str;
     fn run(&self);
 }
 
+/// A test that passes only if it panics, the equivalent of `#[should_panic]`.
+///
+/// ```ignore
+/// #[test_case]
+/// static PANICS: ShouldPanic = ShouldPanic {
+///     name: "panics",
+///     test: || panic!(),
+///     check: None,
+/// };
+/// ```
+///
+/// Execution moves on to the next test from inside the panic handler, so anything the test
+/// had locked stays locked.
+pub struct ShouldPanic {
+    pub name: &'static str,
+    pub test: fn(),
+    /// Runs in the panic handler to check it was the expected panic, failing the test if it
+    /// panics too.
+    pub check: Option<fn(&PanicInfo)>,
+}
+
+/// The [`ShouldPanic::check`] of the running test.
+static PANIC_CHECK: Mutex<Option<fn(&PanicInfo)>> = Mutex::new(None);
+
+impl Testable for ShouldPanic {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(&self) {
+        print!("{} (should panic)...\t", self.name);
+        *PANIC_CHECK.spin_lock() = self.check;
+        EXPECTING_PANIC.store(true, Ordering::SeqCst);
+        (self.test)();
+        EXPECTING_PANIC.store(false, Ordering::SeqCst);
+        println!("[failed]\n");
+        println!("Error: test did not panic\n");
+        exit_qemu(QemuExitCode::Failed);
+    }
+}
+
+/// A benchmark run through the test harness, reporting RTC ticks per iteration.
+///
+/// ```ignore
+/// #[test_case]
+/// static CLEAR: Bench = Bench {
+///     name: "clear",
+///     iterations: 100,
+///     bench: || { /* ... */ },
+/// };
+/// ```
+///
+/// A tick is [`TIMER_FREQ`]Hz, so only things slower than that over all iterations give a
+/// useful number.
+pub struct Bench {
+    pub name: &'static str,
+    pub iterations: usize,
+    pub bench: fn(),
+}
+
+impl Testable for Bench {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(&self) {
+        print!("{} (bench)...\t", self.name);
+        let start = MONOTONIC_TIME.load(Ordering::Acquire);
+        for _ in 0..self.iterations {
+            (self.bench)();
+        }
+        let ticks = MONOTONIC_TIME.load(Ordering::Acquire) - start;
+
+        // Thousandths of a tick so fast benches don't all round to 0
+        let milliticks = ticks * 1000 / self.iterations.max(1);
+        let micros = ticks as u64 * 1_000_000 / TIMER_FREQ as u64 / self.iterations.max(1) as u64;
+        println!(
+            "{}.{:03} ticks/iter (~{}us, {} ticks over {} iterations)",
+            milliticks / 1000,
+            milliticks % 1000,
+            micros,
+            ticks,
+            self.iterations
+        );
+    }
+}
+
 impl<T> Testable for T
 where
-    T: Fn(),
+    T: Fn() + Sync,
 {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
     fn run(&self) {
-        print!("{}...\t", core::any::type_name::<T>());
+        print!("{}...\t", self.name());
         self();
         println!("[ok]");
     }
 }
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    if EXPECTING_PANIC.swap(false, Ordering::SeqCst) {
+        // A failing check panics again, which isn't expected anymore
+        if let Some(check) = PANIC_CHECK.try_lock().and_then(|mut check| check.take()) {
+            check(info);
+        }
+        println!("[ok]");
+        run_tests_from(CURRENT_TEST.load(Ordering::SeqCst) + 1);
+    }
     println!("[failed]\n");
     println!("Error: {}\n", info);
+    println!("Backtrace:\n{}", backtrace::capture());
     exit_qemu(QemuExitCode::Failed);
     hlt_loop()
 }
@@ -40,3 +224,23 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
 fn panic(info: &PanicInfo) -> ! {
     test_panic_handler(info)
 }
+
+#[cfg(test)]
+mod test {
+    use core::{sync::atomic::Ordering, time::Duration};
+
+    use super::{set_timeout, DEADLINE};
+    use crate::util::r#async::sleep_future::MONOTONIC_TIME;
+
+    #[test_case]
+    fn watchdog_sees_ticks() {
+        set_timeout(Duration::from_secs(1));
+        assert_ne!(DEADLINE.load(Ordering::SeqCst), usize::MAX);
+
+        // The watchdog can only fire if the clock advances while a test runs
+        let start = MONOTONIC_TIME.load(Ordering::Acquire);
+        while MONOTONIC_TIME.load(Ordering::Acquire) == start {
+            x86_64::instructions::hlt();
+        }
+    }
+}
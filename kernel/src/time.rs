@@ -0,0 +1,115 @@
+use core::{
+    arch::x86_64::_rdtsc,
+    hint::spin_loop,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use tracing::debug;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    rtc::TIMER_FREQ,
+    util::{once::OnceLock, r#async::sleep_future::MONOTONIC_TIME},
+};
+
+/// Interrupt that advances [`MONOTONIC_TIME`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum TimeSource {
+    /// The RTC's periodic interrupt, at exactly [`TIMER_FREQ`].
+    #[default]
+    Rtc,
+    /// PIT channel 0, for when the RTC can't be used. Runs at the closest rate the PIT can
+    /// divide down to, so [`TIMER_FREQ`] is a little off.
+    Pit,
+}
+
+static TIME_SOURCE: AtomicU8 = AtomicU8::new(TimeSource::Rtc as u8);
+
+/// The interrupt [`MONOTONIC_TIME`] is counting.
+pub fn time_source() -> TimeSource {
+    match TIME_SOURCE.load(Ordering::Acquire) {
+        source if source == TimeSource::Pit as u8 => TimeSource::Pit,
+        _ => TimeSource::Rtc,
+    }
+}
+
+/// Picks what drives [`MONOTONIC_TIME`], has to happen before the interrupt controllers are
+/// set up.
+pub(crate) fn set_time_source(source: TimeSource) {
+    TIME_SOURCE.store(source as u8, Ordering::Release);
+}
+
+/// TSC cycles per microsecond, measured against [`MONOTONIC_TIME`] on first use.
+static TSC_PER_US: OnceLock<u64> = OnceLock::new();
+/// RTC ticks the TSC is measured over, about 16ms.
+const CALIBRATION_TICKS: usize = TIMER_FREQ / 64;
+
+/// Spins for at least `us` microseconds, for init sequences that need waits shorter than an
+/// RTC tick. Async code should [`sleep`](crate::util::r#async::sleep) instead.
+///
+/// # Panics
+/// The first call calibrates the TSC against the clock interrupt, so it panics if interrupts
+/// are disabled.
+pub fn busy_wait_us(us: u64) {
+    let end = rdtsc() + us * tsc_per_us();
+    while rdtsc() < end {
+        spin_loop();
+    }
+}
+
+/// TSC cycles per microsecond, calibrating on the first call.
+pub fn tsc_per_us() -> u64 {
+    *TSC_PER_US.get_or_init(calibrate)
+}
+
+fn rdtsc() -> u64 {
+    // SAFETY: every x86_64 CPU has the TSC
+    unsafe { _rdtsc() }
+}
+
+fn calibrate() -> u64 {
+    assert!(
+        interrupts::are_enabled(),
+        "TSC calibration needs the clock interrupt"
+    );
+    // Start right on a tick so the first one counts in full
+    let edge = MONOTONIC_TIME.load(Ordering::Acquire);
+    while MONOTONIC_TIME.load(Ordering::Acquire) == edge {
+        spin_loop();
+    }
+    let start = MONOTONIC_TIME.load(Ordering::Acquire);
+    let start_tsc = rdtsc();
+    while MONOTONIC_TIME.load(Ordering::Acquire).wrapping_sub(start) < CALIBRATION_TICKS {
+        spin_loop();
+    }
+    let cycles = rdtsc() - start_tsc;
+
+    let us = CALIBRATION_TICKS as u64 * 1_000_000 / TIMER_FREQ as u64;
+    let per_us = (cycles / us).max(1);
+    debug!(per_us, "calibrated TSC");
+    per_us
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::Ordering;
+
+    use super::busy_wait_us;
+    use crate::{rtc::TIMER_FREQ, util::r#async::sleep_future::MONOTONIC_TIME};
+
+    #[test_case]
+    fn busy_wait_one_milli() {
+        // Calibrate first so it isn't part of the measurement
+        busy_wait_us(1);
+        let start = MONOTONIC_TIME.load(Ordering::Acquire);
+        busy_wait_us(1000);
+        let ticks = MONOTONIC_TIME.load(Ordering::Acquire) - start;
+
+        let expected = TIMER_FREQ / 1000;
+        assert!(
+            (expected - 2..=expected + 3).contains(&ticks),
+            "1ms took {ticks} ticks"
+        );
+    }
+}
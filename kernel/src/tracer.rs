@@ -1,10 +1,22 @@
-use core::sync::atomic::{AtomicBool, AtomicU64};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
 
-use alloc::{collections::BTreeMap, fmt, vec::Vec};
-use tracing::{field::Visit, info, span, subscriber::set_global_default, Metadata, Subscriber};
+use alloc::{collections::BTreeMap, fmt, string::String, vec::Vec};
+use smallvec::SmallVec;
+use tracing::{
+    debug, field::Visit, info, span, subscriber::set_global_default, Metadata, Subscriber,
+};
 use tracing_core::span::Current;
 
-use crate::{print, println, util::r#async::mutex::Mutex, vga_print, vga_println};
+use crate::{
+    println,
+    rtc::TIMER_FREQ,
+    util::r#async::{mutex::Mutex, sleep_future::MONOTONIC_TIME},
+    vga_println,
+};
 
 pub fn init() {
     set_global_default(SimpleLogger::default()).expect("Couldn't initialize logging");
@@ -13,21 +25,82 @@ pub fn init() {
 
 pub static SHOULD_USE_SCREEN: AtomicBool = AtomicBool::new(true);
 
-pub struct SerialVisitor;
+/// Whether exiting a span logs how long it was entered for.
+pub static SHOULD_TIME_SPANS: AtomicBool = AtomicBool::new(true);
 
-impl Visit for SerialVisitor {
+/// Number of formatted log lines kept around for [`dump_recent`].
+pub const RECENT_LOG_CAPACITY: usize = 64;
+
+static RECENT_LOGS: Mutex<LogRing> = Mutex::new(LogRing::new());
+
+/// Writes the most recent log lines, oldest first, one per line.
+///
+/// Writes nothing if the log ring is currently locked, so this is safe to call from a panic
+/// handler.
+pub fn dump_recent(out: &mut impl Write) -> fmt::Result {
+    let Some(recent) = RECENT_LOGS.try_lock() else {
+        return Ok(());
+    };
+    for line in recent.iter() {
+        writeln!(out, "{line}")?;
+    }
+    Ok(())
+}
+
+/// Writes the spans entered right now, outermost first, as `outer::inner`.
+///
+/// Writes nothing if the logger is currently locked or isn't the global subscriber, so this is
+/// safe to call from a panic handler.
+pub fn dump_span_stack(out: &mut impl Write) -> fmt::Result {
+    tracing::dispatcher::get_default(|dispatch| {
+        let inner = dispatch
+            .downcast_ref::<SimpleLogger>()
+            .and_then(|logger| logger.inner.try_lock());
+        match inner {
+            Some(inner) => inner.write_stack(out),
+            None => Ok(()),
+        }
+    })
+}
+
+/// Fixed size ring of log lines where the oldest entry gets overwritten once full.
+struct LogRing {
+    lines: [String; RECENT_LOG_CAPACITY],
+    next: usize,
+    len: usize,
+}
+
+impl LogRing {
+    const fn new() -> Self {
+        const EMPTY: String = String::new();
+        Self {
+            lines: [EMPTY; RECENT_LOG_CAPACITY],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        self.lines[self.next] = line;
+        self.next = (self.next + 1) % RECENT_LOG_CAPACITY;
+        self.len = (self.len + 1).min(RECENT_LOG_CAPACITY);
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &String> {
+        let start = (self.next + RECENT_LOG_CAPACITY - self.len) % RECENT_LOG_CAPACITY;
+        (0..self.len).map(move |i| &self.lines[(start + i) % RECENT_LOG_CAPACITY])
+    }
+}
+
+/// Formats the fields of an event into a log line.
+pub struct LineVisitor<'a>(&'a mut String);
+
+impl Visit for LineVisitor<'_> {
     fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
-        let screen = SHOULD_USE_SCREEN.load(core::sync::atomic::Ordering::Relaxed);
         if field.name() == "message" {
-            if screen {
-                vga_print!("{value:?} ");
-            }
-            print!("{value:?} ");
+            let _ = write!(self.0, "{value:?} ");
         } else {
-            if screen {
-                vga_print!("{} = {:?}, ", field.name(), value);
-            }
-            print!("{} = {:?}, ", field.name(), value);
+            let _ = write!(self.0, "{} = {:?}, ", field.name(), value);
         }
     }
 }
@@ -41,6 +114,20 @@ pub struct SimpleLogger {
 pub struct SimpleLoggerInner {
     spans: BTreeMap<u64, (usize, &'static Metadata<'static>)>,
     stack: Vec<u64>,
+    /// Entry ticks per span id, a stack so re-entered spans time correctly
+    entered: BTreeMap<u64, SmallVec<[usize; 4]>>,
+}
+
+impl SimpleLoggerInner {
+    fn write_stack(&self, out: &mut impl Write) -> fmt::Result {
+        for (i, id) in self.stack.iter().enumerate() {
+            if i > 0 {
+                out.write_str("::")?;
+            }
+            out.write_str(self.spans[id].1.name())?;
+        }
+        Ok(())
+    }
 }
 
 impl Subscriber for SimpleLogger {
@@ -76,40 +163,24 @@ impl Subscriber for SimpleLogger {
             let target = metadata.target();
             let screen = SHOULD_USE_SCREEN.load(core::sync::atomic::Ordering::Relaxed);
 
-            print!("[{level}] ");
-            if screen {
-                vga_print!("[{level}] ");
-            }
-            if let Some(inner) = self.inner.try_lock() {
-                let mut stack_iter = inner.stack.iter();
-                let start = stack_iter.next();
-
-                if let Some(start) = start {
-                    print!("{}", inner.spans[start].1.name());
-                    if screen {
-                        vga_print!("{}", inner.spans[start].1.name());
-                    }
-                    for n in stack_iter {
-                        print!("::{}", inner.spans[n].1.name());
-                        if screen {
-                            vga_print!("::{}", inner.spans[n].1.name());
-                        }
-                    }
-                    print!(": ");
-                    if screen {
-                        vga_print!(": ");
-                    }
-                }
+            let mut line = String::new();
+            let _ = write!(line, "[{level}] ");
+            if let Some(inner) = self.inner.try_lock()
+                && !inner.stack.is_empty()
+            {
+                let _ = inner.write_stack(&mut line);
+                let _ = write!(line, ": ");
             };
 
-            print!("{target}: ");
+            let _ = write!(line, "{target}: ");
+            event.record(&mut LineVisitor(&mut line));
+
+            println!("{}", line);
             if screen {
-                vga_print!("{target}: ");
+                vga_println!("{}", line);
             }
-            event.record(&mut SerialVisitor);
-            println!();
-            if screen {
-                vga_println!();
+            if let Some(mut recent) = RECENT_LOGS.try_lock() {
+                recent.push(line);
             }
         })
     }
@@ -117,16 +188,40 @@ impl Subscriber for SimpleLogger {
     fn enter(&self, span: &span::Id) {
         x86_64::instructions::interrupts::without_interrupts(|| {
             let mut inner = self.inner.spin_lock();
-            inner.stack.push(span.into_non_zero_u64().into());
+            let id = span.into_non_zero_u64().into();
+            inner.stack.push(id);
+            inner
+                .entered
+                .entry(id)
+                .or_default()
+                .push(MONOTONIC_TIME.load(Ordering::Acquire));
         })
     }
 
-    fn exit(&self, _span: &span::Id) {
+    fn exit(&self, span: &span::Id) {
         x86_64::instructions::interrupts::without_interrupts(|| {
-            let mut inner = self.inner.spin_lock();
-            // FIXME: this technically assumes that all spans are entered and exited in heirarchical
-            // order
-            inner.stack.pop();
+            let timing = {
+                let mut inner = self.inner.spin_lock();
+                // FIXME: this technically assumes that all spans are entered and exited in
+                // heirarchical order
+                inner.stack.pop();
+                let id = span.into_non_zero_u64().into();
+                let start = inner.entered.get_mut(&id).and_then(SmallVec::pop);
+                // Spans that aren't entered anymore are forgotten so the map doesn't only grow
+                if inner.entered.get(&id).is_some_and(SmallVec::is_empty) {
+                    inner.entered.remove(&id);
+                }
+                start.zip(inner.spans.get(&id).map(|(_, metadata)| metadata.name()))
+            };
+
+            // The lock has to be released before logging since `event` needs it
+            if let Some((start, name)) = timing
+                && SHOULD_TIME_SPANS.load(Ordering::Relaxed)
+            {
+                let ticks = MONOTONIC_TIME.load(Ordering::Acquire).wrapping_sub(start);
+                let elapsed = Duration::from_micros(ticks as u64 * 1_000_000 / TIMER_FREQ as u64);
+                debug!("{name}: {elapsed:?}");
+            }
         })
     }
 
@@ -140,3 +235,65 @@ impl Subscriber for SimpleLogger {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use alloc::{format, string::String};
+
+    use tracing::{info_span, Level};
+
+    use super::{dump_span_stack, LogRing, SimpleLogger, RECENT_LOG_CAPACITY};
+
+    #[test_case]
+    fn log_ring_keeps_newest() {
+        let mut ring = LogRing::new();
+        let total = RECENT_LOG_CAPACITY + 10;
+        for i in 0..total {
+            ring.push(format!("line {i}"));
+        }
+
+        let mut lines = ring.iter();
+        for i in (total - RECENT_LOG_CAPACITY)..total {
+            assert_eq!(lines.next(), Some(&format!("line {i}")));
+        }
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test_case]
+    fn log_ring_partial() {
+        let mut ring = LogRing::new();
+        ring.push(String::from("first"));
+        ring.push(String::from("second"));
+
+        let mut lines = ring.iter();
+        assert_eq!(lines.next().map(String::as_str), Some("first"));
+        assert_eq!(lines.next().map(String::as_str), Some("second"));
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test_case]
+    fn span_stack_names_entered_spans() {
+        let outer = info_span!("outer");
+        let _outer = outer.enter();
+        let inner = tracing::span!(Level::INFO, "inner");
+        let _inner = inner.enter();
+
+        let mut stack = String::new();
+        dump_span_stack(&mut stack).unwrap();
+        assert!(stack.ends_with("outer::inner"));
+    }
+
+    #[test_case]
+    fn exited_spans_are_forgotten() {
+        let span = info_span!("forgotten");
+        let id = span.id().unwrap().into_u64();
+        span.in_scope(|| span.in_scope(|| {}));
+
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            tracing::dispatcher::get_default(|dispatch| {
+                let logger = dispatch.downcast_ref::<SimpleLogger>().unwrap();
+                assert!(!logger.inner.spin_lock().entered.contains_key(&id));
+            })
+        });
+    }
+}
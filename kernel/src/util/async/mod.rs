@@ -1,13 +1,15 @@
-use core::task::Poll;
+use core::{future::poll_fn, pin::pin, task::Poll};
 
-use futures::Future;
+use futures::{future::Either, Future};
 
 pub mod mutex;
+pub mod poison_mutex;
 pub mod sleep_future;
+pub mod stream;
 /// Implements a waker for waking multiple tasks
 pub mod waker_list;
 
-pub use sleep_future::sleep;
+pub use sleep_future::{interval, sleep, Interval};
 
 pub async fn yield_now() {
     struct YieldNow {
@@ -32,3 +34,100 @@ pub async fn yield_now() {
     }
     YieldNow { yielded: false }.await;
 }
+
+/// Polls both futures until one finishes, returning its output and dropping the other.
+///
+/// `a` is polled first, so it wins if both are ready on the same poll.
+pub async fn select2<A: Future, B: Future>(a: A, b: B) -> Either<A::Output, B::Output> {
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+    // Both get polled every time so both register the current waker
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = a.as_mut().poll(cx) {
+            return Poll::Ready(Either::Left(output));
+        }
+        b.as_mut().poll(cx).map(Either::Right)
+    })
+    .await
+}
+
+/// Polls both futures until both finish, returning both outputs.
+///
+/// A future that finishes first isn't polled again while waiting on the other.
+pub async fn join2<A: Future, B: Future>(a: A, b: B) -> (A::Output, B::Output) {
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+    let mut a_output = None;
+    let mut b_output = None;
+    poll_fn(|cx| {
+        if a_output.is_none() {
+            if let Poll::Ready(output) = a.as_mut().poll(cx) {
+                a_output = Some(output);
+            }
+        }
+        if b_output.is_none() {
+            if let Poll::Ready(output) = b.as_mut().poll(cx) {
+                b_output = Some(output);
+            }
+        }
+        match (a_output.take(), b_output.take()) {
+            (Some(a), Some(b)) => Poll::Ready((a, b)),
+            (a, b) => {
+                a_output = a;
+                b_output = b;
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use core::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+
+    use futures::future::{pending, Either};
+
+    use crate::{rtc::TIMER_FREQ, task::block_on};
+
+    use super::{join2, select2, sleep, sleep_future::MONOTONIC_TIME};
+
+    #[test_case]
+    fn select2_ready_wins_and_drops_loser() {
+        static DROPPED: AtomicBool = AtomicBool::new(false);
+        struct Guard;
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                DROPPED.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let loser = async {
+            let _guard = Guard;
+            pending::<()>().await;
+        };
+        assert!(matches!(
+            block_on(select2(loser, async { 42 })),
+            Either::Right(42)
+        ));
+        assert!(DROPPED.load(Ordering::SeqCst));
+    }
+
+    #[test_case]
+    fn join2_waits_for_longer() {
+        let long = Duration::from_millis(10);
+        let start = MONOTONIC_TIME.load(Ordering::Acquire);
+        // Polling the finished short sleep again would panic
+        let ((), answer) = block_on(join2(sleep(Duration::from_millis(2)), async {
+            sleep(long).await;
+            42
+        }));
+        let elapsed = MONOTONIC_TIME.load(Ordering::Acquire) - start;
+        assert_eq!(answer, 42);
+        // Sleeps end a tick early since the monotonic clock lags by one
+        assert!(elapsed >= long.as_millis() as usize * TIMER_FREQ / 1000 - 1);
+    }
+}
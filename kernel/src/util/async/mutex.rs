@@ -2,19 +2,61 @@ use core::{
     cell::UnsafeCell,
     fmt::Debug,
     ops::{Deref, DerefMut},
-    pin::Pin,
+    panic::Location,
+    pin::{pin, Pin},
     sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     task::{Context, Poll},
+    time::Duration,
 };
 
 use alloc::fmt;
-use futures::Future;
-use tracing::trace;
+use futures::{
+    future::{select, Either},
+    Future,
+};
+use tracing::{trace, warn};
 use x86_64::instructions::interrupts;
 
 use crate::println;
 
-use super::waker_list::WakerList;
+use super::{
+    sleep,
+    waker_list::{WakerList, WakerListHandle},
+};
+
+/// Spins on a lock between warnings about a possible deadlock.
+pub const SPIN_WARN_THRESHOLD: usize = 1 << 24;
+
+/// Cap on the exponential backoff between lock attempts, as a power of two of spin loop hints.
+const MAX_BACKOFF_SHIFT: u32 = 6;
+
+/// Waits out a failed lock attempt, twice as long each time up to [`MAX_BACKOFF_SHIFT`], so
+/// spinning cores don't keep fighting over the lock's cache line.
+#[derive(Default)]
+struct Backoff {
+    shift: u32,
+}
+
+impl Backoff {
+    fn spin(&mut self) {
+        for _ in 0..1 << self.shift {
+            core::hint::spin_loop();
+        }
+        self.shift = (self.shift + 1).min(MAX_BACKOFF_SHIFT);
+    }
+}
+
+/// Set while a spin warning is being logged, since logging takes the serial lock which may be
+/// the one we are stuck on.
+static REPORTING_SPIN: AtomicBool = AtomicBool::new(false);
+
+fn report_spin(location: &Location<'_>, spins: usize) {
+    if REPORTING_SPIN.swap(true, Ordering::Acquire) {
+        return;
+    }
+    warn!("spun {spins} times on a lock at {location}, possible deadlock");
+    REPORTING_SPIN.store(false, Ordering::Release);
+}
 
 #[derive(Default)]
 pub struct Mutex<T: ?Sized> {
@@ -38,15 +80,35 @@ impl<T> Mutex<T> {
     }
 }
 impl<T: ?Sized> Mutex<T> {
+    /// Takes the lock if it's free, only returning `None` if it's actually held.
     pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.try_lock_with(|locked| {
+            locked.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        })
+    }
+
+    /// Whether the lock is held right now, which may already have changed by the time it returns.
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Acquire)
+    }
+
+    /// Like [`Self::try_lock`] but may fail spuriously, for loops that retry anyway.
+    fn try_lock_weak(&self) -> Option<MutexGuard<'_, T>> {
+        self.try_lock_with(|locked| {
+            locked.compare_exchange_weak(false, true, Ordering::AcqRel, Ordering::Acquire)
+        })
+    }
+
+    fn try_lock_with(
+        &self,
+        cas: impl FnOnce(&AtomicBool) -> Result<bool, bool>,
+    ) -> Option<MutexGuard<'_, T>> {
         let locked = self.locked.load(Ordering::Acquire);
         if locked {
             return None;
         }
 
-        self.locked
-            .compare_exchange_weak(false, true, Ordering::AcqRel, Ordering::Acquire)
-            .ok()?;
+        cas(&self.locked).ok()?;
 
         Some(MutexGuard {
             inner: unsafe { &mut *self.inner.get() },
@@ -57,28 +119,65 @@ impl<T: ?Sized> Mutex<T> {
 
     pub async fn lock(&self) -> MutexGuard<'_, T> {
         loop {
-            MutexLocker {
-                locked: &self.locked,
-                waker_list: &self.wakeup_list,
-            }
-            .await;
+            MutexLocker::new(&self.locked, &self.wakeup_list).await;
             if let Some(guard) = self.try_lock() {
                 return guard;
             }
         }
     }
 
+    /// Like [`Self::lock`] but gives up with `None` after `dur`.
+    pub async fn lock_timeout(&self, dur: Duration) -> Option<MutexGuard<'_, T>> {
+        wait_unlocked(&self.locked, &self.wakeup_list, dur, || self.try_lock()).await
+    }
+
+    /// Spins until the lock is acquired.
+    ///
+    /// Warns with the caller's location every [`SPIN_WARN_THRESHOLD`] spins.
+    #[track_caller]
     pub fn spin_lock(&self) -> MutexGuard<'_, T> {
-        let mut first = true;
+        self.spin_lock_at(Location::caller())
+    }
+
+    /// [`Self::spin_lock`] reporting `location`, for wrappers that track their own caller.
+    pub(super) fn spin_lock_at(&self, location: &Location<'_>) -> MutexGuard<'_, T> {
+        self.spin(location, SPIN_WARN_THRESHOLD, None)
+            .expect("unbounded spin only returns once locked")
+    }
+
+    /// Like [`Self::spin_lock`] but gives up with `None` after `max_spins` failed attempts.
+    ///
+    /// Useful where a lock held elsewhere would otherwise hang the machine, like in interrupt
+    /// handlers.
+    #[track_caller]
+    pub fn spin_lock_bounded(&self, max_spins: usize) -> Option<MutexGuard<'_, T>> {
+        self.spin(Location::caller(), SPIN_WARN_THRESHOLD, Some(max_spins))
+    }
+
+    /// Spins with backoff until the lock is acquired or `max_spins` runs out.
+    fn spin(
+        &self,
+        location: &Location<'_>,
+        warn_threshold: usize,
+        max_spins: Option<usize>,
+    ) -> Option<MutexGuard<'_, T>> {
+        let mut spins = 0;
+        let mut backoff = Backoff::default();
         loop {
-            if let Some(lock) = self.try_lock() {
-                return lock;
+            if let Some(lock) = self.try_lock_weak() {
+                return Some(lock);
             }
-            if first {
-                first = false;
+            if spins == 0 {
                 trace!("spinning");
             }
-            core::hint::spin_loop();
+            spins += 1;
+            if spins % warn_threshold == 0 {
+                report_spin(location, spins);
+            }
+            if max_spins.is_some_and(|max| spins >= max) {
+                return None;
+            }
+            backoff.spin();
         }
     }
 
@@ -159,16 +258,30 @@ impl<T: ?Sized> Drop for MutexGuard<'_, T> {
     }
 }
 
+/// Waits for the lock to look free, deregistering its waker if dropped before then.
 struct MutexLocker<'t> {
     locked: &'t AtomicBool,
     waker_list: &'t WakerList,
+    handle: Option<WakerListHandle<'t>>,
+}
+
+impl<'t> MutexLocker<'t> {
+    fn new(locked: &'t AtomicBool, waker_list: &'t WakerList) -> Self {
+        Self {
+            locked,
+            waker_list,
+            handle: None,
+        }
+    }
 }
 
 impl Future for MutexLocker<'_> {
     type Output = ();
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Whether or not we were notified the old registration is stale now
+        self.handle = None;
         if self.locked.load(Ordering::Acquire) {
-            self.waker_list.register(cx.waker().clone());
+            self.handle = Some(self.waker_list.register(cx.waker().clone()));
             Poll::Pending
         } else {
             Poll::Ready(())
@@ -176,6 +289,26 @@ impl Future for MutexLocker<'_> {
     }
 }
 
+/// Races [`MutexLocker`] against a sleep, retrying `try_lock` each time the lock looks free.
+async fn wait_unlocked<G>(
+    locked: &AtomicBool,
+    waker_list: &WakerList,
+    dur: Duration,
+    mut try_lock: impl FnMut() -> Option<G>,
+) -> Option<G> {
+    let mut timeout = pin!(sleep(dur));
+    loop {
+        if let Some(guard) = try_lock() {
+            return Some(guard);
+        }
+        let locker = MutexLocker::new(locked, waker_list);
+        // The losing locker is dropped here, taking its registration with it
+        if let Either::Right(_) = select(locker, timeout.as_mut()).await {
+            return try_lock();
+        }
+    }
+}
+
 static NUM_GUARDS: AtomicUsize = AtomicUsize::new(0);
 static SHOULD_REENABLE: AtomicBool = AtomicBool::new(false);
 
@@ -188,12 +321,20 @@ impl<T> IntMutex<T> {
     }
 }
 impl<T: ?Sized> IntMutex<T> {
+    /// Takes the lock if it's free, only returning `None` if it's actually held.
     pub fn try_lock(&self) -> Option<IntMutexGuard<'_, T>> {
+        self.try_lock_with(Mutex::try_lock)
+    }
+
+    fn try_lock_with<'a>(
+        &'a self,
+        lock: impl FnOnce(&'a Mutex<T>) -> Option<MutexGuard<'a, T>>,
+    ) -> Option<IntMutexGuard<'a, T>> {
         let enabled = interrupts::are_enabled();
         if enabled {
             interrupts::disable();
         }
-        let ret = self.0.try_lock().map(IntMutexGuard);
+        let ret = lock(&self.0).map(IntMutexGuard);
 
         match (&ret, enabled) {
             // Couldn't acquire lock so reenable;
@@ -211,28 +352,53 @@ impl<T: ?Sized> IntMutex<T> {
 
     pub async fn lock(&self) -> IntMutexGuard<'_, T> {
         loop {
-            MutexLocker {
-                locked: &self.0.locked,
-                waker_list: &self.0.wakeup_list,
-            }
-            .await;
+            MutexLocker::new(&self.0.locked, &self.0.wakeup_list).await;
             if let Some(guard) = self.try_lock() {
                 return guard;
             }
         }
     }
 
+    /// Like [`Self::lock`] but gives up with `None` after `dur`.
+    pub async fn lock_timeout(&self, dur: Duration) -> Option<IntMutexGuard<'_, T>> {
+        wait_unlocked(&self.0.locked, &self.0.wakeup_list, dur, || self.try_lock()).await
+    }
+
+    /// Spins until the lock is acquired, see [`Mutex::spin_lock`].
+    #[track_caller]
     pub fn spin_lock(&self) -> IntMutexGuard<'_, T> {
-        let mut first = true;
+        let location = Location::caller();
+        let mut spins = 0;
+        let mut backoff = Backoff::default();
         loop {
-            if let Some(lock) = self.try_lock() {
+            if let Some(lock) = self.try_lock_with(Mutex::try_lock_weak) {
                 return lock;
             }
-            if first {
+            if spins == 0 {
                 println!("spinning");
-                first = false;
             }
-            core::hint::spin_loop();
+            spins += 1;
+            if spins % SPIN_WARN_THRESHOLD == 0 {
+                report_spin(location, spins);
+            }
+            backoff.spin();
+        }
+    }
+
+    /// Spins until the lock is acquired with interrupts disabled, and leaves them disabled.
+    ///
+    /// For code that manages the interrupt flag itself, like interrupt handlers, where the
+    /// guard of [`Self::spin_lock`] could enable interrupts on drop at the wrong time. Dropping
+    /// the returned guard only unlocks. Interrupts are disabled here even if they were enabled
+    /// on entry, and re-enabling them is then up to the caller, see
+    /// [`IntMutexKeepDisabledGuard::interrupts_were_enabled`].
+    #[track_caller]
+    pub fn lock_keep_disabled(&self) -> IntMutexKeepDisabledGuard<'_, T> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IntMutexKeepDisabledGuard {
+            guard: self.0.spin_lock_at(Location::caller()),
+            were_enabled,
         }
     }
 
@@ -312,3 +478,160 @@ impl<T: ?Sized> Drop for IntMutexGuard<'_, T> {
         }
     }
 }
+
+/// Guard of [`IntMutex::lock_keep_disabled`], which leaves interrupts alone when dropped.
+pub struct IntMutexKeepDisabledGuard<'t, T: ?Sized> {
+    guard: MutexGuard<'t, T>,
+    were_enabled: bool,
+}
+
+impl<T: ?Sized> IntMutexKeepDisabledGuard<'_, T> {
+    /// Whether interrupts were enabled before locking.
+    pub fn interrupts_were_enabled(&self) -> bool {
+        self.were_enabled
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for IntMutexKeepDisabledGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IntMutexKeepDisabledGuard")
+            .field("inner", &self.guard.inner)
+            .field("were_enabled", &self.were_enabled)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: ?Sized> Deref for IntMutexKeepDisabledGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard.inner
+    }
+}
+
+impl<T: ?Sized> DerefMut for IntMutexKeepDisabledGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.guard.inner
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::String;
+    use core::{
+        panic::Location,
+        pin::pin,
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use futures::{task::noop_waker_ref, Future, FutureExt};
+
+    use super::{IntMutex, Mutex};
+    use crate::tracer;
+
+    #[test_case]
+    fn lock_timeout_gives_up() {
+        let mutex = Mutex::new(());
+        let guard = mutex.spin_lock();
+
+        let mut waiter = pin!(mutex.lock_timeout(Duration::from_millis(5)));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let acquired = loop {
+            match waiter.as_mut().poll(&mut cx) {
+                Poll::Ready(acquired) => break acquired,
+                // Tests run with interrupts enabled so the clock wakes us
+                Poll::Pending => x86_64::instructions::hlt(),
+            }
+        };
+        assert!(acquired.is_none());
+        assert!(mutex.wakeup_list.is_empty());
+
+        drop(guard);
+        let acquired = mutex.lock_timeout(Duration::from_millis(5)).now_or_never();
+        assert!(matches!(acquired, Some(Some(_))));
+    }
+
+    #[test_case]
+    fn dropped_locker_deregisters() {
+        let mutex = Mutex::new(());
+        let guard = mutex.spin_lock();
+
+        let mut cx = Context::from_waker(noop_waker_ref());
+        {
+            let mut waiter = pin!(mutex.lock());
+            assert!(waiter.as_mut().poll(&mut cx).is_pending());
+            assert!(waiter.as_mut().poll(&mut cx).is_pending());
+            assert!(!mutex.wakeup_list.is_empty());
+        }
+        assert!(mutex.wakeup_list.is_empty());
+
+        drop(guard);
+        assert!(mutex.lock().now_or_never().is_some());
+    }
+
+    #[test_case]
+    fn int_lock_timeout_acquires() {
+        let mutex = IntMutex::new(1);
+        let acquired = mutex.lock_timeout(Duration::from_millis(5)).now_or_never();
+        assert_eq!(acquired.flatten().as_deref(), Some(&1));
+    }
+
+    #[test_case]
+    fn uncontended_try_lock_never_fails() {
+        let mutex = Mutex::new(0);
+        let int_mutex = IntMutex::new(0);
+        for _ in 0..100_000 {
+            *mutex.try_lock().expect("spurious try_lock failure") += 1;
+            *int_mutex.try_lock().expect("spurious try_lock failure") += 1;
+        }
+        assert_eq!(*mutex.spin_lock(), 100_000);
+        assert_eq!(*int_mutex.spin_lock(), 100_000);
+    }
+
+    #[test_case]
+    fn long_spin_warns() {
+        let mutex = Mutex::new(());
+        let _guard = mutex.spin_lock();
+
+        let location = Location::caller();
+        assert!(mutex.spin(location, 10, Some(25)).is_none());
+
+        let mut recent = String::new();
+        tracer::dump_recent(&mut recent).unwrap();
+        assert!(recent.contains("spun 20 times"));
+        assert!(recent.contains(file!()));
+    }
+
+    #[test_case]
+    fn bounded_spin_gives_up() {
+        let mutex = Mutex::new(1);
+        let guard = mutex.spin_lock();
+        assert!(mutex.spin_lock_bounded(100).is_none());
+        drop(guard);
+        assert_eq!(mutex.spin_lock_bounded(100).map(|guard| *guard), Some(1));
+    }
+
+    #[test_case]
+    fn keep_disabled_guard_leaves_interrupts_off() {
+        let mutex = IntMutex::new(0);
+
+        x86_64::instructions::interrupts::disable();
+        {
+            let mut guard = mutex.lock_keep_disabled();
+            assert!(!guard.interrupts_were_enabled());
+            *guard += 1;
+        }
+        assert!(!x86_64::instructions::interrupts::are_enabled());
+        assert!(mutex.0.try_lock().is_some());
+
+        x86_64::instructions::interrupts::enable();
+        {
+            let guard = mutex.lock_keep_disabled();
+            assert!(guard.interrupts_were_enabled());
+            assert_eq!(*guard, 1);
+        }
+        assert!(!x86_64::instructions::interrupts::are_enabled());
+        x86_64::instructions::interrupts::enable();
+    }
+}
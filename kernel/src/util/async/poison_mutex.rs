@@ -0,0 +1,168 @@
+use core::{
+    fmt::{self, Debug},
+    ops::{Deref, DerefMut},
+    panic::Location,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use thiserror::Error;
+
+use super::mutex::{Mutex, MutexGuard};
+
+/// A [`Mutex`] that can be marked as holding inconsistent data.
+///
+/// The kernel doesn't unwind, so a panicking holder never drops its guard and poisoning has to
+/// be explicit: call [`PoisonMutexGuard::poison`] before bailing out of a half finished update.
+/// Every later lock then returns [`Poisoned`] until [`PoisonMutex::clear_poison`].
+#[derive(Default)]
+pub struct PoisonMutex<T: ?Sized> {
+    poisoned: AtomicBool,
+    inner: Mutex<T>,
+}
+
+/// Returned by a lock on a poisoned [`PoisonMutex`], still holding the guard.
+#[derive(Error)]
+#[error("poisoned lock: another holder left the data inconsistent")]
+pub struct Poisoned<G> {
+    guard: G,
+}
+
+pub type LockResult<G> = Result<G, Poisoned<G>>;
+
+impl<G> Poisoned<G> {
+    /// Takes the guard anyway, for callers that can repair or ignore the data.
+    pub fn into_inner(self) -> G {
+        self.guard
+    }
+}
+
+impl<G> Debug for Poisoned<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Poisoned").finish_non_exhaustive()
+    }
+}
+
+impl<T> PoisonMutex<T> {
+    pub const fn new(inner: T) -> Self {
+        Self {
+            poisoned: AtomicBool::new(false),
+            inner: Mutex::new(inner),
+        }
+    }
+}
+
+impl<T: ?Sized> PoisonMutex<T> {
+    pub fn try_lock(&self) -> Option<LockResult<PoisonMutexGuard<'_, T>>> {
+        self.inner.try_lock().map(|guard| self.wrap(guard))
+    }
+
+    pub async fn lock(&self) -> LockResult<PoisonMutexGuard<'_, T>> {
+        self.wrap(self.inner.lock().await)
+    }
+
+    /// Spins until the lock is acquired, see [`Mutex::spin_lock`].
+    #[track_caller]
+    pub fn spin_lock(&self) -> LockResult<PoisonMutexGuard<'_, T>> {
+        let location = Location::caller();
+        self.wrap(self.inner.spin_lock_at(location))
+    }
+
+    /// Marks the data as inconsistent without taking the lock.
+    pub fn poison(&self) {
+        self.poisoned.store(true, Ordering::Release);
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Acquire)
+    }
+
+    /// Declares the data consistent again.
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Release);
+    }
+
+    fn wrap<'t>(&'t self, guard: MutexGuard<'t, T>) -> LockResult<PoisonMutexGuard<'t, T>> {
+        let guard = PoisonMutexGuard {
+            guard,
+            poisoned: &self.poisoned,
+        };
+        if self.is_poisoned() {
+            Err(Poisoned { guard })
+        } else {
+            Ok(guard)
+        }
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for PoisonMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("PoisonMutex");
+        d.field("poisoned", &self.is_poisoned());
+        match self.inner.try_lock() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.finish_non_exhaustive()
+    }
+}
+
+pub struct PoisonMutexGuard<'t, T: ?Sized> {
+    guard: MutexGuard<'t, T>,
+    poisoned: &'t AtomicBool,
+}
+
+impl<T: ?Sized> PoisonMutexGuard<'_, T> {
+    /// Marks the data as inconsistent, the lock is still released when this guard drops.
+    pub fn poison(&self) {
+        self.poisoned.store(true, Ordering::Release);
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for PoisonMutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoisonMutexGuard")
+            .field("inner", &&*self.guard)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: ?Sized> Deref for PoisonMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for PoisonMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::FutureExt;
+
+    use super::PoisonMutex;
+
+    #[test_case]
+    fn poisoned_lock_errs() {
+        let mutex = PoisonMutex::new(1);
+        {
+            let mut guard = mutex.spin_lock().unwrap();
+            *guard = 2;
+            guard.poison();
+        }
+        assert!(mutex.is_poisoned());
+
+        let err = mutex.try_lock().unwrap().unwrap_err();
+        assert_eq!(*err.into_inner(), 2);
+        assert!(mutex.lock().now_or_never().unwrap().is_err());
+
+        mutex.clear_poison();
+        assert_eq!(*mutex.spin_lock().unwrap(), 2);
+        mutex.poison();
+        assert!(mutex.spin_lock().is_err());
+    }
+}
@@ -4,7 +4,6 @@ use core::{
     sync::atomic::{AtomicUsize, Ordering},
     task::{Context, Poll, Waker},
     time::Duration,
-    usize,
 };
 
 use alloc::collections::BTreeMap;
@@ -61,13 +60,46 @@ pub fn wake_sleep(tick: usize) {
     }
 }
 
+/// Periodic timer for animations and polling, see [`interval`].
+#[derive(Debug)]
+pub struct Interval {
+    period: usize,
+    next_tick: usize,
+}
+
+/// Creates an [`Interval`] whose first tick completes immediately and every later one a
+/// `period` after the previous.
+///
+/// Deadlines are absolute so time spent between ticks doesn't add up to drift, but ticks
+/// missed entirely are skipped instead of firing in a burst.
+pub fn interval(period: Duration) -> Interval {
+    Interval {
+        period: duration_ticks(period).max(1),
+        next_tick: MONOTONIC_TIME.load(Ordering::Acquire),
+    }
+}
+
+impl Interval {
+    pub async fn tick(&mut self) {
+        SleepFuture::until(self.next_tick).await;
+        let now = MONOTONIC_TIME.load(Ordering::Acquire);
+        self.next_tick = self.next_tick.wrapping_add(self.period).max(now);
+    }
+}
+
+fn duration_ticks(dur: Duration) -> usize {
+    (dur.as_secs_f64() * TIMER_FREQ as f64) as usize
+}
+
 impl SleepFuture {
     pub fn new(dur: Duration) -> Self {
-        let ticks = dur.as_secs_f64() * TIMER_FREQ as f64;
         // have to subtract one because monotonic is 1 num behind
-        let ticks = ticks as usize -1;
+        let ticks = duration_ticks(dur).saturating_sub(1);
         let start = MONOTONIC_TIME.load(Ordering::Acquire);
-        let end_tick = start.wrapping_add(ticks);
+        Self::until(start.wrapping_add(ticks))
+    }
+
+    fn until(end_tick: usize) -> Self {
         Self {
             end_tick,
             registered: false,
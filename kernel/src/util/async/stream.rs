@@ -0,0 +1,71 @@
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crossbeam_queue::ArrayQueue;
+use futures::task::AtomicWaker;
+
+pub use futures::{Stream, StreamExt};
+
+/// A stream of what gets pushed onto `queue`, for queues filled from interrupt handlers.
+///
+/// Whoever pushes has to wake `waker` afterwards. The stream never ends.
+pub struct QueueStream<T: 'static> {
+    queue: &'static ArrayQueue<T>,
+    waker: &'static AtomicWaker,
+}
+
+impl<T> QueueStream<T> {
+    pub fn new(queue: &'static ArrayQueue<T>, waker: &'static AtomicWaker) -> Self {
+        Self { queue, waker }
+    }
+}
+
+impl<T> Stream for QueueStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(item) = self.queue.pop() {
+            return Poll::Ready(Some(item));
+        }
+
+        self.waker.register(cx.waker());
+        // Something could have been pushed before the waker was registered
+        match self.queue.pop() {
+            Some(item) => {
+                self.waker.take();
+                Poll::Ready(Some(item))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::task::{Context, Poll};
+
+    use crossbeam_queue::ArrayQueue;
+    use futures::task::{noop_waker_ref, AtomicWaker};
+
+    use crate::{task::block_on, util::once::Lazy};
+
+    use super::{QueueStream, StreamExt};
+
+    #[test_case]
+    fn queue_stream_yields_in_order() {
+        static QUEUE: Lazy<ArrayQueue<u8>> = Lazy::new(|| ArrayQueue::new(4));
+        static WAKER: AtomicWaker = AtomicWaker::new();
+        for byte in [1, 2, 3] {
+            QUEUE.push(byte).unwrap();
+        }
+
+        let mut doubled = QueueStream::new(&*QUEUE, &WAKER).map(|byte| byte * 2);
+        for expected in [2, 4, 6] {
+            assert_eq!(block_on(doubled.next()), Some(expected));
+        }
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert_eq!(doubled.poll_next_unpin(&mut cx), Poll::Pending);
+    }
+}
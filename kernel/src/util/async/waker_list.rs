@@ -1,26 +1,185 @@
-use core::task::Waker;
+use alloc::sync::Arc;
+use core::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::Waker,
+};
 
 use crossbeam_queue::SegQueue;
 
+/// A queued waker and the flag its notifier and its handle race to claim.
+///
+/// Whoever sets `claimed` first owns the entry: a notify wakes it, a dropped handle cancels it.
+/// Cancelled entries stay queued until a notify pops and skips them, so nothing is ever
+/// requeued behind an interrupt's back.
+#[derive(Debug)]
+struct Waiter {
+    claimed: Arc<AtomicBool>,
+    waker: Waker,
+}
+
+impl Waiter {
+    fn claim(&self) -> bool {
+        !self.claimed.swap(true, Ordering::AcqRel)
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct WakerList {
-    inner: SegQueue<Waker>,
+    inner: SegQueue<Waiter>,
+    /// Registered waiters that are neither notified nor cancelled.
+    waiting: AtomicUsize,
 }
 
 impl WakerList {
     pub const fn new() -> Self {
         Self {
             inner: SegQueue::new(),
+            waiting: AtomicUsize::new(0),
         }
     }
 
     pub fn notify_one(&self) {
-        if let Some(waker) = self.inner.pop() {
-            waker.wake_by_ref();
+        while let Some(waiter) = self.inner.pop() {
+            if waiter.claim() {
+                self.waiting.fetch_sub(1, Ordering::AcqRel);
+                waiter.waker.wake();
+                return;
+            }
+        }
+    }
+
+    /// Wakes every waiter registered when this is called.
+    ///
+    /// Wakers that register again while being woken stay queued for the next notify instead
+    /// of being woken in a loop.
+    pub fn notify_all(&self) {
+        for _ in 0..self.inner.len() {
+            let Some(waiter) = self.inner.pop() else {
+                break;
+            };
+            if waiter.claim() {
+                self.waiting.fetch_sub(1, Ordering::AcqRel);
+                waiter.waker.wake();
+            }
+        }
+    }
+
+    /// How many waiters are registered, which can be stale by the time it's read.
+    pub fn waiter_count(&self) -> usize {
+        self.waiting.load(Ordering::Acquire)
+    }
+
+    /// Queues `waker` until a [`Self::notify_one`] or until the handle is dropped.
+    ///
+    /// Waiters that give up drop their handle, so a later notify isn't spent on them.
+    pub fn register(&self, waker: Waker) -> WakerListHandle<'_> {
+        let claimed = Arc::new(AtomicBool::new(false));
+        self.waiting.fetch_add(1, Ordering::AcqRel);
+        self.inner.push(Waiter {
+            claimed: claimed.clone(),
+            waker,
+        });
+        WakerListHandle {
+            list: self,
+            claimed,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiter_count() == 0
+    }
+}
+
+/// A registration in a [`WakerList`], cancelled on drop if it hasn't been notified yet.
+#[derive(Debug)]
+#[must_use = "dropping the handle deregisters the waker"]
+pub struct WakerListHandle<'a> {
+    list: &'a WakerList,
+    claimed: Arc<AtomicBool>,
+}
+
+impl Drop for WakerListHandle<'_> {
+    fn drop(&mut self) {
+        if !self.claimed.swap(true, Ordering::AcqRel) {
+            self.list.waiting.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::sync::Arc;
+    use core::{
+        mem,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use futures::task::{waker, ArcWake};
+
+    use super::WakerList;
+
+    struct CountingWaker {
+        woken: AtomicUsize,
+        /// Registers again when woken, like a future polled straight away.
+        reregister: Option<&'static WakerList>,
+    }
+
+    impl ArcWake for CountingWaker {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.woken.fetch_add(1, Ordering::SeqCst);
+            if let Some(list) = arc_self.reregister {
+                mem::forget(list.register(waker(arc_self.clone())));
+            }
         }
     }
 
-    pub fn register(&self, waker: Waker) {
-        self.inner.push(waker);
+    #[test_case]
+    fn notify_all_wakes_everyone() {
+        static LIST: WakerList = WakerList::new();
+        let wakers = [None, None, Some(&LIST), None].map(|reregister| {
+            Arc::new(CountingWaker {
+                woken: AtomicUsize::new(0),
+                reregister,
+            })
+        });
+        let handles = wakers
+            .iter()
+            .map(|w| LIST.register(waker(w.clone())))
+            .collect::<alloc::vec::Vec<_>>();
+        assert_eq!(LIST.waiter_count(), 4);
+
+        LIST.notify_all();
+        assert!(wakers.iter().all(|w| w.woken.load(Ordering::SeqCst) == 1));
+        // Only the waker that registered again is left
+        assert_eq!(LIST.waiter_count(), 1);
+
+        drop(handles);
+        LIST.notify_all();
+        assert_eq!(wakers[2].woken.load(Ordering::SeqCst), 2);
+    }
+
+    #[test_case]
+    fn notify_one_skips_cancelled_waiters() {
+        static LIST: WakerList = WakerList::new();
+        let wakers = [(), ()].map(|_| {
+            Arc::new(CountingWaker {
+                woken: AtomicUsize::new(0),
+                reregister: None,
+            })
+        });
+        let first = LIST.register(waker(wakers[0].clone()));
+        let second = LIST.register(waker(wakers[1].clone()));
+        drop(first);
+        assert_eq!(LIST.waiter_count(), 1);
+
+        // The cancelled entry is still queued ahead, the wakeup must get past it
+        LIST.notify_one();
+        assert_eq!(wakers[0].woken.load(Ordering::SeqCst), 0);
+        assert_eq!(wakers[1].woken.load(Ordering::SeqCst), 1);
+        assert!(LIST.is_empty());
+
+        // Already notified, so dropping the handle doesn't count it again
+        drop(second);
+        assert!(LIST.is_empty());
     }
 }
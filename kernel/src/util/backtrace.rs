@@ -0,0 +1,238 @@
+use core::{arch::asm, fmt, mem::size_of};
+
+use thiserror::Error;
+use x86_64::{structures::paging::Translate, VirtAddr};
+
+use crate::{
+    memory::mapping::MAPPER,
+    util::once::{OnceLock, TryInitError},
+};
+
+/// Frames past this are cut off, deep enough to get out of any panic machinery.
+pub const MAX_FRAMES: usize = 32;
+
+/// Return addresses of the calls leading up to [`capture`], innermost first.
+///
+/// Kept out of the heap so it can be taken while panicking.
+#[derive(Debug, Clone)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+/// Walks the `rbp` chain from the caller of this function.
+///
+/// Needs the kernel built with frame pointers. The walk stops at the first frame pointer that
+/// isn't mapped, isn't aligned or doesn't move up the stack, so a corrupt chain ends the trace
+/// early rather than faulting.
+#[inline(never)]
+pub fn capture() -> Backtrace {
+    let mut backtrace = Backtrace {
+        frames: [0; MAX_FRAMES],
+        len: 0,
+    };
+    let mut rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+
+    // The panic may have happened with the page tables locked, so only ever try them
+    let Some(mapper) = MAPPER.try_lock() else {
+        return backtrace;
+    };
+    let readable =
+        |addr: u64| VirtAddr::try_new(addr).is_ok_and(|addr| mapper.translate_addr(addr).is_some());
+    while backtrace.len < MAX_FRAMES {
+        let return_slot = rbp.wrapping_add(size_of::<u64>() as u64);
+        if rbp == 0 || rbp % 8 != 0 || !readable(rbp) || !readable(return_slot) {
+            break;
+        }
+        let (next, return_address) =
+            unsafe { (*(rbp as *const u64), *(return_slot as *const u64)) };
+        if return_address == 0 {
+            break;
+        }
+        backtrace.frames[backtrace.len] = return_address;
+        backtrace.len += 1;
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    backtrace
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbols = SYMBOLS.try_get().ok();
+        for (i, &addr) in self.frames().iter().enumerate() {
+            write!(f, "{i:>3}: {addr:#018x}")?;
+            if let Some(symbols) = symbols {
+                write!(f, " kernel+{:#x}", addr.wrapping_sub(symbols.base))?;
+                if let Some((name, offset)) = symbols.resolve(addr) {
+                    write!(f, " {name}+{offset:#x}")?;
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+static SYMBOLS: OnceLock<SymbolTable> = OnceLock::new();
+
+#[derive(Error, Debug)]
+pub enum SymbolError {
+    #[error("Kernel image is not a 64 bit little endian ELF")]
+    NotElf,
+    #[error("Kernel image has no symbol table, was it stripped?")]
+    NoSymtab,
+    #[error("Kernel image is truncated or has out of bounds sections")]
+    Truncated,
+    #[error("Symbols already loaded: {0:?}")]
+    AlreadyLoaded(#[from] TryInitError),
+}
+
+/// One `Elf64_Sym`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Symbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    shndx: u16,
+    value: u64,
+    size: u64,
+}
+
+const STT_FUNC: u8 = 2;
+const SHT_SYMTAB: u32 = 2;
+
+/// The `.symtab` and its string table out of the kernel ELF the bootloader loaded.
+struct SymbolTable {
+    symbols: &'static [Symbol],
+    strings: &'static [u8],
+    /// Where the image was loaded, symbol values are relative to it.
+    base: u64,
+}
+
+impl SymbolTable {
+    fn parse(elf: &'static [u8], base: u64) -> Result<Self, SymbolError> {
+        let read_u16 = |at: usize| {
+            elf.get(at..at + 2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+                .ok_or(SymbolError::Truncated)
+        };
+        let read_u32 = |at: usize| {
+            elf.get(at..at + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                .ok_or(SymbolError::Truncated)
+        };
+        let read_u64 = |at: usize| {
+            elf.get(at..at + 8)
+                .map(|b| u64::from_le_bytes(b.try_into().unwrap()) as usize)
+                .ok_or(SymbolError::Truncated)
+        };
+        // ELFCLASS64, ELFDATA2LSB
+        if elf.get(..6) != Some(b"\x7fELF\x02\x01") {
+            return Err(SymbolError::NotElf);
+        }
+        let sections = read_u64(0x28)?;
+        let section_size = read_u16(0x3a)?;
+        let section_count = read_u16(0x3c)?;
+        let section = |i: usize| sections + i * section_size;
+
+        let symtab = (0..section_count)
+            .find(|&i| read_u32(section(i) + 4).is_ok_and(|kind| kind == SHT_SYMTAB))
+            .ok_or(SymbolError::NoSymtab)?;
+        let (offset, len) = (
+            read_u64(section(symtab) + 0x18)?,
+            read_u64(section(symtab) + 0x20)?,
+        );
+        let strtab = read_u32(section(symtab) + 0x28)? as usize;
+        let (str_offset, str_len) = (
+            read_u64(section(strtab) + 0x18)?,
+            read_u64(section(strtab) + 0x20)?,
+        );
+
+        let symbols = elf
+            .get(offset..offset + len)
+            .ok_or(SymbolError::Truncated)?;
+        if symbols.as_ptr() as usize % core::mem::align_of::<Symbol>() != 0 {
+            return Err(SymbolError::Truncated);
+        }
+        // Alignment is checked above and every bit pattern is a valid `Symbol`
+        let symbols = unsafe {
+            core::slice::from_raw_parts(
+                symbols.as_ptr().cast::<Symbol>(),
+                len / size_of::<Symbol>(),
+            )
+        };
+        let strings = elf
+            .get(str_offset..str_offset + str_len)
+            .ok_or(SymbolError::Truncated)?;
+        Ok(Self {
+            symbols,
+            strings,
+            base,
+        })
+    }
+
+    /// The function containing `addr` and how far into it `addr` is.
+    fn resolve(&self, addr: u64) -> Option<(&'static str, u64)> {
+        let addr = addr.checked_sub(self.base)?;
+        let symbol = self.symbols.iter().find(|s| {
+            s.info & 0xf == STT_FUNC && s.value <= addr && addr < s.value + s.size.max(1)
+        })?;
+        let name = self.strings.get(symbol.name as usize..)?;
+        let end = name.iter().position(|&b| b == 0)?;
+        let name = core::str::from_utf8(&name[..end]).ok()?;
+        Some((name, addr - symbol.value))
+    }
+}
+
+/// Loads symbols out of the kernel ELF so [`Backtrace`]s can name their frames.
+///
+/// `elf` is the whole file as the bootloader loaded it and `base` where its image was placed.
+pub fn init_symbols(elf: &'static [u8], base: u64) -> Result<(), SymbolError> {
+    let symbols = SymbolTable::parse(elf, base)?;
+    SYMBOLS.try_init_once(|| symbols)?;
+    Ok(())
+}
+
+/// The function containing `addr` and the offset into it, if symbols are loaded.
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    SYMBOLS.try_get().ok()?.resolve(addr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{capture, resolve, Backtrace};
+
+    #[inline(never)]
+    fn nested(depth: usize) -> Backtrace {
+        if depth == 0 {
+            capture()
+        } else {
+            let backtrace = nested(depth - 1);
+            core::hint::black_box(backtrace)
+        }
+    }
+
+    #[test_case]
+    fn capture_counts_nested_frames() {
+        let outer = nested(0);
+        let inner = nested(3);
+        assert!(!outer.frames().is_empty());
+        assert_eq!(inner.frames().len(), outer.frames().len() + 3);
+        // Past the call from this test everything is the same
+        assert_eq!(&inner.frames()[5..], &outer.frames()[2..]);
+        if let Some((name, _)) = resolve(inner.frames()[0]) {
+            assert!(name.contains("nested"), "innermost frame is {name}");
+        }
+    }
+}
@@ -1,5 +1,7 @@
 pub mod r#async;
+pub mod backtrace;
 pub mod once;
+pub mod random;
 
 pub fn hlt_loop() -> ! {
     loop {
@@ -0,0 +1,154 @@
+use core::{
+    arch::x86_64::{_rdrand64_step, _rdseed64_step, _rdtsc},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use raw_cpuid::CpuId;
+
+use crate::{
+    cpu,
+    rtc::RTC,
+    util::{once::Lazy, r#async::mutex::IntMutex},
+};
+
+/// Intel recommends giving up on RDRAND after 10 underflows in a row.
+const RDRAND_RETRIES: usize = 10;
+const RDSEED_RETRIES: usize = 100;
+
+static RDRAND_ENABLED: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(cpu::features().rdrand));
+static RDSEED_ENABLED: Lazy<bool> = Lazy::new(|| {
+    CpuId::new()
+        .get_extended_feature_info()
+        .is_some_and(|info| info.has_rdseed())
+});
+
+static FALLBACK: Lazy<IntMutex<Xorshift>> = Lazy::new(|| IntMutex::new(Xorshift::new(seed())));
+
+/// A random number from RDRAND, `None` if the CPU doesn't have it or it keeps failing.
+pub fn u64() -> Option<u64> {
+    if !RDRAND_ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    (0..RDRAND_RETRIES).find_map(|_| {
+        let mut value = 0;
+        // Safe because CPUID says RDRAND is there
+        (unsafe { rdrand64(&mut value) } == 1).then_some(value)
+    })
+}
+
+/// A random number from RDRAND, or from the xorshift fallback where RDRAND isn't available.
+///
+/// The fallback is not suitable for anything that has to be unpredictable.
+pub fn rand_u64() -> u64 {
+    u64().unwrap_or_else(|| FALLBACK.spin_lock().next_u64())
+}
+
+/// Fills `buf` the same way as [`rand_u64`].
+pub fn fill_bytes(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        chunk.copy_from_slice(&rand_u64().to_ne_bytes()[..chunk.len()]);
+    }
+}
+
+/// Seeds the fallback from RDSEED if there is one, or the timestamp counter and the wall
+/// clock otherwise.
+fn seed() -> u64 {
+    let hardware = RDSEED_ENABLED
+        .then(|| {
+            (0..RDSEED_RETRIES).find_map(|_| {
+                let mut value = 0;
+                // Safe because CPUID says RDSEED is there
+                (unsafe { rdseed64(&mut value) } == 1).then_some(value)
+            })
+        })
+        .flatten();
+    hardware.or_else(u64).unwrap_or_else(|| {
+        // Whoever holds the RTC may be what wants the number, so don't wait on it
+        let wall_clock = RTC
+            .try_lock()
+            .and_then(|mut rtc| rtc.try_read_date_time().ok())
+            .map_or(0, |time| time.and_utc().timestamp() as u64);
+        // Safe because every x86_64 CPU has a TSC
+        let tsc = unsafe { _rdtsc() };
+        tsc ^ wall_clock.rotate_left(32)
+    })
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand64(value: &mut u64) -> i32 {
+    _rdrand64_step(value)
+}
+
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed64(value: &mut u64) -> i32 {
+    _rdseed64_step(value)
+}
+
+/// Marsaglia's xorshift64.
+#[derive(Debug, Clone)]
+pub struct Xorshift {
+    state: u64,
+}
+
+impl Xorshift {
+    pub const fn new(seed: u64) -> Self {
+        // An all zero state would only ever produce zeros
+        let state = if seed == 0 {
+            0x9e37_79b9_7f4a_7c15
+        } else {
+            seed
+        };
+        Self { state }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::Ordering;
+
+    use super::{fill_bytes, rand_u64, u64, Xorshift, RDRAND_ENABLED};
+
+    #[test_case]
+    fn successive_calls_differ() {
+        if let Some(first) = u64() {
+            assert_ne!(Some(first), u64());
+        }
+        let mut first = [0; 16];
+        let mut second = [0; 16];
+        fill_bytes(&mut first);
+        fill_bytes(&mut second);
+        assert_ne!(first, second);
+        assert_ne!(rand_u64(), rand_u64());
+    }
+
+    #[test_case]
+    fn fallback_without_rdrand() {
+        let enabled = RDRAND_ENABLED.swap(false, Ordering::Relaxed);
+        assert_eq!(u64(), None);
+        let (a, b) = (rand_u64(), rand_u64());
+        let mut first = [0; 13];
+        let mut second = [0; 13];
+        fill_bytes(&mut first);
+        fill_bytes(&mut second);
+        RDRAND_ENABLED.store(enabled, Ordering::Relaxed);
+
+        assert_ne!(a, b);
+        assert_ne!(first, second);
+        assert_ne!(first, [0; 13]);
+    }
+
+    #[test_case]
+    fn xorshift_zero_seed() {
+        let mut rng = Xorshift::new(0);
+        assert_ne!(rng.next_u64(), 0);
+    }
+}
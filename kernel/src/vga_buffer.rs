@@ -1,25 +1,48 @@
 #![allow(dead_code)]
-use bootloader_api::info::FrameBufferInfo;
-use core::str;
-use core::{fmt, slice};
-use embedded_graphics::primitives::{PrimitiveStyle, Rectangle, StyledDrawable};
-use embedded_graphics::{mono_font::MonoTextStyle, pixelcolor::Rgb888, prelude::*, text::Text};
-use tracing::warn;
-
 use crate::framebuffer::Display;
 use crate::util::r#async::mutex::MutexGuard;
 use crate::{
     framebuffer::DISPLAY,
     util::{once::OnceLock, r#async::mutex::Mutex},
 };
+use crate::{speaker, task};
+use alloc::collections::VecDeque;
+use bootloader_api::info::FrameBufferInfo;
+use core::{fmt, mem};
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle, StyledDrawable};
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_9X15, MonoFont, MonoTextStyle},
+    pixelcolor::Rgb888,
+    prelude::*,
+    text::Text,
+};
 
 pub static WRITER: OnceLock<Mutex<Writer>> = OnceLock::new();
 
+/// Drawn in place of characters the font has no glyph for.
+pub const REPLACEMENT: char = '?';
+
+/// Tab stops are every this many character cells.
+const TAB_WIDTH: usize = 4;
+
+/// Bytes of text kept while the display is busy, the oldest are dropped past this.
+const PENDING_LEN: usize = 4096;
+
 pub struct Writer {
     buffer: Option<MutexGuard<'static, Display<'static>>>,
     info: FrameBufferInfo,
+    font: &'static MonoFont<'static>,
     x_pos: usize,
     y_pos: usize,
+    /// Text written while the display was busy, drawn by the next print that gets it.
+    pending: VecDeque<u8>,
+    /// Bytes pushed out of `pending` since it was last drawn.
+    dropped: usize,
+    /// Move words that don't fit on the rest of the line to the next one instead of splitting
+    /// them at the edge.
+    word_wrap: bool,
+    /// Whether the last character written was part of a word.
+    in_word: bool,
 }
 
 impl Writer {
@@ -27,61 +50,119 @@ impl Writer {
         Self {
             buffer: None,
             info,
+            font: &FONT_9X15,
             x_pos: 0,
             y_pos: 0,
+            pending: VecDeque::with_capacity(PENDING_LEN),
+            dropped: 0,
+            word_wrap: false,
+            in_word: false,
         }
     }
 
+    /// Turns word wrapping on or off, it's off by default.
+    ///
+    /// Words longer than a whole line are still broken at the edge. Only the text of one
+    /// write is looked ahead at, so a word split across writes may still be split on screen.
+    pub fn set_word_wrap(&mut self, word_wrap: bool) {
+        self.word_wrap = word_wrap;
+    }
+
+    /// Switches the font of everything written from now on.
+    ///
+    /// Text already on screen stays, so switching mid line can overlap it.
+    pub fn set_font(&mut self, font: &'static MonoFont<'static>) {
+        self.font = font;
+    }
+
+    /// Horizontal distance from one character to the next.
+    fn advance(&self) -> usize {
+        (self.font.character_size.width + self.font.character_spacing) as usize
+    }
+
+    fn line_height(&self) -> usize {
+        self.font.character_size.height as usize
+    }
+
+    /// How many characters fit on a line with the current font.
+    pub fn columns(&self) -> usize {
+        self.info.width / self.advance()
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
-        match byte {
-            b'\n' => self.new_line(),
-            byte => {
-                let new_xpos = self.x_pos + 9;
-                if new_xpos >= self.info.width {
+        self.write_char(byte as char)
+    }
+
+    /// Draws `c` at the cursor, or [`REPLACEMENT`] if the font has no glyph for it.
+    pub fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.new_line(),
+            '\r' => self.x_pos = 0,
+            '\t' => {
+                let next_stop = (self.x_pos / self.advance() / TAB_WIDTH + 1) * TAB_WIDTH;
+                if next_stop > self.columns() {
+                    // Past the edge wraps like any other character would
+                    self.new_line();
+                } else {
+                    self.x_pos = next_stop * self.advance();
+                }
+            }
+            c => {
+                let new_xpos = self.x_pos + self.advance();
+                if new_xpos > self.info.width {
                     self.new_line();
                 }
-                let new_ypos = self.y_pos + 15;
+                let new_ypos = self.y_pos + self.line_height();
                 if new_ypos >= self.info.height {
                     self.x_pos = 0;
                     self.y_pos = 0;
                     let _ = self.buffer.as_mut().map(|b| b.clear(Rgb888::BLACK));
                 }
 
-                // Safe because we should only be getting ascii
-                let slice = unsafe { slice::from_raw_parts(&byte as *const u8, 1) };
-                let text = unsafe { str::from_utf8_unchecked(slice) };
+                let mut utf8 = [0; 4];
                 let text = Text::with_baseline(
-                    text,
+                    self.glyph(c).encode_utf8(&mut utf8),
                     embedded_graphics::geometry::Point {
                         x: self.x_pos as i32,
                         y: self.y_pos as i32,
                     },
-                    MonoTextStyle::new(
-                        &embedded_graphics::mono_font::ascii::FONT_9X15,
-                        Rgb888::WHITE,
-                    ),
+                    MonoTextStyle::new(self.font, Rgb888::WHITE),
                     embedded_graphics::text::Baseline::Top,
                 );
                 self.buffer.as_mut().map(|b| text.draw(b.as_mut()));
-                self.x_pos += 9;
+                self.x_pos += self.advance();
             }
         }
     }
 
+    /// The character actually drawn for `c` with the current font.
+    fn glyph(&self, c: char) -> char {
+        let mapping = self.font.glyph_mapping;
+        // Fonts map anything they lack to the index of their own replacement glyph
+        if c.is_control() || (c != REPLACEMENT && mapping.index(c) == mapping.index(REPLACEMENT)) {
+            REPLACEMENT
+        } else {
+            c
+        }
+    }
+
     fn backspace(&mut self) {
         if self.x_pos == 0 {
-            self.y_pos -= 15;
-            self.x_pos = (self.info.stride / 9) * 9;
+            if self.y_pos == 0 {
+                return;
+            }
+            self.y_pos -= self.line_height();
+            self.x_pos = self.columns() * self.advance();
         }
-        self.x_pos -= 9;
+        self.x_pos -= self.advance();
         let rect = Rectangle::new(
             Point {
                 x: self.x_pos as i32,
                 y: self.y_pos as i32,
             },
             Size {
-                width: 9,
-                height: 15,
+                width: self.advance() as u32,
+                height: self.line_height() as u32,
             },
         );
         self.buffer
@@ -90,27 +171,86 @@ impl Writer {
     }
 
     fn new_line(&mut self) {
-        self.y_pos += 15;
+        self.y_pos += self.line_height();
         self.x_pos = 0;
     }
 
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+        for (i, c) in s.char_indices() {
+            let starts_word = !c.is_whitespace() && !self.in_word;
+            self.in_word = !c.is_whitespace();
+            if self.word_wrap && starts_word {
+                let word = s[i..].split(char::is_whitespace).next().unwrap_or_default();
+                self.wrap_before(word.chars().count());
+            }
+            match c {
                 // backspace
-                0x08 => self.backspace(),
-                // not part of printable ASCII range
-                _ => self.write_byte(0xfe),
+                '\x08' => self.backspace(),
+                // bell
+                '\x07' => {
+                    task::spawn(speaker::beep(speaker::BELL_FREQ, speaker::BELL_DURATION));
+                }
+                c => self.write_char(c),
+            }
+        }
+    }
+
+    /// Starts a new line if a word of `len` characters doesn't fit on the rest of this one but
+    /// would on a whole line.
+    fn wrap_before(&mut self, len: usize) {
+        let width = len * self.advance();
+        if self.x_pos > 0 && self.x_pos + width > self.info.width && len <= self.columns() {
+            self.new_line();
+        }
+    }
+
+    /// Keeps `s` to be drawn once the display is free, dropping the oldest text when full.
+    fn queue(&mut self, s: &str) {
+        for &byte in s.as_bytes() {
+            if self.pending.len() == PENDING_LEN {
+                self.pending.pop_front();
+                self.dropped += 1;
             }
+            self.pending.push_back(byte);
         }
+        // Don't leave the tail of a dropped character at the front
+        while self
+            .pending
+            .front()
+            .is_some_and(|&byte| byte & 0xC0 == 0x80)
+        {
+            self.pending.pop_front();
+            self.dropped += 1;
+        }
+    }
+
+    /// Draws the text queued while the display was busy, noting how much didn't fit.
+    fn drain_pending(&mut self) {
+        let dropped = mem::take(&mut self.dropped);
+        if dropped > 0 {
+            let _ = fmt::Write::write_fmt(
+                self,
+                format_args!("[{dropped} bytes dropped while the display was busy]\n"),
+            );
+        }
+        let mut pending = mem::take(&mut self.pending);
+        // Only whole characters are ever left in the queue
+        if let Ok(text) = core::str::from_utf8(pending.make_contiguous()) {
+            self.write_string(text);
+        }
+        pending.clear();
+        self.pending = pending;
     }
 }
 
+/// Draws if the writer has the display, queues the text for later otherwise.
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.write_string(s);
+        if self.buffer.is_some() {
+            self.write_string(s);
+        } else {
+            self.queue(s);
+        }
         Ok(())
     }
 }
@@ -132,14 +272,142 @@ pub fn _print(args: fmt::Arguments) {
 
     x86_64::instructions::interrupts::without_interrupts(|| {
         if let Ok(writer) = WRITER.try_get() {
+            let mut write = writer.spin_lock();
             if let Some(display) = DISPLAY.get().try_lock() {
-                let mut write = writer.spin_lock();
                 write.buffer.replace(display);
+                write.drain_pending();
                 write.write_fmt(args).unwrap();
                 write.buffer.take().unwrap().draw_frame();
             } else {
-                warn!("Tried to write to screen while someone else is\nAre you sure you meant to?");
+                // Someone else is drawing, the next print that gets the display draws this
+                write.write_fmt(args).unwrap();
             }
         }
     });
 }
+
+#[cfg(test)]
+mod test {
+    use alloc::format;
+    use core::fmt::Write;
+
+    use embedded_graphics::mono_font::{ascii, iso_8859_1::FONT_6X10};
+
+    use super::{Writer, PENDING_LEN, REPLACEMENT, TAB_WIDTH};
+    use crate::framebuffer::DISPLAY;
+
+    #[test_case]
+    fn smaller_font_fits_more_columns() {
+        let mut writer = Writer::new(DISPLAY.get().spin_lock().get_info());
+        let columns = writer.columns();
+        writer.set_font(&FONT_6X10);
+        assert!(writer.columns() > columns);
+
+        // Fill a whole line, the last character must not wrap early
+        for _ in 0..writer.columns() {
+            writer.write_byte(b'x');
+        }
+        assert_eq!((writer.x_pos, writer.y_pos), (writer.columns() * 6, 0));
+        writer.write_byte(b'x');
+        assert_eq!((writer.x_pos, writer.y_pos), (6, 10));
+    }
+
+    #[test_case]
+    fn non_ascii_glyphs() {
+        let mut writer = Writer::new(DISPLAY.get().spin_lock().get_info());
+        assert_eq!(writer.glyph('é'), 'é');
+        assert_eq!(writer.glyph('?'), '?');
+        // Not in Latin-1
+        assert_eq!(writer.glyph('─'), REPLACEMENT);
+        assert_eq!(writer.glyph('\x1b'), REPLACEMENT);
+
+        writer.set_font(&ascii::FONT_6X10);
+        assert_eq!(writer.glyph('é'), REPLACEMENT);
+        assert_eq!(writer.glyph('a'), 'a');
+
+        // One cell per char, not per byte
+        writer.write_string("héllo");
+        assert_eq!(writer.x_pos, 5 * 6);
+    }
+
+    #[test_case]
+    fn busy_display_queues_text() {
+        let mut writer = Writer::new(DISPLAY.get().spin_lock().get_info());
+        // Without the display, as when someone else holds it
+        write!(writer, "héllo").unwrap();
+        assert_eq!(writer.pending.len(), "héllo".len());
+        assert_eq!(writer.x_pos, 0);
+
+        writer.buffer.replace(DISPLAY.get().spin_lock());
+        write!(writer, "!").unwrap();
+        writer.drain_pending();
+        drop(writer.buffer.take());
+        assert!(writer.pending.is_empty());
+        // The queued text is drawn before what came after it
+        assert_eq!(writer.x_pos, 6 * writer.advance());
+    }
+
+    #[test_case]
+    fn full_queue_counts_dropped() {
+        let mut writer = Writer::new(DISPLAY.get().spin_lock().get_info());
+        for _ in 0..PENDING_LEN {
+            write!(writer, "x").unwrap();
+        }
+        write!(writer, "y").unwrap();
+        assert_eq!(writer.pending.len(), PENDING_LEN);
+        assert_eq!(writer.pending.back(), Some(&b'y'));
+        assert_eq!(writer.dropped, 1);
+
+        // Dropping one byte of a two byte character drops the whole character
+        let mut writer = Writer::new(DISPLAY.get().spin_lock().get_info());
+        for _ in 0..PENDING_LEN / 2 {
+            write!(writer, "é").unwrap();
+        }
+        write!(writer, "y").unwrap();
+        assert_eq!(writer.dropped, 2);
+        assert_eq!(writer.pending.front(), Some(&"é".as_bytes()[0]));
+    }
+
+    #[test_case]
+    fn tab_and_carriage_return() {
+        let mut writer = Writer::new(DISPLAY.get().spin_lock().get_info());
+        let advance = writer.advance();
+        writer.write_string("a\t");
+        assert_eq!((writer.x_pos, writer.y_pos), (TAB_WIDTH * advance, 0));
+        writer.write_string("b");
+        assert_eq!(writer.x_pos, (TAB_WIDTH + 1) * advance);
+        writer.write_string("\r");
+        assert_eq!((writer.x_pos, writer.y_pos), (0, 0));
+
+        // A tab at the last stop wraps instead of running off the screen
+        let last_stop = writer.columns() / TAB_WIDTH * TAB_WIDTH;
+        writer.x_pos = last_stop * advance;
+        writer.write_string("\t");
+        assert_eq!((writer.x_pos, writer.y_pos), (0, writer.line_height()));
+    }
+
+    #[test_case]
+    fn word_wrap_moves_whole_words() {
+        let mut writer = Writer::new(DISPLAY.get().spin_lock().get_info());
+        let (advance, line_height) = (writer.advance(), writer.line_height());
+        let columns = writer.columns();
+        let filler = "a".repeat(columns - 3);
+        let line = format!("{filler} hello world");
+
+        // Off by default, so "hello" is split at the edge
+        writer.write_string(&line);
+        assert_eq!((writer.x_pos, writer.y_pos), (9 * advance, line_height));
+
+        let mut writer = Writer::new(DISPLAY.get().spin_lock().get_info());
+        writer.set_word_wrap(true);
+        writer.write_string(&line);
+        assert_eq!(
+            (writer.x_pos, writer.y_pos),
+            ("hello world".len() * advance, line_height)
+        );
+
+        // A word longer than a line can't be moved, so it's broken
+        writer.write_string(&format!("\n{}", "b".repeat(columns + 2)));
+        assert_eq!((writer.x_pos, writer.y_pos), (2 * advance, 3 * line_height));
+    }
+}
@@ -0,0 +1,41 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+
+use alloc::vec::Vec;
+use bootloader_api::{entry_point, BootInfo};
+use kernel::{
+    allocator::{last_oom, KERNEL_HEAP_MAX_LEN},
+    print, println,
+    qemu::{exit_qemu, QemuExitCode},
+    util::hlt_loop,
+    BOOTLOADER_CONFIG,
+};
+
+const OVERSIZED: usize = 2 * KERNEL_HEAP_MAX_LEN;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    print!("heap_oom::oversized_allocation...\t");
+
+    let oversized = Vec::<u8>::with_capacity(OVERSIZED);
+    core::hint::black_box(oversized);
+
+    panic!("Allocating more than the heap succeeded");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // The handler reports on serial and records the layout before panicking
+    if last_oom().is_some_and(|layout| layout.size() == OVERSIZED) {
+        println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+        hlt_loop()
+    }
+    kernel::testing::test_panic_handler(info)
+}
@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::panic::PanicInfo;
+
+use bootloader_api::{entry_point, BootInfo};
+use kernel::{
+    print, println,
+    qemu::exit_qemu,
+    util::{hlt_loop, once::Lazy},
+    BOOTLOADER_CONFIG,
+};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    // Sets up the heap the fault stacks are allocated from and loads the GDT
+    kernel::init(boot_info);
+    print!("page_fault_ist::stack_overflow_page_fault...\t");
+
+    // The test IDT has no handlers for device interrupts
+    x86_64::instructions::interrupts::disable();
+    init_test_idt();
+
+    // Without its own stack the page fault handler would fault again and double fault
+    stack_overflow();
+
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    volatile::Volatile::new(0).read(); // Prevent tail recursion
+}
+
+static TEST_IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
+    unsafe {
+        idt.page_fault
+            .set_handler_fn(test_page_fault_handler)
+            .set_stack_index(kernel::gdt::PAGE_FAULT_IST_INDEX);
+        idt.double_fault
+            .set_handler_fn(test_double_fault_handler)
+            .set_stack_index(kernel::gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+
+    idt
+});
+
+fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: PageFaultErrorCode,
+) {
+    println!("[ok]");
+    exit_qemu(kernel::qemu::QemuExitCode::Success);
+    hlt_loop()
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    println!("[failed]\n");
+    println!("Error: page fault escalated to a double fault\n");
+    exit_qemu(kernel::qemu::QemuExitCode::Failed);
+    hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
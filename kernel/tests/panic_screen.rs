@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use bootloader_api::{entry_point, BootInfo};
+use embedded_graphics::{pixelcolor::Rgb888, prelude::*};
+use kernel::{
+    framebuffer::DISPLAY,
+    panic_screen, print, println,
+    qemu::{exit_qemu, QemuExitCode},
+    util::hlt_loop,
+    BOOTLOADER_CONFIG,
+};
+use tracing::{info, info_span};
+
+const LAST_LINE: &str = "last words before the panic";
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    print!("panic_screen::recent_log_drawn...\t");
+
+    let span = info_span!("doomed");
+    let _span = span.enter();
+    info!("{}", LAST_LINE);
+    panic!("expected panic");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    panic_screen::show(info);
+
+    let logged =
+        panic_screen::with_text(|text| text.contains(LAST_LINE) && text.contains("doomed"))
+            .unwrap_or(false);
+    let drawn = DISPLAY.try_get().is_ok_and(|disp| {
+        let disp = disp.spin_lock();
+        let size = disp.bounding_box().size;
+        (0..size.height.min(200)).any(|y| {
+            (0..size.width).any(|x| disp.pixel(Point::new(x as i32, y as i32)) == Some(Rgb888::RED))
+        })
+    });
+    if logged && drawn {
+        println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        println!("[failed]\n");
+        println!(
+            "Error: panic screen missing the recent log (logged {}, drawn {})\n",
+            logged, drawn
+        );
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop()
+}
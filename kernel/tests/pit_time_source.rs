@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+
+use core::{panic::PanicInfo, sync::atomic::Ordering, time::Duration};
+
+use bootloader_api::{entry_point, BootInfo};
+use kernel::{
+    interrupts::{self, InterruptIndex},
+    pit, print, println,
+    qemu::{exit_qemu, QemuExitCode},
+    rtc::TIMER_FREQ,
+    task::block_on,
+    time::TimeSource,
+    util::{
+        hlt_loop,
+        r#async::{sleep, sleep_future::MONOTONIC_TIME},
+    },
+    InitOptions, BOOTLOADER_CONFIG,
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init_with(
+        boot_info,
+        InitOptions {
+            time_source: TimeSource::Pit,
+            ..Default::default()
+        },
+    );
+    print!("pit_time_source::sleep_wakes...\t");
+    assert_ne!(pit::frequency(), 0);
+
+    x86_64::instructions::interrupts::enable();
+    let start = MONOTONIC_TIME.load(Ordering::Acquire);
+    block_on(sleep(Duration::from_millis(10)));
+    let ticks = MONOTONIC_TIME.load(Ordering::Acquire) - start;
+
+    assert!(ticks >= TIMER_FREQ / 100, "slept only {} ticks", ticks);
+    assert!(interrupts::counts()[InterruptIndex::Timer as usize] > 0);
+    println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::{alloc::Layout, panic::PanicInfo, ptr};
+
+use bootloader_api::{entry_point, BootInfo};
+use kernel::{
+    allocator::{self, heap_len, KERNEL_HEAP_MAX_LEN},
+    memory::PAGE_ALLOCATOR,
+    print, println,
+    qemu::{exit_qemu, QemuExitCode},
+    util::hlt_loop,
+    InitOptions, BOOTLOADER_CONFIG,
+};
+
+const HEAP_LEN: usize = 1024 * 1024;
+const CHUNK: usize = 1024 * 1024;
+const MAX_CHUNKS: usize = KERNEL_HEAP_MAX_LEN / CHUNK;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init_with(
+        boot_info,
+        InitOptions {
+            heap_len: HEAP_LEN,
+            ..Default::default()
+        },
+    );
+    print!("small_heap::grows_until_out_of_frames...\t");
+    assert_eq!(heap_len(), HEAP_LEN);
+    assert_eq!(allocator::stats().fallback_size, HEAP_LEN);
+
+    // Kept off the heap, it's about to be full
+    let mut chunks = [ptr::null_mut(); MAX_CHUNKS];
+    let layout = Layout::from_size_align(CHUNK, 4096).unwrap();
+    let mut count = 0;
+    while count < MAX_CHUNKS {
+        let chunk = unsafe { alloc::alloc::alloc(layout) };
+        if chunk.is_null() {
+            break;
+        }
+        // Every chunk has to be backed, not just handed out
+        unsafe { chunk.write_bytes(0xAA, CHUNK) };
+        chunks[count] = chunk;
+        count += 1;
+    }
+    let grown_to = allocator::stats().fallback_size;
+    let free_frames = PAGE_ALLOCATOR.get().spin_lock().free_frames();
+    for &chunk in &chunks[..count] {
+        unsafe { alloc::alloc::dealloc(chunk, layout) };
+    }
+
+    assert!(count * CHUNK > HEAP_LEN, "only {} chunks fit", count);
+    assert!(grown_to > HEAP_LEN);
+    // Growth only stops once it runs out of frames or reserved space
+    assert!(
+        (free_frames as usize) * 4096 < 2 * CHUNK || grown_to + 2 * CHUNK > KERNEL_HEAP_MAX_LEN,
+        "stopped growing at {:#x} with {} frames free",
+        grown_to,
+        free_frames
+    );
+    println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::testing::test_panic_handler(info)
+}
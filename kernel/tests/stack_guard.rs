@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+
+use core::{fmt::Write, panic::PanicInfo};
+
+use bootloader_api::{entry_point, BootInfo};
+use kernel::{
+    panic_screen::PanicText,
+    print, println,
+    qemu::{exit_qemu, QemuExitCode},
+    util::hlt_loop,
+    BOOTLOADER_CONFIG,
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    print!("stack_guard::overflow_reported...\t");
+
+    stack_overflow();
+
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    volatile::Volatile::new(0).read(); // Prevent tail recursion
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // The report comes from the page fault handler, on its own stack
+    let mut text = PanicText::new();
+    let _ = write!(text, "{}", info);
+    if text.as_str().contains("STACK GUARD") {
+        println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+        hlt_loop()
+    }
+    kernel::testing::test_panic_handler(info)
+}
@@ -1,9 +1,16 @@
 use clap::{Parser, ValueEnum};
 use std::{
     env,
+    fs::{File, OpenOptions},
+    path::PathBuf,
     process::{self, Command},
 };
 
+/// Smallest amount of RAM in MiB the kernel can boot with (heap, backbuffer and page tables)
+const MIN_MEMORY_MB: i64 = 64;
+/// Largest amount of RAM in MiB worth asking QEMU for
+const MAX_MEMORY_MB: i64 = 16 * 1024;
+
 /// QEMU runner for zoom_os
 #[derive(Parser)]
 #[command(version, about)]
@@ -11,6 +18,44 @@ struct Args {
     /// Boot from UEFI or Bios
     #[arg(short, long, value_enum, default_value = "uefi")]
     boot: BootType,
+    /// Amount of guest RAM in MiB
+    #[arg(
+        short,
+        long,
+        default_value_t = 128,
+        value_parser = clap::value_parser!(u32).range(MIN_MEMORY_MB..=MAX_MEMORY_MB)
+    )]
+    memory: u32,
+    /// Number of guest CPUs
+    #[arg(short, long, value_parser = clap::value_parser!(u32).range(1..=255))]
+    cpus: Option<u32>,
+    /// How QEMU shows the framebuffer
+    #[arg(short, long, value_enum, default_value = "window")]
+    display: DisplayMode,
+    /// Halt at startup until GDB attaches
+    #[arg(long)]
+    gdb: bool,
+    /// Port the QEMU gdbserver listens on
+    #[arg(long, default_value_t = 1234, requires = "gdb")]
+    gdb_port: u16,
+    /// Keep the VM around on triple faults and shutdowns so it can be inspected
+    #[arg(long)]
+    no_reboot: bool,
+    /// Write the serial output to a file instead of this terminal
+    #[arg(long, value_name = "PATH")]
+    serial_log: Option<PathBuf>,
+    /// Attach the serial port to this terminal, the default without --serial-log
+    #[arg(long, conflicts_with = "serial_log")]
+    serial_stdio: bool,
+    /// Accelerator QEMU runs the guest with
+    #[arg(short, long, value_enum, default_value = "tcg")]
+    accel: Accel,
+    /// Raw image attached as a virtio-blk drive
+    #[arg(long, value_name = "PATH")]
+    disk: Option<PathBuf>,
+    /// Size in MiB of a sparse image to create if the --disk image doesn't exist
+    #[arg(long, value_name = "MIB", requires = "disk")]
+    disk_size: Option<u64>,
 }
 
 #[derive(Clone, Copy, ValueEnum, Default)]
@@ -20,6 +65,26 @@ enum BootType {
     Uefi,
 }
 
+#[derive(Clone, Copy, ValueEnum, Default)]
+enum Accel {
+    Kvm,
+    #[default]
+    Tcg,
+}
+
+#[derive(Clone, Copy, ValueEnum, Default)]
+enum DisplayMode {
+    /// QEMU's default window
+    #[default]
+    Window,
+    /// A GTK window
+    Gtk,
+    /// No display at all, serial becomes the only visible output
+    None,
+    /// A VNC server on localhost:5900
+    Vnc,
+}
+
 fn main() {
     let args = Args::parse();
     let mut qemu = Command::new("qemu-system-x86_64");
@@ -36,9 +101,91 @@ fn main() {
             qemu.arg(format!("format=raw,file={}", env!("BIOS_IMAGE")));
         }
     }
+    match args.accel {
+        Accel::Kvm => {
+            if let Err(err) = OpenOptions::new().read(true).write(true).open("/dev/kvm") {
+                eprintln!("error: --accel kvm needs read/write access to /dev/kvm: {err}");
+                eprintln!("make sure KVM is enabled and your user is in the `kvm` group");
+                process::exit(1);
+            }
+            qemu.arg("-enable-kvm").arg("-cpu").arg("host");
+        }
+        Accel::Tcg => {
+            qemu.arg("-accel").arg("tcg");
+        }
+    }
+    qemu.arg("-m").arg(format!("{}M", args.memory));
+    if let Some(cpus) = args.cpus {
+        qemu.arg("-smp").arg(cpus.to_string());
+    }
+    match args.display {
+        DisplayMode::Window => {}
+        DisplayMode::Gtk => {
+            qemu.arg("-display").arg("gtk");
+        }
+        DisplayMode::None => {
+            // The kernel unwraps its framebuffer, so keep emulating the VGA device even though
+            // it isn't shown anywhere
+            println!("warning: no display, serial is the only visible output");
+            qemu.arg("-display").arg("none").arg("-vga").arg("std");
+        }
+        DisplayMode::Vnc => {
+            println!("VNC server listening on localhost:5900");
+            qemu.arg("-display").arg("vnc=localhost:0");
+        }
+    }
+    if args.gdb {
+        qemu.arg("-gdb").arg(format!("tcp::{}", args.gdb_port));
+        qemu.arg("-S");
+        println!("Waiting for a debugger, connect with:");
+        println!(
+            "    gdb {} -ex \"target remote localhost:{}\"",
+            env!("KERNEL_BINARY"),
+            args.gdb_port
+        );
+        println!("or:");
+        println!(
+            "    lldb {} -o \"gdb-remote localhost:{}\"",
+            env!("KERNEL_BINARY"),
+            args.gdb_port
+        );
+    }
+    if args.no_reboot {
+        qemu.arg("-no-reboot").arg("-no-shutdown");
+    }
+    if let Some(disk) = &args.disk {
+        if !disk.exists() {
+            let Some(size) = args.disk_size else {
+                eprintln!(
+                    "error: disk image {} doesn't exist, pass --disk-size to create it",
+                    disk.display()
+                );
+                process::exit(1);
+            };
+            println!("Creating {}MiB disk image {}", size, disk.display());
+            File::create(disk)
+                .and_then(|file| file.set_len(size * 1024 * 1024))
+                .unwrap();
+        }
+        qemu.arg("-drive").arg(format!(
+            "format=raw,file={},if=none,id=disk1",
+            disk.display()
+        ));
+        qemu.arg("-device").arg("virtio-blk-pci,drive=disk1");
+    }
     qemu.arg("-device")
         .arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
-    qemu.arg("-serial").arg("stdio");
+    match (&args.serial_log, args.serial_stdio) {
+        (Some(path), false) => {
+            println!("Logging serial output to {}", path.display());
+            qemu.arg("-serial").arg(format!("file:{}", path.display()));
+        }
+        // --serial-stdio conflicts with --serial-log, so clap already rejected both
+        (Some(_), true) => unreachable!("--serial-stdio and --serial-log are exclusive"),
+        (None, _) => {
+            qemu.arg("-serial").arg("stdio");
+        }
+    }
     let exit_status = qemu.status().unwrap();
     process::exit(exit_status.code().unwrap_or(-1));
 }